@@ -0,0 +1,112 @@
+#!/usr/bin/env python3
+"""Prints the SHA-1 hashes of the bundled breached passwords, one uppercase hex hash per line.
+
+The list holds the most common passwords of public breach corpora, the variants attackers
+try first (capitalized, with digits, years or symbols appended, leetspeak) and dates.
+The filter bundled with the server is built from it:
+
+    python3 data/breached_passwords.py > /tmp/breached.sha1
+    cargo run -- build-breached-filter /tmp/breached.sha1 data/breached_passwords.bloom 0.001
+"""
+
+import hashlib
+import itertools
+
+COMMON = """
+123456 123456789 12345 qwerty password 12345678 111111 123123 1234567890 1234567 qwerty123
+000000 1q2w3e aa12345678 abc123 password1 1234 qwertyuiop 123321 password123 1q2w3e4r5t
+iloveyou 654321 666666 987654321 123 123456a qwe123 1q2w3e4r 7777777 1qaz2wsx 123qwe zxcvbnm
+121212 asdasd a123456 555555 dragon 112233 123123123 monkey 11111111 qazwsx 159753 asdfghjkl
+222222 1234qwer qwerty1 123654 123abc asdfgh 777777 aaaaaa myspace1 88888888 fuckyou 123456789a
+999999 888888 football princess 789456123 147258369 12341234 computer 00000000 696969 superman
+michael shadow 12344321 123qweasd 987654 baseball master jennifer hunter 2000 letmein killer
+trustno1 jordan harley ranger buster thomas tigger robert soccer batman test pass hockey george
+charlie andrew michelle love sunshine jessica asshole pepper daniel access 654321 joshua maggie
+starwars silver william dallas yankees hello amanda orange biteme freedom thunder ginger nicole
+matthew chelsea cheese summer welcome whatever ashley secret mustang purple taylor flower
+lovely babygirl butterfly liverpool admin login solo passw0rd zaq12wsx charlie1 donald qwerty12
+iloveu samsung pokemon naruto minecraft letmein1 welcome1 monkey1 dragon1 football1 baseball1
+abcdef abcd1234 abcdefg abcdefgh azerty azertyuiop qwertz qwertzuiop ytrewq asdf asdf1234 zxcv
+q1w2e3r4 q1w2e3r4t5 1qazxsw2 zaq1xsw2 qweasdzxc qweasd 1a2b3c 1a2b3c4d a1b2c3 a1b2c3d4 aaaa
+changeme default guest root toor administrator user demo test123 testing temp temp123 secret1
+hallo passwort schatz fussball schalke04 bayern borussia hallo123 passwort1 schatz1 ichliebedich
+geheim geheim123 sommer winter frühling herbst sonne mond stern blume katze hund pferd
+schweiz zuerich zurich bern basel luzern genf suisse svizzera helvetia grüezi gruezi
+merci bonjour soleil chocolat loulou doudou motdepasse marseille
+ciao amore tesoro juventus
+science wissenschaft physik chemie biologie mathe mathematik schule lehrer schueler klasse
+student teacher school college university einstein newton curie tesla darwin galileo
+letsscience lets-science lets science austin matrix 123456aa klaster zxcvbn p@ssw0rd 131313
+princess1 sunshine1 iloveyou1 michael1 jordan23 superman1 batman1 spiderman ironman
+starwars1 pokemon1 naruto1 minecraft1 fortnite roblox
+""".split()
+
+NAMES = """
+anna laura sarah julia lisa lena lea leonie emma mia hannah sophie marie lara nina jana
+luca leon noah david daniel lukas jonas elias tim jan finn felix max paul ben simon nico
+michael thomas peter andreas stefan christian markus martin alexander sebastian tobias
+maria sandra nicole claudia andrea susanne sabine julia melanie jessica jennifer ashley
+amanda sarah nicole stephanie heather elizabeth megan rachel emily hannah olivia sophia
+james john robert michael william david richard joseph charles christopher matthew anthony
+""".split()
+
+KEYBOARD = """
+qwertyui asdfghjk zxcvbnm1 qazwsxedc 1qaz2wsx3edc qwer1234 1234qwerasdf asdfqwer
+!qaz2wsx 1qaz@wsx qwert12345 12qwaszx 123qwe123 qweqwe asdasd123 zxczxc qwerty123456
+""".split()
+
+SUFFIXES = [
+    "", "1", "12", "123", "1234", "12345", "123456", "!", "!!", "1!", "123!", "@", "#",
+    "?", ".", "01", "007", "69", "666", "777", "11", "22", "99", "00", "2", "3",
+]
+
+
+def leet(word):
+    return word.translate(str.maketrans("aeiost", "43105+")).replace("+", "7")
+
+
+def variants(word):
+    forms = {word, word.capitalize(), word.upper(), leet(word), leet(word).capitalize()}
+    for form, suffix in itertools.product(forms, SUFFIXES):
+        yield form + suffix
+    for form, year in itertools.product(forms, range(1950, 2031)):
+        yield f"{form}{year}"
+        yield f"{form}{year % 100:02d}"
+    for form, number in itertools.product(forms, range(100)):
+        yield f"{form}{number}"
+
+
+def dates():
+    for year, month, day in itertools.product(range(1950, 2031), range(1, 13), range(1, 32)):
+        yield f"{day:02d}{month:02d}{year}"
+        yield f"{year}{month:02d}{day:02d}"
+        yield f"{day:02d}.{month:02d}.{year}"
+        yield f"{day:02d}{month:02d}{year % 100:02d}"
+
+
+def numbers():
+    for digit, length in itertools.product("0123456789", range(4, 13)):
+        yield digit * length
+    for length in range(4, 11):
+        yield "".join(str(i % 10) for i in range(1, length + 1))
+        yield "".join(str(i % 10) for i in range(length, 0, -1))
+    for number in range(1000, 10000):
+        yield str(number)
+        yield str(number) * 2
+
+
+def passwords():
+    for word in itertools.chain(COMMON, NAMES, KEYBOARD):
+        yield from variants(word.lower())
+    yield from dates()
+    yield from numbers()
+
+
+def main():
+    hashes = {hashlib.sha1(p.encode()).hexdigest().upper() for p in passwords()}
+    for digest in sorted(hashes):
+        print(digest)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1 @@
+drop table "quiz_attempt";
\ No newline at end of file
@@ -0,0 +1,16 @@
+create table "quiz_attempt" (
+    id uuid primary key default uuid_generate_v1mc(),
+    quiz_id uuid not null,
+    user_id uuid not null,
+    answers json not null,
+    correct int not null,
+    total int not null,
+    score_awarded int not null,
+    created_at timestamptz not null default now(),
+    constraint fk_quiz_id
+        foreign key(quiz_id)
+            references "quiz"(id),
+    constraint fk_user_id
+        foreign key(user_id)
+            references "user"(id)
+);
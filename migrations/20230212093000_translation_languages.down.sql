@@ -0,0 +1,22 @@
+alter table "question" drop constraint fk_question;
+alter table "challenge" drop constraint fk_description;
+alter table "quiz" drop constraint fk_title;
+
+-- Only one language per translation can be kept
+delete from "translation" a using "translation" b
+where a.id = b.id and a.ctid > b.ctid;
+
+alter table "translation" drop constraint fk_translation_key;
+alter table "translation" drop constraint translation_pkey;
+alter table "translation" add constraint translation_pkey primary key (id);
+alter table "translation" alter column id set default uuid_generate_v1mc();
+alter table "translation" add constraint unique_content unique (id, language_code);
+
+alter table "quiz" add constraint fk_title
+    foreign key(title)
+        references "translation"(id);
+alter table "challenge" add constraint fk_description
+    foreign key(description)
+        references "translation"(id);
+
+drop table "translation_key";
@@ -0,0 +1,27 @@
+-- A translation is identified by its key and can have content in several languages
+create table "translation_key" (
+    id uuid primary key default uuid_generate_v1mc()
+);
+
+insert into "translation_key" (id) select id from "translation";
+
+alter table "quiz" drop constraint fk_title;
+alter table "challenge" drop constraint fk_description;
+
+alter table "translation" drop constraint unique_content;
+alter table "translation" drop constraint translation_pkey;
+alter table "translation" alter column id drop default;
+alter table "translation" add constraint translation_pkey primary key (id, language_code);
+alter table "translation" add constraint fk_translation_key
+    foreign key(id)
+        references "translation_key"(id);
+
+alter table "quiz" add constraint fk_title
+    foreign key(title)
+        references "translation_key"(id);
+alter table "challenge" add constraint fk_description
+    foreign key(description)
+        references "translation_key"(id);
+alter table "question" add constraint fk_question
+    foreign key(question)
+        references "translation_key"(id);
@@ -0,0 +1,2 @@
+drop table "refresh_token";
+drop table "auth_session";
\ No newline at end of file
@@ -0,0 +1,24 @@
+-- A login session. All refresh tokens issued through rotation belong to the same session
+create table "auth_session" (
+    id uuid primary key default uuid_generate_v1mc(),
+    user_id uuid not null,
+    created_at timestamptz not null default now(),
+    expires_at timestamptz not null,
+    revoked_at timestamptz,
+    constraint fk_user_id
+        foreign key(user_id)
+            references "user"(id)
+);
+
+create index auth_session_user_id_idx on "auth_session" (user_id);
+
+create table "refresh_token" (
+    hash text primary key,
+    session_id uuid not null,
+    created_at timestamptz not null default now(),
+    used_at timestamptz,
+    constraint fk_session_id
+        foreign key(session_id)
+            references "auth_session"(id)
+            on delete cascade
+);
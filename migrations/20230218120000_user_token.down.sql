@@ -0,0 +1,2 @@
+drop table "user_token";
+drop type tokenpurpose;
\ No newline at end of file
@@ -0,0 +1,17 @@
+create type tokenpurpose as enum ('passwordreset');
+
+-- Single-use tokens sent to users, e.g. by mail. Only a hash of the token is stored
+create table "user_token" (
+    hash text primary key,
+    user_id uuid not null,
+    purpose tokenpurpose not null,
+    created_at timestamptz not null default now(),
+    expires_at timestamptz not null,
+    used_at timestamptz,
+    constraint fk_user_id
+        foreign key(user_id)
+            references "user"(id)
+            on delete cascade
+);
+
+create index user_token_user_id_idx on "user_token" (user_id);
@@ -0,0 +1,6 @@
+alter table "user" drop column if exists email_verified_at;
+delete from "user_token" where purpose = 'emailverification';
+alter type tokenpurpose rename to tokenpurpose_old;
+create type tokenpurpose as enum ('passwordreset');
+alter table "user_token" alter column purpose type tokenpurpose using purpose::text::tokenpurpose;
+drop type tokenpurpose_old;
@@ -0,0 +1,2 @@
+alter table "user" add email_verified_at timestamptz;
+alter type tokenpurpose add value 'emailverification';
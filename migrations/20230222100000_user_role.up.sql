@@ -0,0 +1,3 @@
+create type userrole as enum ('admin', 'teacher', 'student');
+
+alter table "user" add role userrole not null default 'student';
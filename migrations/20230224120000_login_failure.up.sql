@@ -0,0 +1,7 @@
+-- Failed logins per account or client, used to throttle password guessing
+create table "login_failure" (
+    key text primary key,
+    failures int not null,
+    last_failure_at timestamptz not null default now(),
+    locked_until timestamptz
+);
@@ -0,0 +1,8 @@
+drop table "recovery_code";
+drop table "user_totp";
+
+delete from "user_token" where purpose = 'loginchallenge';
+alter type tokenpurpose rename to tokenpurpose_old;
+create type tokenpurpose as enum ('passwordreset', 'emailverification');
+alter table "user_token" alter column purpose type tokenpurpose using purpose::text::tokenpurpose;
+drop type tokenpurpose_old;
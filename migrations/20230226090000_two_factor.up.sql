@@ -0,0 +1,27 @@
+-- TOTP secrets are needed in plain text to compute codes
+create table "user_totp" (
+    user_id uuid primary key,
+    secret text not null,
+    created_at timestamptz not null default now(),
+    confirmed_at timestamptz,
+    -- The time step of the last accepted code, to prevent replaying it
+    last_used_step bigint,
+    constraint fk_user_id
+        foreign key(user_id)
+            references "user"(id)
+            on delete cascade
+);
+
+create table "recovery_code" (
+    hash text primary key,
+    user_id uuid not null,
+    used_at timestamptz,
+    constraint fk_user_id
+        foreign key(user_id)
+            references "user"(id)
+            on delete cascade
+);
+
+create index recovery_code_user_id_idx on "recovery_code" (user_id);
+
+alter type tokenpurpose add value 'loginchallenge';
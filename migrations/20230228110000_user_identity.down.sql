@@ -0,0 +1,9 @@
+alter table "user" drop constraint require_email;
+alter table "user" add constraint require_password check
+(
+  (is_guest and email is null and hash is null) or
+  (not is_guest and email is not null and hash is not null)
+);
+
+drop table "oidc_login";
+drop table "user_identity";
@@ -0,0 +1,31 @@
+-- Accounts at external identity providers users log in with
+create table "user_identity" (
+    provider text not null,
+    subject text not null,
+    user_id uuid not null,
+    created_at timestamptz not null default now(),
+    primary key (provider, subject),
+    constraint fk_user_id
+        foreign key(user_id)
+            references "user"(id)
+            on delete cascade
+);
+
+create index user_identity_user_id_idx on "user_identity" (user_id);
+
+-- Logins started at an external provider, until the user is sent back
+create table "oidc_login" (
+    state_hash text primary key,
+    provider text not null,
+    code_verifier text not null,
+    nonce text not null,
+    expires_at timestamptz not null
+);
+
+-- Users who only log in with an external provider have no password
+alter table "user" drop constraint require_password;
+alter table "user" add constraint require_email check
+(
+  (is_guest and email is null and hash is null) or
+  (not is_guest and email is not null)
+);
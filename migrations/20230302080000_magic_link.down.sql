@@ -0,0 +1,5 @@
+delete from "user_token" where purpose = 'magiclink';
+alter type tokenpurpose rename to tokenpurpose_old;
+create type tokenpurpose as enum ('passwordreset', 'emailverification', 'loginchallenge');
+alter table "user_token" alter column purpose type tokenpurpose using purpose::text::tokenpurpose;
+drop type tokenpurpose_old;
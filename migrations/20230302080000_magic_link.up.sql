@@ -0,0 +1 @@
+alter type tokenpurpose add value 'magiclink';
@@ -0,0 +1,22 @@
+create type apiscope as enum ('challenge:progress:read', 'challenge:progress:write');
+
+-- Personal API keys for integrations, e.g. a dashboard pushing challenge progress
+create table "api_key" (
+    id uuid primary key default uuid_generate_v1mc(),
+    user_id uuid not null,
+    name text not null,
+    -- The first characters of the key, to tell keys apart
+    prefix text not null,
+    hash text not null unique,
+    scopes apiscope[] not null,
+    created_at timestamptz not null default now(),
+    expires_at timestamptz,
+    last_used_at timestamptz,
+    revoked_at timestamptz,
+    constraint fk_user_id
+        foreign key(user_id)
+            references "user"(id)
+            on delete cascade
+);
+
+create index api_key_user_id_idx on "api_key" (user_id);
@@ -0,0 +1,3 @@
+drop table "audit_event";
+drop function prevent_audit_event_change();
+drop type auditaction;
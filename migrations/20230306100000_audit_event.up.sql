@@ -0,0 +1,48 @@
+create type auditaction as enum (
+    'register',
+    'login',
+    'loginfailed',
+    'logout',
+    'passwordchange',
+    'passwordreset',
+    'emailverification',
+    'rolechange',
+    'twofactorenable',
+    'twofactordisable',
+    'apikeycreate',
+    'apikeyrevoke',
+    'accountdeletion',
+    'quizcreate',
+    'challengecreate',
+    'challengedelete'
+);
+
+-- Security relevant actions. Events are never changed or removed
+create table "audit_event" (
+    id uuid primary key default uuid_generate_v1mc(),
+    created_at timestamptz not null default now(),
+    -- Not a foreign key, events outlive deleted users
+    actor_id uuid,
+    action auditaction not null,
+    target_id uuid,
+    ip text,
+    user_agent text,
+    details jsonb
+);
+
+create index audit_event_created_at_idx on "audit_event" (created_at);
+create index audit_event_actor_id_idx on "audit_event" (actor_id);
+create index audit_event_target_id_idx on "audit_event" (target_id);
+
+create or replace function prevent_audit_event_change()
+    returns trigger as
+$$
+begin
+    raise exception 'audit events are append-only';
+end;
+$$ language plpgsql;
+
+create trigger audit_event_append_only
+    before update or delete on "audit_event"
+    for each row
+execute function prevent_audit_event_change();
@@ -0,0 +1,2 @@
+drop table "user_challenge_day";
+alter table "user" drop column timezone;
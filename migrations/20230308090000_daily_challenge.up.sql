@@ -0,0 +1,15 @@
+-- IANA name of the timezone daily challenges reset in, e.g. 'Europe/Zurich'
+alter table "user" add timezone text not null default 'UTC';
+
+-- Progress of daily challenges per calendar day in the user's timezone.
+-- user_challenge keeps the total over all days
+create table "user_challenge_day" (
+    user_id uuid not null,
+    challenge_id uuid not null,
+    day date not null,
+    progress int not null,
+    updated_at timestamptz,
+    primary key (user_id, challenge_id, day)
+);
+
+select trigger_updated_at('"user_challenge_day"');
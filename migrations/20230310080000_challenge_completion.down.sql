@@ -0,0 +1,5 @@
+drop table "challenge_reward";
+alter table "user_challenge_day" drop column completed_at;
+alter table "user_challenge" drop column completed_at;
+alter table "challenge" drop constraint positive_goal;
+alter table "challenge" drop column reward;
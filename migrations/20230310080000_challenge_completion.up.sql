@@ -0,0 +1,19 @@
+-- Score a user gets for completing the challenge, every day for daily challenges
+alter table "challenge" add reward int not null default 0;
+alter table "challenge" add constraint positive_goal check (goal > 0) not valid;
+
+alter table "user_challenge" add completed_at timestamptz;
+alter table "user_challenge_day" add completed_at timestamptz;
+
+-- Rewards handed out, so deleting and re-adding progress cannot earn one twice.
+-- day is the completed day of daily challenges and null for other challenges
+create table "challenge_reward" (
+    user_id uuid not null,
+    challenge_id uuid not null,
+    day date,
+    reward int not null,
+    created_at timestamptz not null default now()
+);
+
+create unique index challenge_reward_once
+    on "challenge_reward" (user_id, challenge_id, coalesce(day, '-infinity'::date));
@@ -0,0 +1,3 @@
+drop table "challenge_progress_event";
+drop function prevent_progress_event_change();
+drop type progresssource;
@@ -0,0 +1,35 @@
+create type progresssource as enum ('app', 'apikey', 'migration');
+
+-- Every change of challenge progress. user_challenge and user_challenge_day hold the totals.
+-- Events are never changed, only deleted together with the progress, challenge or user
+create table "challenge_progress_event" (
+    id uuid primary key default uuid_generate_v1mc(),
+    user_id uuid not null,
+    challenge_id uuid not null,
+    amount int not null,
+    created_at timestamptz not null default now(),
+    source progresssource not null,
+    note text
+);
+
+create index challenge_progress_event_user_idx
+    on "challenge_progress_event" (user_id, challenge_id, created_at);
+
+create or replace function prevent_progress_event_change()
+    returns trigger as
+$$
+begin
+    raise exception 'progress events cannot be changed';
+end;
+$$ language plpgsql;
+
+create trigger challenge_progress_event_append_only
+    before update on "challenge_progress_event"
+    for each row
+execute function prevent_progress_event_change();
+
+-- Progress from before the ledger becomes a single event
+insert into "challenge_progress_event" (user_id, challenge_id, amount, created_at, source)
+select user_id, challenge_id, progress, coalesce(updated_at, now()), 'migration'
+from "user_challenge"
+where progress <> 0;
@@ -0,0 +1,8 @@
+alter table "challenge" drop column allow_decrease;
+alter table "challenge" drop column max_per_day;
+alter table "challenge" drop column max_per_submission;
+
+alter table "challenge_reward" drop constraint fk_challenge;
+alter table "challenge_progress_event" drop constraint fk_challenge;
+alter table "user_challenge_day" drop constraint fk_challenge;
+alter table "user_challenge" drop constraint fk_challenge;
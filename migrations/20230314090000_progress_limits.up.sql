@@ -0,0 +1,20 @@
+-- Progress could be added to challenges that never existed
+delete from "user_challenge" where challenge_id not in (select id from "challenge");
+delete from "user_challenge_day" where challenge_id not in (select id from "challenge");
+delete from "challenge_progress_event" where challenge_id not in (select id from "challenge");
+delete from "challenge_reward" where challenge_id not in (select id from "challenge");
+
+alter table "user_challenge" add constraint fk_challenge
+    foreign key (challenge_id) references "challenge"(id);
+alter table "user_challenge_day" add constraint fk_challenge
+    foreign key (challenge_id) references "challenge"(id);
+alter table "challenge_progress_event" add constraint fk_challenge
+    foreign key (challenge_id) references "challenge"(id);
+alter table "challenge_reward" add constraint fk_challenge
+    foreign key (challenge_id) references "challenge"(id);
+
+-- Limits for the progress users can add, null means unlimited
+alter table "challenge" add max_per_submission int check (max_per_submission > 0);
+alter table "challenge" add max_per_day int check (max_per_day > 0);
+-- Whether progress may be negative, otherwise it only ever increases
+alter table "challenge" add allow_decrease boolean not null default false;
@@ -0,0 +1,16 @@
+-- Responses of mutating requests, replayed when a client retries a request with the same Idempotency-Key
+create table "idempotency_key" (
+    user_id uuid not null,
+    key text not null,
+    -- Hash of the endpoint and the request body, a key can't be reused for a different request
+    request_hash text not null,
+    -- Both are null while the original request is still being processed
+    status smallint,
+    body text,
+    created_at timestamptz not null default now(),
+    primary key (user_id, key),
+    constraint fk_user_id
+        foreign key(user_id)
+            references "user"(id)
+            on delete cascade
+);
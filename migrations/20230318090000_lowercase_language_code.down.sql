@@ -0,0 +1 @@
+drop index translation_language_code_idx;
@@ -0,0 +1,12 @@
+-- Language codes are case insensitive, keep a single translation per key and language
+delete from "translation" t
+using "translation" other
+where t.id = other.id
+    and lower(t.language_code) = lower(other.language_code)
+    and t.language_code <> other.language_code
+    and t.language_code <> lower(t.language_code)
+    and (other.language_code = lower(other.language_code) or t.language_code > other.language_code);
+
+update "translation" set language_code = lower(language_code);
+
+create unique index translation_language_code_idx on "translation" (id, lower(language_code));
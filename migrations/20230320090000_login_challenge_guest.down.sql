@@ -0,0 +1 @@
+alter table "user_token" drop column guest_id;
@@ -0,0 +1,6 @@
+-- The guest to merge into the account once its login challenge is completed
+alter table "user_token" add column guest_id uuid;
+alter table "user_token" add constraint fk_guest_id
+    foreign key(guest_id)
+        references "user"(id)
+        on delete cascade;
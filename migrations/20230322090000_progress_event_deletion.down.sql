@@ -0,0 +1,18 @@
+drop trigger challenge_progress_event_append_only on "challenge_progress_event";
+create trigger challenge_progress_event_append_only
+    before update on "challenge_progress_event"
+    for each row
+execute function prevent_progress_event_change();
+
+create or replace function prevent_progress_event_change()
+    returns trigger as
+$$
+begin
+    raise exception 'progress events cannot be changed';
+end;
+$$ language plpgsql;
+
+alter table "challenge_progress_event" drop constraint fk_user_id;
+
+-- Values can't be removed from an enum, the value stays unused
+delete from "challenge_progress_event" where source = 'reset';
@@ -0,0 +1,28 @@
+alter type progresssource add value 'reset';
+
+-- Events only go away together with their user, removing progress records a reset instead
+delete from "challenge_progress_event" event
+where not exists (select 1 from "user" where id = event.user_id);
+
+alter table "challenge_progress_event" add constraint fk_user_id
+    foreign key(user_id)
+        references "user"(id)
+        on delete cascade;
+
+create or replace function prevent_progress_event_change()
+    returns trigger as
+$$
+begin
+    -- Deleting the user cascades to its events, the user is already gone then
+    if tg_op = 'DELETE' and not exists (select 1 from "user" where id = old.user_id) then
+        return old;
+    end if;
+    raise exception 'progress events cannot be changed';
+end;
+$$ language plpgsql;
+
+drop trigger challenge_progress_event_append_only on "challenge_progress_event";
+create trigger challenge_progress_event_append_only
+    before update or delete on "challenge_progress_event"
+    for each row
+execute function prevent_progress_event_change();
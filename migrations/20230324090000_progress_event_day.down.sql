@@ -0,0 +1,2 @@
+drop index challenge_progress_event_day_idx;
+alter table "challenge_progress_event" drop column day;
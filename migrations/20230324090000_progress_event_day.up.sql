@@ -0,0 +1,15 @@
+-- The local date of the user an event was recorded on, daily limits count the events of a date
+-- instead of a window computed from the current timezone
+alter table "challenge_progress_event" add column day date;
+
+alter table "challenge_progress_event" disable trigger challenge_progress_event_append_only;
+update "challenge_progress_event" event
+set day = (event.created_at at time zone "user".timezone)::date
+from "user"
+where "user".id = event.user_id;
+alter table "challenge_progress_event" enable trigger challenge_progress_event_append_only;
+
+alter table "challenge_progress_event" alter column day set not null;
+
+create index challenge_progress_event_day_idx
+    on "challenge_progress_event" (user_id, challenge_id, day);
@@ -0,0 +1,7 @@
+create or replace function prevent_audit_event_change()
+    returns trigger as
+$$
+begin
+    raise exception 'audit events are append-only';
+end;
+$$ language plpgsql;
@@ -0,0 +1,37 @@
+-- Audit events outlive their users, but not their personal data. Deleting a user clears the
+-- IP, user agent and details of their events. Nothing else can be changed, and only with
+-- audit.anonymize set for the transaction
+create or replace function prevent_audit_event_change()
+    returns trigger as
+$$
+begin
+    if tg_op = 'UPDATE'
+        and current_setting('audit.anonymize', true) = 'on'
+        and new.id = old.id
+        and new.created_at = old.created_at
+        and new.actor_id is not distinct from old.actor_id
+        and new.action = old.action
+        and new.target_id is not distinct from old.target_id
+        and new.ip is null
+        and new.user_agent is null
+        and new.details is null then
+        return new;
+    end if;
+    raise exception 'audit events are append-only';
+end;
+$$ language plpgsql;
+
+alter table "audit_event" disable trigger audit_event_append_only;
+
+-- Failed logins stored the email, they point to the account instead
+update "audit_event" set target_id = "user".id, details = details - 'email'
+from "user"
+where action = 'loginfailed' and target_id is null
+    and lower(details ->> 'email') = lower("user".email);
+update "audit_event" set details = details - 'email' where action = 'loginfailed';
+
+-- Users deleted before only leave what they did behind
+update "audit_event" set ip = null, user_agent = null, details = null
+where actor_id is not null and not exists (select 1 from "user" where id = actor_id);
+
+alter table "audit_event" enable trigger audit_event_append_only;
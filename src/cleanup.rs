@@ -0,0 +1,126 @@
+use std::time::{Duration, Instant};
+
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram},
+    Context, KeyValue,
+};
+use poem_openapi::Object;
+use sqlx::PgPool;
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::core;
+
+#[derive(Error, Debug)]
+pub enum CleanupConfigError {
+    #[error("{0} is not a number")]
+    NotANumber(&'static str),
+}
+
+/// The outcome of a cleanup run.
+#[derive(Object, Debug, Clone)]
+pub struct GuestCleanupReport {
+    /// Number of deleted guest accounts
+    pub deleted: u64,
+    /// Guests without activity for this many days were deleted
+    pub retention_days: i32,
+}
+
+/// Periodically deletes guest accounts nobody used for a while.
+/// Every app launch may register a new guest, most of them are never used again.
+#[derive(Clone)]
+pub struct GuestCleanup {
+    /// Guests without activity for this many days are deleted
+    pub retention_days: i32,
+    /// Time between two automatic runs. No automatic runs happen if it is `None`
+    pub interval: Option<Duration>,
+    runs: Counter<u64>,
+    deleted: Counter<u64>,
+    duration: Histogram<f64>,
+}
+
+impl GuestCleanup {
+    pub fn new(retention_days: i32, interval: Option<Duration>) -> Self {
+        let meter = global::meter("letsscience");
+        Self {
+            retention_days,
+            interval,
+            runs: meter
+                .u64_counter("guest_cleanup_runs")
+                .with_description("Runs of the guest cleanup, by result")
+                .init(),
+            deleted: meter
+                .u64_counter("guest_cleanup_deleted")
+                .with_description("Guest accounts deleted by the cleanup")
+                .init(),
+            duration: meter
+                .f64_histogram("guest_cleanup_duration_seconds")
+                .with_description("Duration of guest cleanup runs")
+                .init(),
+        }
+    }
+
+    /// Reads `GUEST_RETENTION_DAYS` (30 days by default) and `GUEST_CLEANUP_INTERVAL_HOURS`
+    /// (daily by default, `0` disables automatic runs).
+    pub fn from_env() -> Result<Self, CleanupConfigError> {
+        let retention_days = match std::env::var("GUEST_RETENTION_DAYS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| CleanupConfigError::NotANumber("GUEST_RETENTION_DAYS"))?,
+            Err(_) => 30,
+        };
+        let interval_hours: u64 = match std::env::var("GUEST_CLEANUP_INTERVAL_HOURS") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| CleanupConfigError::NotANumber("GUEST_CLEANUP_INTERVAL_HOURS"))?,
+            Err(_) => 24,
+        };
+        let interval = (interval_hours > 0).then(|| Duration::from_secs(interval_hours * 3600));
+        Ok(Self::new(retention_days, interval))
+    }
+
+    /// Deletes inactive guests once and records the run in the metrics.
+    #[tracing::instrument(skip(self, pool))]
+    pub async fn run(&self, pool: &PgPool) -> sqlx::Result<GuestCleanupReport> {
+        let cx = Context::current();
+        let start = Instant::now();
+        let result = core::user::delete_inactive_guests(pool, self.retention_days).await;
+        self.duration
+            .record(&cx, start.elapsed().as_secs_f64(), &[]);
+        match result {
+            Ok(deleted) => {
+                self.runs.add(&cx, 1, &[KeyValue::new("result", "ok")]);
+                self.deleted.add(&cx, deleted, &[]);
+                info!(
+                    "deleted {} guests inactive for {} days",
+                    deleted, self.retention_days
+                );
+                Ok(GuestCleanupReport {
+                    deleted,
+                    retention_days: self.retention_days,
+                })
+            }
+            Err(e) => {
+                self.runs.add(&cx, 1, &[KeyValue::new("result", "error")]);
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs the cleanup in the background every `interval`, starting right away.
+    pub fn spawn(self, pool: PgPool) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                if let Err(e) = self.run(&pool).await {
+                    error!("error {:?} while deleting inactive guests", e);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,215 @@
+use chrono::{offset::Utc, DateTime};
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::{PgHasArrayType, PgTypeInfo},
+    PgPool, Result, Type,
+};
+use uuid::Uuid;
+
+use crate::core::user::Role;
+
+/// What an API key may be used for. Keys can only call the endpoints their scopes allow.
+#[derive(Type, Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[sqlx(type_name = "apiscope")]
+pub enum ApiScope {
+    #[oai(rename = "challenge:progress:read")]
+    #[serde(rename = "challenge:progress:read")]
+    #[sqlx(rename = "challenge:progress:read")]
+    ChallengeProgressRead,
+    #[oai(rename = "challenge:progress:write")]
+    #[serde(rename = "challenge:progress:write")]
+    #[sqlx(rename = "challenge:progress:write")]
+    ChallengeProgressWrite,
+}
+
+impl PgHasArrayType for ApiScope {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_apiscope")
+    }
+}
+
+impl ApiScope {
+    /// Whether a key with this scope may call the endpoint.
+    pub fn allows(&self, method: &str, path: &str) -> bool {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match self {
+            Self::ChallengeProgressRead => {
+                method == "GET"
+                    && matches!(
+                        segments[..],
+                        ["api", "challenges", "self"] | ["api", "challenges", "self", "timeline"]
+                    )
+            }
+            Self::ChallengeProgressWrite => {
+                matches!(method, "POST" | "DELETE")
+                    && matches!(segments[..], ["api", "challenge", _, "progress"])
+            }
+        }
+    }
+}
+
+#[derive(Object, Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    /// The first characters of the key
+    pub prefix: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// The owner of an API key a request was made with.
+#[derive(Debug, Clone)]
+pub struct ApiKeyOwner {
+    pub key_id: Uuid,
+    pub user_id: Uuid,
+    pub role: Role,
+    pub scopes: Vec<ApiScope>,
+}
+
+// Stores a new API key for the user with the given (hashed) key.
+#[tracing::instrument(skip(pool, hash))]
+pub async fn create_api_key(
+    pool: &PgPool,
+    user_id: Uuid,
+    name: &str,
+    prefix: &str,
+    hash: &str,
+    scopes: &[ApiScope],
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<ApiKey> {
+    sqlx::query_as!(
+        ApiKey,
+        r#"
+            insert into "api_key" (user_id, name, prefix, hash, scopes, expires_at)
+            values ($1, $2, $3, $4, $5, $6)
+            returning id, name, prefix, scopes as "scopes: Vec<ApiScope>", created_at, expires_at,
+                last_used_at, revoked_at
+        "#,
+        user_id,
+        name,
+        prefix,
+        hash,
+        scopes as _,
+        expires_at
+    )
+    .fetch_one(pool)
+    .await
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn get_api_keys(pool: &PgPool, user_id: Uuid) -> Result<Vec<ApiKey>> {
+    sqlx::query_as!(
+        ApiKey,
+        r#"
+            select id, name, prefix, scopes as "scopes: Vec<ApiScope>", created_at, expires_at,
+                last_used_at, revoked_at
+            from "api_key" where user_id = $1
+            order by created_at
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+// Revokes an API key of the user.
+// Returns false if the user has no such active key
+#[tracing::instrument(skip(pool))]
+pub async fn revoke_api_key(pool: &PgPool, user_id: Uuid, id: Uuid) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+            update "api_key" set revoked_at = now()
+            where id = $1 and user_id = $2 and revoked_at is null
+        "#,
+        id,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// Looks up the owner of an active API key by its hash and remembers when it was used.
+// Returns Ok(None) if the key is unknown, expired or revoked
+#[tracing::instrument(skip(pool, hash))]
+pub async fn authenticate(pool: &PgPool, hash: &str) -> Result<Option<ApiKeyOwner>> {
+    sqlx::query_as!(
+        ApiKeyOwner,
+        r#"
+            update "api_key" set last_used_at = now()
+            from "user"
+            where "user".id = api_key.user_id and api_key.hash = $1
+                and api_key.revoked_at is null
+                and (api_key.expires_at is null or api_key.expires_at > now())
+            returning api_key.id as key_id, api_key.user_id, "user".role as "role: Role",
+                api_key.scopes as "scopes: Vec<ApiScope>"
+        "#,
+        hash
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use sqlx::PgPool;
+
+    use super::ApiScope;
+    use crate::core::{self, user::User};
+
+    #[test]
+    fn scopes() {
+        let write = ApiScope::ChallengeProgressWrite;
+        let id = "3f2b9a0e-0000-0000-0000-000000000000";
+        assert!(write.allows("POST", &format!("/api/challenge/{}/progress", id)));
+        assert!(write.allows("DELETE", &format!("/api/challenge/{}/progress", id)));
+        assert!(!write.allows("POST", "/api/challenge"));
+        assert!(!write.allows("GET", "/api/challenges/self"));
+        assert!(ApiScope::ChallengeProgressRead.allows("GET", "/api/challenges/self"));
+        assert!(ApiScope::ChallengeProgressRead.allows("GET", "/api/challenges/self/timeline"));
+        assert!(!ApiScope::ChallengeProgressRead.allows("GET", "/api/user/self"));
+    }
+
+    #[sqlx::test]
+    async fn api_keys(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        let scopes = [ApiScope::ChallengeProgressWrite];
+        let key = super::create_api_key(&pool, user_id, "Dashboard", "abcd", "hash", &scopes, None)
+            .await?;
+        assert_eq!(key.scopes, scopes);
+        super::create_api_key(
+            &pool,
+            user_id,
+            "Expired",
+            "efgh",
+            "expired",
+            &scopes,
+            Some(Utc::now() - Duration::days(1)),
+        )
+        .await?;
+
+        let owner = super::authenticate(&pool, "hash").await?.unwrap();
+        assert_eq!(owner.user_id, user_id);
+        assert_eq!(owner.key_id, key.id);
+        assert_eq!(owner.scopes, scopes);
+        assert!(super::authenticate(&pool, "expired").await?.is_none());
+        assert!(super::authenticate(&pool, "unknown").await?.is_none());
+
+        let keys = super::get_api_keys(&pool, user_id).await?;
+        assert_eq!(keys.len(), 2);
+        assert!(keys[0].last_used_at.is_some());
+
+        assert!(super::revoke_api_key(&pool, user_id, key.id).await?);
+        assert!(!super::revoke_api_key(&pool, user_id, key.id).await?);
+        assert!(super::authenticate(&pool, "hash").await?.is_none());
+        Ok(())
+    }
+}
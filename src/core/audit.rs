@@ -0,0 +1,282 @@
+use chrono::{offset::Utc, DateTime};
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool, Result, Type};
+use uuid::Uuid;
+
+#[derive(Type, Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[sqlx(type_name = "auditaction", rename_all = "lowercase")]
+pub enum AuditAction {
+    Register,
+    Login,
+    LoginFailed,
+    Logout,
+    PasswordChange,
+    PasswordReset,
+    EmailVerification,
+    RoleChange,
+    TwoFactorEnable,
+    TwoFactorDisable,
+    ApiKeyCreate,
+    ApiKeyRevoke,
+    AccountDeletion,
+    QuizCreate,
+    ChallengeCreate,
+    ChallengeDelete,
+}
+
+#[derive(Object, Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    /// The user who acted, if known
+    pub actor_id: Option<Uuid>,
+    pub action: AuditAction,
+    /// The user or content the action affected
+    pub target_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewAuditEvent {
+    pub actor_id: Option<Uuid>,
+    pub action: AuditAction,
+    pub target_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub actor_id: Option<Uuid>,
+    pub action: Option<AuditAction>,
+    pub target_id: Option<Uuid>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn record(pool: &PgPool, event: &NewAuditEvent) -> Result<()> {
+    sqlx::query!(
+        r#"
+            insert into "audit_event" (actor_id, action, target_id, ip, user_agent, details)
+            values ($1, $2, $3, $4, $5, $6)
+        "#,
+        event.actor_id,
+        event.action as _,
+        event.target_id,
+        event.ip,
+        event.user_agent,
+        event.details
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Clears the IP, user agent and details of the events the users did or were affected by,
+// e.g. when they are deleted. The events themselves stay.
+// The trigger only allows this while audit.anonymize is set for the transaction
+#[tracing::instrument(skip(conn))]
+pub async fn anonymize_users(conn: &mut PgConnection, user_ids: &[Uuid]) -> Result<()> {
+    sqlx::query!(r#"select set_config('audit.anonymize', 'on', true)"#)
+        .fetch_one(&mut *conn)
+        .await?;
+    sqlx::query!(
+        r#"
+            update "audit_event" set ip = null, user_agent = null, details = null
+            where (actor_id = any($1) or target_id = any($1))
+                and (ip is not null or user_agent is not null or details is not null)
+        "#,
+        user_ids
+    )
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query!(r#"select set_config('audit.anonymize', 'off', true)"#)
+        .fetch_one(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+// Clears the IP, user agent and details of failed logins with the email hash,
+// they were recorded before an account with the email existed
+#[tracing::instrument(skip(pool))]
+pub async fn anonymize_email(pool: &PgPool, email_hash: &str) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(r#"select set_config('audit.anonymize', 'on', true)"#)
+        .fetch_one(&mut tx)
+        .await?;
+    sqlx::query!(
+        r#"
+            update "audit_event" set ip = null, user_agent = null, details = null
+            where action = 'loginfailed' and details ->> 'email_hash' = $1
+        "#,
+        email_hash
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await
+}
+
+// Returns a page of the events matching the filter, newest first, and the number of all
+// matching events.
+#[tracing::instrument(skip(pool))]
+pub async fn get_events(
+    pool: &PgPool,
+    filter: &AuditFilter,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<AuditEvent>, i64)> {
+    let events = sqlx::query_as!(
+        AuditEvent,
+        r#"
+            select id, created_at, actor_id, action as "action: AuditAction", target_id, ip,
+                user_agent, details
+            from "audit_event"
+            where ($1::uuid is null or actor_id = $1)
+                and ($2::auditaction is null or action = $2)
+                and ($3::uuid is null or target_id = $3)
+                and ($4::timestamptz is null or created_at >= $4)
+                and ($5::timestamptz is null or created_at < $5)
+            order by created_at desc, id desc
+            limit $6 offset $7
+        "#,
+        filter.actor_id,
+        filter.action as _,
+        filter.target_id,
+        filter.from,
+        filter.to,
+        limit,
+        offset
+    )
+    .fetch_all(pool)
+    .await?;
+    let total = sqlx::query_scalar!(
+        r#"
+            select count(*) as "count!" from "audit_event"
+            where ($1::uuid is null or actor_id = $1)
+                and ($2::auditaction is null or action = $2)
+                and ($3::uuid is null or target_id = $3)
+                and ($4::timestamptz is null or created_at >= $4)
+                and ($5::timestamptz is null or created_at < $5)
+        "#,
+        filter.actor_id,
+        filter.action as _,
+        filter.target_id,
+        filter.from,
+        filter.to
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok((events, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::{AuditAction, AuditFilter, NewAuditEvent};
+
+    fn event(actor_id: Uuid, action: AuditAction) -> NewAuditEvent {
+        NewAuditEvent {
+            actor_id: Some(actor_id),
+            action,
+            target_id: None,
+            ip: Some("127.0.0.1".to_owned()),
+            user_agent: None,
+            details: None,
+        }
+    }
+
+    #[sqlx::test]
+    async fn audit_events(pool: PgPool) -> sqlx::Result<()> {
+        let actor = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        super::record(&pool, &event(actor, AuditAction::Login)).await?;
+        super::record(&pool, &event(actor, AuditAction::Logout)).await?;
+        super::record(&pool, &event(other, AuditAction::Login)).await?;
+
+        let (events, total) = super::get_events(&pool, &AuditFilter::default(), 2, 0).await?;
+        assert_eq!(total, 3);
+        assert_eq!(events.len(), 2);
+
+        let filter = AuditFilter {
+            actor_id: Some(actor),
+            ..AuditFilter::default()
+        };
+        let (events, total) = super::get_events(&pool, &filter, 10, 0).await?;
+        assert_eq!(total, 2);
+        assert_eq!(events[0].action, AuditAction::Logout);
+
+        let filter = AuditFilter {
+            action: Some(AuditAction::Login),
+            ..AuditFilter::default()
+        };
+        let (_, total) = super::get_events(&pool, &filter, 10, 0).await?;
+        assert_eq!(total, 2);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn append_only(pool: PgPool) -> sqlx::Result<()> {
+        super::record(&pool, &event(Uuid::new_v4(), AuditAction::Login)).await?;
+        assert!(sqlx::query!(r#"delete from "audit_event""#)
+            .execute(&pool)
+            .await
+            .is_err());
+        assert!(sqlx::query!(r#"update "audit_event" set ip = null"#)
+            .execute(&pool)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn anonymize_deleted_user(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+        let mut login = event(user_id, AuditAction::Login);
+        login.user_agent = Some("Browser".to_owned());
+        login.details = Some(serde_json::json!({ "method": "password" }));
+        super::record(&pool, &login).await?;
+        let failed = NewAuditEvent {
+            actor_id: None,
+            target_id: None,
+            details: Some(serde_json::json!({ "email_hash": "hash" })),
+            ..event(user_id, AuditAction::LoginFailed)
+        };
+        super::record(&pool, &failed).await?;
+        let other = Uuid::new_v4();
+        super::record(&pool, &event(other, AuditAction::Login)).await?;
+
+        crate::core::user::delete_user(&pool, user_id)
+            .await?
+            .unwrap();
+        super::anonymize_email(&pool, "hash").await?;
+        let (events, _) = super::get_events(&pool, &AuditFilter::default(), 10, 0).await?;
+        assert_eq!(events.len(), 3);
+        for event in events {
+            let anonymized =
+                event.ip.is_none() && event.user_agent.is_none() && event.details.is_none();
+            assert_eq!(anonymized, event.actor_id != Some(other));
+        }
+
+        // Nothing else may change, not even while anonymizing
+        let mut tx = pool.begin().await?;
+        sqlx::query!(r#"select set_config('audit.anonymize', 'on', true)"#)
+            .fetch_one(&mut tx)
+            .await?;
+        assert!(
+            sqlx::query!(r#"update "audit_event" set action = 'logout'"#)
+                .execute(&mut tx)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
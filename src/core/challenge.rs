@@ -1,28 +1,46 @@
-use crate::entities::challenge::{Challenge, ChallengeType, UserChallenge};
+use crate::entities::challenge::{
+    Challenge, ChallengeDay, ChallengeTranslation, ChallengeType, ProgressEvent, ProgressRejection,
+    ProgressRejectionReason, ProgressResult, ProgressSource, UserChallenge,
+};
+use chrono::{offset::Utc, DateTime, Duration, NaiveDate};
 use sqlx::{PgPool, Result};
 use uuid::Uuid;
 
-use super::quiz::insert_translation;
+use super::translation::{insert_translation, upsert_translation};
 
 #[tracing::instrument(skip(pool))]
-pub async fn insert_challenge(pool: &PgPool, challenge: &Challenge) -> Result<Uuid> {
-    let description_id = insert_translation(pool, &challenge.description, None).await?;
+pub async fn insert_challenge(
+    pool: &PgPool,
+    challenge: &Challenge,
+    language_code: Option<String>,
+) -> Result<Uuid> {
+    let description_id = insert_translation(pool, &challenge.description, language_code).await?;
     sqlx::query_scalar!(
         r#"
-        insert into "challenge" (type, goal, description, title, category) values ($1, $2, $3, $4, $5)
+        insert into "challenge" (type, goal, description, title, category, reward,
+            max_per_submission, max_per_day, allow_decrease)
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9)
         returning id"#,
         challenge.r#type as _,
         challenge.goal,
         description_id,
         challenge.title,
-        challenge.category
+        challenge.category,
+        challenge.reward,
+        challenge.max_per_submission,
+        challenge.max_per_day,
+        challenge.allow_decrease
     )
     .fetch_one(pool)
     .await
 }
 
 #[tracing::instrument]
-pub async fn get_challenge(pool: &PgPool, id: Uuid) -> Result<Option<Challenge>> {
+pub async fn get_challenge(
+    pool: &PgPool,
+    id: Uuid,
+    languages: &[String],
+) -> Result<Option<Challenge>> {
     sqlx::query_as!(
         Challenge,
         r#"
@@ -32,76 +50,455 @@ pub async fn get_challenge(pool: &PgPool, id: Uuid) -> Result<Option<Challenge>>
                 goal,
                 title,
                 category,
-                content description
+                translation.content as "description!",
+                reward,
+                max_per_submission,
+                max_per_day,
+                allow_decrease
             from challenge challenge
-            inner join translation
-            on challenge.description = translation.id
+            inner join lateral (
+                select content from translation
+                where translation.id = challenge.description
+                order by array_position($2::text[], lower(language_code)) nulls last, language_code
+                limit 1
+            ) translation on true
             where challenge.id = $1
         "#,
-        id
+        id,
+        languages
     )
     .fetch_optional(pool)
     .await
 }
 
+#[derive(Debug)]
+pub enum ProgressOutcome {
+    Added(ProgressResult),
+    /// The challenge or the user does not exist.
+    NotFound,
+    /// The progress breaks the limits of the challenge.
+    Rejected(ProgressRejection),
+}
+
+/// The limits of a challenge for adding progress.
+#[derive(Debug, Clone, Default)]
+pub struct ProgressLimits {
+    pub max_per_submission: Option<i32>,
+    pub max_per_day: Option<i32>,
+    pub allow_decrease: bool,
+}
+
+impl ProgressLimits {
+    /// Checks progress before it is added. `added_today` is the progress added so far today,
+    /// `totals` the progress values it is added to, none of them may go below zero.
+    pub fn check(
+        &self,
+        progress: i32,
+        added_today: i64,
+        totals: &[i32],
+    ) -> std::result::Result<(), ProgressRejection> {
+        let rejection = |reason, limit, remaining| ProgressRejection {
+            reason,
+            limit,
+            remaining,
+        };
+        if progress == 0 {
+            return Err(rejection(ProgressRejectionReason::Zero, None, None));
+        }
+        if progress < 0 && !self.allow_decrease {
+            return Err(rejection(ProgressRejectionReason::Negative, None, None));
+        }
+        if let Some(max) = self.max_per_submission {
+            if progress.unsigned_abs() > max.unsigned_abs() {
+                let reason = ProgressRejectionReason::SubmissionLimit;
+                return Err(rejection(reason, Some(max), None));
+            }
+        }
+        if let Some(max) = self.max_per_day {
+            let remaining = (i64::from(max) - added_today).max(0);
+            if progress > 0 && i64::from(progress) > remaining {
+                let reason = ProgressRejectionReason::DailyLimit;
+                return Err(rejection(reason, Some(max), Some(remaining as i32)));
+            }
+        }
+        if totals
+            .iter()
+            .any(|total| total.checked_add(progress).is_none())
+        {
+            return Err(rejection(ProgressRejectionReason::Overflow, None, None));
+        }
+        let lowest = totals.iter().copied().min().unwrap_or(0);
+        if progress < 0 && i64::from(lowest) + i64::from(progress) < 0 {
+            let reason = ProgressRejectionReason::BelowZero;
+            return Err(rejection(reason, None, Some(lowest.max(0))));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProgressEventFilter {
+    pub challenge_id: Option<Uuid>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+// Records progress in a challenge of the user as an event and adds it to the totals,
+// if the limits of the challenge allow it.
+// Progress of daily challenges also counts for the current day in the user's timezone,
+// the returned progress is the one of today then.
+// Reaching the goal marks the challenge (or the day) as completed and adds the reward of the
+// challenge to the user's score, only once per challenge (or day).
 #[tracing::instrument]
 pub async fn add_progress(
     pool: &PgPool,
     user_id: Uuid,
     challenge_id: Uuid,
     progress: i32,
-) -> Result<Option<UserChallenge>> {
-    sqlx::query_as!(
-        UserChallenge,
+    source: ProgressSource,
+    note: Option<&str>,
+) -> Result<ProgressOutcome> {
+    let mut tx = pool.begin().await?;
+    // Locking the user runs their submissions one after another, so limits cannot be raced
+    let Some(timezone) = sqlx::query_scalar!(
+        r#"select timezone from "user" where id = $1 for update"#,
+        user_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    else {
+        return Ok(ProgressOutcome::NotFound);
+    };
+    let Some(limits) = sqlx::query_as!(
+        ProgressLimits,
+        r#"
+            select max_per_submission, max_per_day, allow_decrease
+            from "challenge" where id = $1
+        "#,
+        challenge_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    else {
+        return Ok(ProgressOutcome::NotFound);
+    };
+    let current = sqlx::query!(
+        r#"
+            select
+                coalesce((
+                    select progress from "user_challenge"
+                    where user_id = $1 and challenge_id = $2
+                ), 0) as "total!",
+                coalesce((
+                    select progress from "user_challenge_day"
+                    where user_id = $1 and challenge_id = $2
+                        and day = (now() at time zone $3)::date
+                ), 0) as "day_total!",
+                exists(
+                    select 1 from "challenge" where id = $2 and type = 'dailychallenge'
+                ) as "daily!",
+                coalesce((
+                    select sum(amount) from "challenge_progress_event"
+                    where user_id = $1 and challenge_id = $2 and source <> 'reset'
+                        and day = (now() at time zone $3)::date
+                ), 0) as "added_today!"
+        "#,
+        user_id,
+        challenge_id,
+        timezone
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    // Only daily challenges keep progress per day
+    let mut totals = vec![current.total];
+    if current.daily {
+        totals.push(current.day_total);
+    }
+    if let Err(rejection) = limits.check(progress, current.added_today, &totals) {
+        return Ok(ProgressOutcome::Rejected(rejection));
+    }
+
+    // The event keeps its date, changing the timezone later does not move it to another day
+    sqlx::query!(
+        r#"
+            insert into challenge_progress_event
+                (user_id, challenge_id, amount, source, note, day)
+            values ($1, $2, $3, $4, $5, (now() at time zone $6)::date)
+        "#,
+        user_id,
+        challenge_id,
+        progress,
+        source as _,
+        note,
+        timezone
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
         r#"
             insert into user_challenge (user_id, challenge_id, progress)
             values ($1, $2, $3)
             on conflict on constraint one_user_per_challenge
             do update set progress = user_challenge.progress + EXCLUDED.progress
-            returning *
         "#,
         user_id,
         challenge_id,
         progress
     )
-    .fetch_optional(pool)
-    .await
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+            insert into user_challenge_day (user_id, challenge_id, day, progress)
+            select "user".id, challenge.id, (now() at time zone "user".timezone)::date, $3
+            from "user", "challenge"
+            where "user".id = $1 and challenge.id = $2 and challenge.type = 'dailychallenge'
+            on conflict (user_id, challenge_id, day)
+            do update set progress = user_challenge_day.progress + EXCLUDED.progress
+        "#,
+        user_id,
+        challenge_id,
+        progress
+    )
+    .execute(&mut tx)
+    .await?;
+
+    let counter_completion = sqlx::query!(
+        r#"
+            update user_challenge set completed_at = now()
+            from "challenge"
+            where user_challenge.user_id = $1 and user_challenge.challenge_id = $2
+                and challenge.id = user_challenge.challenge_id and challenge.type = 'counter'
+                and user_challenge.completed_at is null
+                and user_challenge.progress >= challenge.goal
+            returning challenge.reward
+        "#,
+        user_id,
+        challenge_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .map(|record| (record.reward, None));
+    let daily_completion = sqlx::query!(
+        r#"
+            update user_challenge_day set completed_at = now()
+            from "challenge", "user"
+            where user_challenge_day.user_id = $1 and user_challenge_day.challenge_id = $2
+                and "user".id = user_challenge_day.user_id
+                and user_challenge_day.day = (now() at time zone "user".timezone)::date
+                and challenge.id = user_challenge_day.challenge_id
+                and challenge.type = 'dailychallenge'
+                and user_challenge_day.completed_at is null
+                and user_challenge_day.progress >= challenge.goal
+            returning challenge.reward, user_challenge_day.day
+        "#,
+        user_id,
+        challenge_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .map(|record| (record.reward, Some(record.day)));
+
+    let completion = counter_completion.or(daily_completion);
+    let mut reward = 0;
+    if let Some((challenge_reward, day)) = completion {
+        let awarded = sqlx::query_scalar!(
+            r#"
+                insert into challenge_reward (user_id, challenge_id, day, reward)
+                values ($1, $2, $3, $4)
+                on conflict do nothing
+                returning reward
+            "#,
+            user_id,
+            challenge_id,
+            day,
+            challenge_reward
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        if let Some(awarded) = awarded {
+            sqlx::query!(
+                r#"update "user" set score = score + $1 where id = $2"#,
+                awarded,
+                user_id
+            )
+            .execute(&mut tx)
+            .await?;
+            reward = awarded;
+        }
+    }
+    tx.commit().await?;
+
+    let challenge = get_user_challenges(pool, user_id, Some(challenge_id))
+        .await?
+        .into_iter()
+        .next();
+    Ok(match challenge {
+        Some(challenge) => ProgressOutcome::Added(ProgressResult {
+            challenge,
+            just_completed: completion.is_some(),
+            reward,
+        }),
+        None => ProgressOutcome::NotFound,
+    })
 }
 
+// Returns the progress of the user in all or a single challenge.
+// Daily challenges show the progress of today and the user's streaks.
 #[tracing::instrument]
 pub async fn get_user_challenges(
     pool: &PgPool,
     user_id: Uuid,
     challenge_id: Option<Uuid>,
 ) -> Result<Vec<UserChallenge>> {
-    if let Some(challenge_id) = challenge_id {
-        sqlx::query_as!(
-            UserChallenge,
-            r#"
-                select * from "user_challenge"
-                where challenge_id = $1 and user_id = $2
-            "#,
-            challenge_id,
-            user_id
-        )
-        .fetch_all(pool)
-        .await
-    } else {
-        sqlx::query_as!(
-            UserChallenge,
-            r#"
-                select * from "user_challenge"
-                where user_id = $1
-            "#,
-            user_id
-        )
-        .fetch_all(pool)
-        .await
+    let mut challenges = sqlx::query_as!(
+        UserChallenge,
+        r#"
+            select
+                user_challenge.user_id,
+                user_challenge.challenge_id,
+                case when challenge.type = 'dailychallenge' then coalesce(today.progress, 0)
+                    else user_challenge.progress
+                end as "progress!",
+                user_challenge.updated_at,
+                case when challenge.type = 'dailychallenge' then today.completed_at
+                    else user_challenge.completed_at
+                end as completed_at,
+                case when challenge.type = 'dailychallenge'
+                    then (now() at time zone "user".timezone)::date
+                end as day,
+                null::int as current_streak,
+                null::int as longest_streak
+            from "user_challenge"
+            inner join "user" on "user".id = user_challenge.user_id
+            left join "challenge" on challenge.id = user_challenge.challenge_id
+            left join "user_challenge_day" today
+                on today.user_id = user_challenge.user_id
+                and today.challenge_id = user_challenge.challenge_id
+                and today.day = (now() at time zone "user".timezone)::date
+            where user_challenge.user_id = $1
+                and ($2::uuid is null or user_challenge.challenge_id = $2)
+        "#,
+        user_id,
+        challenge_id
+    )
+    .fetch_all(pool)
+    .await?;
+    if challenges.iter().any(|challenge| challenge.day.is_some()) {
+        let days = get_challenge_days(pool, user_id, challenge_id, None).await?;
+        for challenge in challenges.iter_mut() {
+            let Some(today) = challenge.day else {
+                continue;
+            };
+            let completed = days
+                .iter()
+                .filter(|day| day.challenge_id == challenge.challenge_id && day.completed)
+                .map(|day| day.day)
+                .collect();
+            let (current, longest) = streaks(completed, today);
+            challenge.current_streak = Some(current);
+            challenge.longest_streak = Some(longest);
+        }
     }
+    Ok(challenges)
+}
+
+// Returns the progress per day in daily challenges of the user, newest first.
+#[tracing::instrument(skip(pool))]
+pub async fn get_challenge_days(
+    pool: &PgPool,
+    user_id: Uuid,
+    challenge_id: Option<Uuid>,
+    limit: Option<i64>,
+) -> Result<Vec<ChallengeDay>> {
+    sqlx::query_as!(
+        ChallengeDay,
+        r#"
+            select
+                user_challenge_day.challenge_id,
+                user_challenge_day.day,
+                user_challenge_day.progress,
+                user_challenge_day.progress >= challenge.goal as "completed!"
+            from "user_challenge_day"
+            inner join "challenge" on challenge.id = user_challenge_day.challenge_id
+            where user_challenge_day.user_id = $1
+                and ($2::uuid is null or user_challenge_day.challenge_id = $2)
+            order by user_challenge_day.day desc, user_challenge_day.challenge_id
+            limit $3
+        "#,
+        user_id,
+        challenge_id,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+// Returns the progress events of the user matching the filter in chronological order, the newest
+// ones if there are more than `limit`. Totals are the progress after each event.
+#[tracing::instrument(skip(pool))]
+pub async fn get_progress_events(
+    pool: &PgPool,
+    user_id: Uuid,
+    filter: &ProgressEventFilter,
+    limit: Option<i64>,
+) -> Result<Vec<ProgressEvent>> {
+    let mut events = sqlx::query_as!(
+        ProgressEvent,
+        r#"
+            select id as "id!", challenge_id as "challenge_id!", amount as "amount!",
+                total as "total!", created_at as "created_at!",
+                source as "source!: ProgressSource", note
+            from (
+                select id, challenge_id, amount, created_at, source, note,
+                    sum(amount) over (
+                        partition by challenge_id order by created_at, id
+                    ) as total
+                from "challenge_progress_event"
+                where user_id = $1 and ($2::uuid is null or challenge_id = $2)
+            ) events
+            where ($3::timestamptz is null or created_at >= $3)
+                and ($4::timestamptz is null or created_at < $4)
+            order by created_at desc, id desc
+            limit $5
+        "#,
+        user_id,
+        filter.challenge_id,
+        filter.from,
+        filter.to,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    events.reverse();
+    Ok(events)
+}
+
+// Returns the current and the longest streak of consecutive days in which a daily challenge
+// was completed. Today not being completed yet does not break the current streak.
+fn streaks(mut completed: Vec<NaiveDate>, today: NaiveDate) -> (i32, i32) {
+    completed.sort_unstable();
+    completed.dedup();
+    let mut longest = 0;
+    let mut run = 0;
+    let mut previous: Option<NaiveDate> = None;
+    for &day in &completed {
+        run = match previous {
+            Some(previous) if day - previous == Duration::days(1) => run + 1,
+            _ => 1,
+        };
+        longest = longest.max(run);
+        previous = Some(day);
+    }
+    let current = match previous {
+        Some(last) if last == today || last == today - Duration::days(1) => run,
+        _ => 0,
+    };
+    (current, longest)
 }
 
 #[tracing::instrument]
-pub async fn get_challenges(pool: &PgPool) -> Result<Vec<Challenge>> {
+pub async fn get_challenges(pool: &PgPool, languages: &[String]) -> Result<Vec<Challenge>> {
     Ok(sqlx::query!(
         r#"
         select 
@@ -110,11 +507,20 @@ pub async fn get_challenges(pool: &PgPool) -> Result<Vec<Challenge>> {
             goal,
             title,
             category,
-            content
+            translation.content as "content!",
+            reward,
+            max_per_submission,
+            max_per_day,
+            allow_decrease
         from "challenge"
-        inner join translation
-        on challenge.description = translation.id
-   "#
+        inner join lateral (
+            select content from translation
+            where translation.id = challenge.description
+            order by array_position($1::text[], lower(language_code)) nulls last, language_code
+            limit 1
+        ) translation on true
+   "#,
+        languages
     )
     .fetch_all(pool)
     .await?
@@ -126,18 +532,48 @@ pub async fn get_challenges(pool: &PgPool) -> Result<Vec<Challenge>> {
         category: record.category,
         goal: record.goal,
         description: record.content,
+        reward: record.reward,
+        max_per_submission: record.max_per_submission,
+        max_per_day: record.max_per_day,
+        allow_decrease: record.allow_decrease,
     })
     .collect())
 }
 
+// Adds or replaces the description of a challenge in the given language.
+// Returns Ok(None) if the challenge does not exist
+#[tracing::instrument(skip(pool))]
+pub async fn translate_challenge(
+    pool: &PgPool,
+    id: Uuid,
+    language_code: &str,
+    translation: &ChallengeTranslation,
+) -> Result<Option<()>> {
+    let Some(description_id) =
+        sqlx::query_scalar!(r#"select description from "challenge" where id = $1"#, id)
+            .fetch_optional(pool)
+            .await?
+    else {
+        return Ok(None);
+    };
+    upsert_translation(
+        pool,
+        description_id,
+        language_code,
+        &translation.description,
+    )
+    .await?;
+    Ok(Some(()))
+}
+
 #[tracing::instrument]
 pub async fn delete_progress(
     pool: &PgPool,
     user_id: Uuid,
     challenge_id: Uuid,
 ) -> Result<Option<UserChallenge>> {
-    sqlx::query_as!(
-        UserChallenge,
+    let mut tx = pool.begin().await?;
+    let deleted = sqlx::query!(
         r#"
             delete from user_challenge
             where user_id = $1 and challenge_id = $2
@@ -146,19 +582,554 @@ pub async fn delete_progress(
         user_id,
         challenge_id
     )
-    .fetch_optional(pool)
-    .await
+    .fetch_optional(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"delete from user_challenge_day where user_id = $1 and challenge_id = $2"#,
+        user_id,
+        challenge_id
+    )
+    .execute(&mut tx)
+    .await?;
+    // Events are never deleted, a reset brings the total back to zero
+    sqlx::query!(
+        r#"
+            insert into challenge_progress_event (user_id, challenge_id, amount, source, day)
+            select $1, $2, -sum(amount), 'reset',
+                (now() at time zone (select timezone from "user" where id = $1))::date
+            from challenge_progress_event
+            where user_id = $1 and challenge_id = $2
+            having sum(amount) <> 0
+        "#,
+        user_id,
+        challenge_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(deleted.map(|record| UserChallenge {
+        user_id: record.user_id,
+        challenge_id: record.challenge_id,
+        progress: record.progress,
+        updated_at: record.updated_at,
+        completed_at: record.completed_at,
+        ..UserChallenge::default()
+    }))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeleteChallengeOutcome {
+    Deleted,
+    NotFound,
+    /// Progress was recorded for the challenge, its events cannot be deleted.
+    HasProgress,
+}
+
+// Deletes a challenge together with its translations, unless progress was recorded for it.
+#[tracing::instrument(skip(pool))]
+pub async fn delete_challenge(pool: &PgPool, id: Uuid) -> Result<DeleteChallengeOutcome> {
+    let mut tx = pool.begin().await?;
+    let has_progress = sqlx::query_scalar!(
+        r#"
+            select exists(
+                select 1 from "challenge_progress_event" where challenge_id = $1
+            ) as "exists!"
+        "#,
+        id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    if has_progress {
+        return Ok(DeleteChallengeOutcome::HasProgress);
+    }
+    sqlx::query!(
+        r#"delete from "user_challenge" where challenge_id = $1"#,
+        id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"delete from "user_challenge_day" where challenge_id = $1"#,
+        id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"delete from "challenge_reward" where challenge_id = $1"#,
+        id
+    )
+    .execute(&mut tx)
+    .await?;
+    // Progress references the challenge, so it goes first. Nothing is deleted if the
+    // challenge does not exist, since the transaction is not committed then
+    let Some(description_id) = sqlx::query_scalar!(
+        r#"delete from "challenge" where id = $1 returning description"#,
+        id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    else {
+        return Ok(DeleteChallengeOutcome::NotFound);
+    };
+    sqlx::query!(r#"delete from "translation" where id = $1"#, description_id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(
+        r#"delete from "translation_key" where id = $1"#,
+        description_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(DeleteChallengeOutcome::Deleted)
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::core::translation::language_preferences;
+
+    async fn add(
+        pool: &PgPool,
+        user_id: Uuid,
+        id: Uuid,
+        progress: i32,
+    ) -> sqlx::Result<ProgressResult> {
+        match super::add_progress(pool, user_id, id, progress, ProgressSource::App, None).await? {
+            ProgressOutcome::Added(result) => Ok(result),
+            outcome => panic!("progress was not added: {:?}", outcome),
+        }
+    }
 
     #[sqlx::test]
     async fn insert_challenge(pool: PgPool) -> sqlx::Result<()> {
-        let res = super::insert_challenge(&pool, &Challenge::default()).await;
+        let res = super::insert_challenge(&pool, &Challenge::default(), None).await;
         assert!(res.is_err());
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn translate_challenge(pool: PgPool) -> sqlx::Result<()> {
+        let challenge = Challenge {
+            description: "Save energy".to_owned(),
+            goal: 1,
+            ..Challenge::default()
+        };
+        let id = super::insert_challenge(&pool, &challenge, None).await?;
+        let translation = ChallengeTranslation {
+            description: "Spare Energie".to_owned(),
+        };
+        super::translate_challenge(&pool, id, "de", &translation)
+            .await?
+            .unwrap();
+
+        let languages = language_preferences(Some("de-CH"), None);
+        let german = super::get_challenge(&pool, id, &languages).await?.unwrap();
+        assert_eq!(german.description, "Spare Energie");
+        let languages = language_preferences(None, None);
+        let english = super::get_challenge(&pool, id, &languages).await?.unwrap();
+        assert_eq!(english.description, "Save energy");
+
+        let missing = super::translate_challenge(&pool, Uuid::new_v4(), "de", &translation).await?;
+        assert!(missing.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn delete_challenge(pool: PgPool) -> sqlx::Result<()> {
+        let challenge = Challenge {
+            description: "Save energy".to_owned(),
+            goal: 1,
+            ..Challenge::default()
+        };
+        let id = super::insert_challenge(&pool, &challenge, None).await?;
+        let user_id = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+        add(&pool, user_id, id, 1).await?;
+
+        let outcome = super::delete_challenge(&pool, id).await?;
+        assert_eq!(outcome, DeleteChallengeOutcome::HasProgress);
+        let languages = language_preferences(None, None);
+        assert!(super::get_challenge(&pool, id, &languages).await?.is_some());
+
+        let unused = super::insert_challenge(&pool, &challenge, None).await?;
+        let outcome = super::delete_challenge(&pool, unused).await?;
+        assert_eq!(outcome, DeleteChallengeOutcome::Deleted);
+        assert!(super::get_challenge(&pool, unused, &languages)
+            .await?
+            .is_none());
+        let outcome = super::delete_challenge(&pool, unused).await?;
+        assert_eq!(outcome, DeleteChallengeOutcome::NotFound);
+        Ok(())
+    }
+
+    fn date(day: &str) -> NaiveDate {
+        day.parse().unwrap()
+    }
+
+    #[test]
+    fn streaks() {
+        let today = date("2023-03-10");
+        assert_eq!(super::streaks(vec![], today), (0, 0));
+        let days = vec![date("2023-03-09"), date("2023-03-08"), date("2023-03-01")];
+        assert_eq!(super::streaks(days.clone(), today), (2, 2));
+        let mut with_today = days.clone();
+        with_today.push(today);
+        assert_eq!(super::streaks(with_today, today), (3, 3));
+        assert_eq!(super::streaks(days, date("2023-03-11")), (0, 2));
+    }
+
+    #[sqlx::test]
+    async fn daily_progress(pool: PgPool) -> sqlx::Result<()> {
+        let challenge = Challenge {
+            description: "Take the bike".to_owned(),
+            r#type: ChallengeType::DailyChallenge,
+            goal: 2,
+            ..Challenge::default()
+        };
+        let id = super::insert_challenge(&pool, &challenge, None).await?;
+        let user_id = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+
+        let progress = add(&pool, user_id, id, 1).await?;
+        assert_eq!(progress.challenge.progress, 1);
+        assert_eq!(progress.challenge.current_streak, Some(0));
+        let today = progress.challenge.day.unwrap();
+        let progress = add(&pool, user_id, id, 1).await?;
+        assert_eq!(progress.challenge.progress, 2);
+        assert_eq!(progress.challenge.current_streak, Some(1));
+
+        // Yesterday's progress is kept, but does not count for today
+        sqlx::query!(
+            r#"
+                insert into user_challenge_day (user_id, challenge_id, day, progress)
+                values ($1, $2, $3, 5), ($1, $2, $4, 1)
+            "#,
+            user_id,
+            id,
+            today - Duration::days(1),
+            today - Duration::days(2)
+        )
+        .execute(&pool)
+        .await?;
+        let progress = super::get_user_challenges(&pool, user_id, Some(id)).await?;
+        assert_eq!(progress[0].progress, 2);
+        assert_eq!(progress[0].current_streak, Some(2));
+        assert_eq!(progress[0].longest_streak, Some(2));
+
+        let days = super::get_challenge_days(&pool, user_id, Some(id), Some(2)).await?;
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].day, today);
+        assert!(days[1].completed);
+
+        assert!(super::delete_progress(&pool, user_id, id).await?.is_some());
+        assert!(super::get_challenge_days(&pool, user_id, Some(id), None)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn counter_progress(pool: PgPool) -> sqlx::Result<()> {
+        let challenge = Challenge {
+            description: "Save energy".to_owned(),
+            goal: 10,
+            ..Challenge::default()
+        };
+        let id = super::insert_challenge(&pool, &challenge, None).await?;
+        let user_id = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+        add(&pool, user_id, id, 3).await?;
+        let progress = add(&pool, user_id, id, 4).await?;
+        assert_eq!(progress.challenge.progress, 7);
+        assert!(progress.challenge.day.is_none());
+        assert!(progress.challenge.current_streak.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn completion_reward(pool: PgPool) -> sqlx::Result<()> {
+        let challenge = Challenge {
+            description: "Plant trees".to_owned(),
+            goal: 3,
+            reward: 50,
+            ..Challenge::default()
+        };
+        let id = super::insert_challenge(&pool, &challenge, None).await?;
+        let user_id = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+
+        let progress = add(&pool, user_id, id, 2).await?;
+        assert!(!progress.just_completed);
+        assert!(progress.challenge.completed_at.is_none());
+        let progress = add(&pool, user_id, id, 2).await?;
+        assert!(progress.just_completed);
+        assert_eq!(progress.reward, 50);
+        assert!(progress.challenge.completed_at.is_some());
+        let progress = add(&pool, user_id, id, 1).await?;
+        assert!(!progress.just_completed);
+        assert_eq!(progress.reward, 0);
+
+        // Starting over completes the challenge again, but is not rewarded again
+        super::delete_progress(&pool, user_id, id).await?.unwrap();
+        let progress = add(&pool, user_id, id, 3).await?;
+        assert!(progress.just_completed);
+        assert_eq!(progress.reward, 0);
+        let user = crate::core::user::get_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.score, 50);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn daily_completion_reward(pool: PgPool) -> sqlx::Result<()> {
+        let challenge = Challenge {
+            description: "Take the bike".to_owned(),
+            r#type: ChallengeType::DailyChallenge,
+            goal: 1,
+            reward: 10,
+            ..Challenge::default()
+        };
+        let id = super::insert_challenge(&pool, &challenge, None).await?;
+        let user_id = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+        sqlx::query!(
+            r#"
+                insert into user_challenge_day (user_id, challenge_id, day, progress, completed_at)
+                values ($1, $2, current_date - 1, 1, now() - interval '1 day')
+            "#,
+            user_id,
+            id
+        )
+        .execute(&pool)
+        .await?;
+
+        let progress = add(&pool, user_id, id, 1).await?;
+        assert!(progress.just_completed);
+        assert_eq!(progress.reward, 10);
+        let progress = add(&pool, user_id, id, 1).await?;
+        assert!(!progress.just_completed);
+        let user = crate::core::user::get_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.score, 10);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn progress_events(pool: PgPool) -> sqlx::Result<()> {
+        let challenge = Challenge {
+            description: "Save energy".to_owned(),
+            goal: 100,
+            allow_decrease: true,
+            ..Challenge::default()
+        };
+        let id = super::insert_challenge(&pool, &challenge, None).await?;
+        let user_id = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+        add(&pool, user_id, id, 3).await?;
+        super::add_progress(
+            &pool,
+            user_id,
+            id,
+            4,
+            ProgressSource::ApiKey,
+            Some("Smart meter"),
+        )
+        .await?;
+        let progress = add(&pool, user_id, id, -2).await?;
+
+        let filter = ProgressEventFilter::default();
+        let events = super::get_progress_events(&pool, user_id, &filter, None).await?;
+        let totals: Vec<i64> = events.iter().map(|event| event.total).collect();
+        assert_eq!(totals, [3, 7, 5]);
+        assert_eq!(events[2].total, i64::from(progress.challenge.progress));
+        assert_eq!(events[1].source, ProgressSource::ApiKey);
+        assert_eq!(events[1].note.as_deref(), Some("Smart meter"));
+
+        // The newest events keep the totals of all events
+        let events = super::get_progress_events(&pool, user_id, &filter, Some(2)).await?;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].total, 7);
+
+        assert!(
+            sqlx::query!(r#"update challenge_progress_event set amount = 100"#)
+                .execute(&pool)
+                .await
+                .is_err()
+        );
+
+        assert!(sqlx::query!(r#"delete from challenge_progress_event"#)
+            .execute(&pool)
+            .await
+            .is_err());
+
+        super::delete_progress(&pool, user_id, id).await?.unwrap();
+        let events = super::get_progress_events(&pool, user_id, &filter, None).await?;
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[3].source, ProgressSource::Reset);
+        assert_eq!(events[3].amount, -5);
+        assert_eq!(events[3].total, 0);
+
+        crate::core::user::delete_user(&pool, user_id)
+            .await?
+            .unwrap();
+        let events = super::get_progress_events(&pool, user_id, &filter, None).await?;
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn progress_limits() {
+        let limits = ProgressLimits {
+            max_per_submission: Some(10),
+            max_per_day: Some(15),
+            allow_decrease: false,
+        };
+        let reason =
+            |result: std::result::Result<(), ProgressRejection>| result.unwrap_err().reason;
+        assert_eq!(
+            reason(limits.check(0, 0, &[0])),
+            ProgressRejectionReason::Zero
+        );
+        assert_eq!(
+            reason(limits.check(-1, 0, &[5])),
+            ProgressRejectionReason::Negative
+        );
+        assert_eq!(
+            reason(limits.check(11, 0, &[0])),
+            ProgressRejectionReason::SubmissionLimit
+        );
+        assert!(limits.check(10, 5, &[5]).is_ok());
+        let rejection = limits.check(10, 6, &[6]).unwrap_err();
+        assert_eq!(rejection.reason, ProgressRejectionReason::DailyLimit);
+        assert_eq!(rejection.remaining, Some(9));
+        assert_eq!(
+            reason(limits.check(1, 0, &[i32::MAX])),
+            ProgressRejectionReason::Overflow
+        );
+
+        let limits = ProgressLimits {
+            allow_decrease: true,
+            ..ProgressLimits::default()
+        };
+        assert!(limits.check(-5, 100, &[5]).is_ok());
+        let rejection = limits.check(-6, 0, &[10, 5]).unwrap_err();
+        assert_eq!(rejection.reason, ProgressRejectionReason::BelowZero);
+        assert_eq!(rejection.remaining, Some(5));
+    }
+
+    #[sqlx::test]
+    async fn decrease_below_zero(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+        for r#type in [ChallengeType::Counter, ChallengeType::DailyChallenge] {
+            let challenge = Challenge {
+                description: "Save energy".to_owned(),
+                goal: 100,
+                allow_decrease: true,
+                r#type,
+                ..Challenge::default()
+            };
+            let id = super::insert_challenge(&pool, &challenge, None).await?;
+            add(&pool, user_id, id, 3).await?;
+            let outcome =
+                super::add_progress(&pool, user_id, id, -4, ProgressSource::App, None).await?;
+            let ProgressOutcome::Rejected(rejection) = outcome else {
+                panic!("progress was not rejected: {:?}", outcome);
+            };
+            assert_eq!(rejection.reason, ProgressRejectionReason::BelowZero);
+            assert_eq!(rejection.remaining, Some(3));
+
+            let progress = add(&pool, user_id, id, -3).await?;
+            assert_eq!(progress.challenge.progress, 0);
+        }
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn progress_limit_rejection(pool: PgPool) -> sqlx::Result<()> {
+        let challenge = Challenge {
+            description: "Save energy".to_owned(),
+            goal: 100,
+            max_per_day: Some(5),
+            ..Challenge::default()
+        };
+        let id = super::insert_challenge(&pool, &challenge, None).await?;
+        let user_id = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+        add(&pool, user_id, id, 4).await?;
+        let outcome = super::add_progress(&pool, user_id, id, 2, ProgressSource::App, None).await?;
+        let ProgressOutcome::Rejected(rejection) = outcome else {
+            panic!("progress was not rejected: {:?}", outcome);
+        };
+        assert_eq!(rejection.reason, ProgressRejectionReason::DailyLimit);
+        assert_eq!(rejection.remaining, Some(1));
+
+        // Removing the progress does not reset the daily limit
+        super::delete_progress(&pool, user_id, id).await?.unwrap();
+        let outcome = super::add_progress(&pool, user_id, id, 2, ProgressSource::App, None).await?;
+        assert!(matches!(outcome, ProgressOutcome::Rejected(_)));
+
+        let outcome =
+            super::add_progress(&pool, user_id, Uuid::new_v4(), 1, ProgressSource::App, None)
+                .await?;
+        assert!(matches!(outcome, ProgressOutcome::NotFound));
+        let events =
+            super::get_progress_events(&pool, user_id, &ProgressEventFilter::default(), None)
+                .await?;
+        assert_eq!(events.len(), 2);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn daily_limit_after_timezone_change(pool: PgPool) -> sqlx::Result<()> {
+        let challenge = Challenge {
+            description: "Save energy".to_owned(),
+            goal: 100,
+            max_per_day: Some(5),
+            ..Challenge::default()
+        };
+        let id = super::insert_challenge(&pool, &challenge, None).await?;
+        let user_id = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+        add(&pool, user_id, id, 5).await?;
+
+        // One hour east or west is always on the same date
+        let mut timezone = None;
+        for candidate in ["Etc/GMT+1", "Etc/GMT-1"] {
+            if crate::core::user::keeps_local_date(&pool, user_id, candidate)
+                .await?
+                .unwrap()
+            {
+                timezone = Some(candidate.to_owned());
+            }
+        }
+        let patch = crate::core::user::UserPatch {
+            timezone,
+            ..Default::default()
+        };
+        crate::core::user::update_user(&pool, user_id, &patch)
+            .await?
+            .unwrap();
+        let outcome = super::add_progress(&pool, user_id, id, 1, ProgressSource::App, None).await?;
+        let ProgressOutcome::Rejected(rejection) = outcome else {
+            panic!("progress was not rejected: {:?}", outcome);
+        };
+        assert_eq!(rejection.reason, ProgressRejectionReason::DailyLimit);
+
+        // 26 hours apart, at least one of them is on another date
+        let east =
+            crate::core::user::keeps_local_date(&pool, user_id, "Pacific/Kiritimati").await?;
+        let west = crate::core::user::keeps_local_date(&pool, user_id, "Etc/GMT+12").await?;
+        assert!(!(east.unwrap() && west.unwrap()));
+        Ok(())
+    }
 }
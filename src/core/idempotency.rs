@@ -0,0 +1,192 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{offset::Utc, Duration};
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Result};
+use uuid::Uuid;
+
+/// Responses are replayed for retries within this many hours.
+pub const IDEMPOTENCY_WINDOW_HOURS: i64 = 24;
+/// A request still unfinished after this many seconds is assumed to have crashed,
+/// a retry may claim its key again.
+pub const IN_PROGRESS_TIMEOUT_SECONDS: i64 = 60;
+
+/// A response stored for an idempotency key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredResponse {
+    pub status: i16,
+    /// The JSON body, if the response has one
+    pub body: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClaimOutcome {
+    /// The key is new, the request has to be processed and its response stored.
+    Claimed,
+    /// A request with the key was already processed.
+    Completed(StoredResponse),
+    /// A request with the key is still being processed.
+    InProgress,
+    /// The key was used for a different request.
+    Mismatch,
+}
+
+/// Identifies a request, e.g. the endpoint followed by the JSON body.
+pub fn request_hash(request: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(request.as_bytes()))
+}
+
+// Claims the key of the user for a request before it is processed.
+// Keys outside of the window are forgotten first.
+#[tracing::instrument(skip(pool))]
+pub async fn claim(
+    pool: &PgPool,
+    user_id: Uuid,
+    key: &str,
+    request_hash: &str,
+) -> Result<ClaimOutcome> {
+    sqlx::query!(
+        r#"delete from "idempotency_key" where user_id = $1 and created_at < $2"#,
+        user_id,
+        Utc::now() - Duration::hours(IDEMPOTENCY_WINDOW_HOURS)
+    )
+    .execute(pool)
+    .await?;
+    let claimed = sqlx::query_scalar!(
+        r#"
+            insert into "idempotency_key" (user_id, key, request_hash) values ($1, $2, $3)
+            on conflict (user_id, key) do update
+            set request_hash = excluded.request_hash, created_at = now()
+            where "idempotency_key".status is null and "idempotency_key".created_at < $4
+            returning true as "claimed!"
+        "#,
+        user_id,
+        key,
+        request_hash,
+        Utc::now() - Duration::seconds(IN_PROGRESS_TIMEOUT_SECONDS)
+    )
+    .fetch_optional(pool)
+    .await?;
+    if claimed.is_some() {
+        return Ok(ClaimOutcome::Claimed);
+    }
+
+    let existing = sqlx::query!(
+        r#"
+            select request_hash, status, body from "idempotency_key"
+            where user_id = $1 and key = $2
+        "#,
+        user_id,
+        key
+    )
+    .fetch_optional(pool)
+    .await?;
+    // The key was released by the failed original request in the meantime
+    let Some(existing) = existing else {
+        return Ok(ClaimOutcome::InProgress);
+    };
+    Ok(if existing.request_hash != request_hash {
+        ClaimOutcome::Mismatch
+    } else if let Some(status) = existing.status {
+        ClaimOutcome::Completed(StoredResponse {
+            status,
+            body: existing.body,
+        })
+    } else {
+        ClaimOutcome::InProgress
+    })
+}
+
+// Stores the response of the request that claimed the key.
+#[tracing::instrument(skip(pool))]
+pub async fn complete(
+    pool: &PgPool,
+    user_id: Uuid,
+    key: &str,
+    response: &StoredResponse,
+) -> Result<()> {
+    sqlx::query!(
+        r#"update "idempotency_key" set status = $3, body = $4 where user_id = $1 and key = $2"#,
+        user_id,
+        key,
+        response.status,
+        response.body
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Releases a claimed key without a response, so that the request can be retried.
+#[tracing::instrument(skip(pool))]
+pub async fn release(pool: &PgPool, user_id: Uuid, key: &str) -> Result<()> {
+    sqlx::query!(
+        r#"delete from "idempotency_key" where user_id = $1 and key = $2 and status is null"#,
+        user_id,
+        key
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::{ClaimOutcome, StoredResponse};
+
+    #[sqlx::test]
+    async fn claim_and_replay(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+        let hash = super::request_hash("POST /api/quiz {}");
+        let outcome = super::claim(&pool, user_id, "retry", &hash).await?;
+        assert_eq!(outcome, ClaimOutcome::Claimed);
+        let outcome = super::claim(&pool, user_id, "retry", &hash).await?;
+        assert_eq!(outcome, ClaimOutcome::InProgress);
+
+        let response = StoredResponse {
+            status: 201,
+            body: Some("\"quiz\"".to_owned()),
+        };
+        super::complete(&pool, user_id, "retry", &response).await?;
+        let outcome = super::claim(&pool, user_id, "retry", &hash).await?;
+        assert_eq!(outcome, ClaimOutcome::Completed(response));
+        let other = super::request_hash("POST /api/challenge {}");
+        let outcome = super::claim(&pool, user_id, "retry", &other).await?;
+        assert_eq!(outcome, ClaimOutcome::Mismatch);
+
+        // Other users have their own keys
+        let other_user = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+        let outcome = super::claim(&pool, other_user, "retry", &hash).await?;
+        assert_eq!(outcome, ClaimOutcome::Claimed);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn released_and_expired_keys(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = crate::core::user::insert_user(&pool, &Default::default())
+            .await?
+            .unwrap();
+        let hash = super::request_hash("POST /api/quiz {}");
+        super::claim(&pool, user_id, "failed", &hash).await?;
+        super::release(&pool, user_id, "failed").await?;
+        let outcome = super::claim(&pool, user_id, "failed", &hash).await?;
+        assert_eq!(outcome, ClaimOutcome::Claimed);
+
+        let response = StoredResponse {
+            status: 201,
+            body: None,
+        };
+        super::complete(&pool, user_id, "failed", &response).await?;
+        sqlx::query!(r#"update "idempotency_key" set created_at = now() - interval '25 hours'"#)
+            .execute(&pool)
+            .await?;
+        let outcome = super::claim(&pool, user_id, "failed", &hash).await?;
+        assert_eq!(outcome, ClaimOutcome::Claimed);
+        Ok(())
+    }
+}
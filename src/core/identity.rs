@@ -0,0 +1,131 @@
+use chrono::{offset::Utc, DateTime};
+use sqlx::{PgPool, Result};
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct OidcLogin {
+    pub provider: String,
+    pub code_verifier: String,
+    pub nonce: String,
+}
+
+// Remembers a login started at an external provider, identified by the (hashed) state.
+#[tracing::instrument(skip(pool, state_hash, login))]
+pub async fn create_login(
+    pool: &PgPool,
+    state_hash: &str,
+    login: &OidcLogin,
+    expires_at: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+            insert into "oidc_login" (state_hash, provider, code_verifier, nonce, expires_at)
+            values ($1, $2, $3, $4, $5)
+        "#,
+        state_hash,
+        login.provider,
+        login.code_verifier,
+        login.nonce,
+        expires_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Removes the login and returns it, so every state can only be used once.
+// Returns Ok(None) if the state is unknown or expired
+#[tracing::instrument(skip(pool, state_hash))]
+pub async fn consume_login(pool: &PgPool, state_hash: &str) -> Result<Option<OidcLogin>> {
+    sqlx::query_as!(
+        OidcLogin,
+        r#"
+            delete from "oidc_login"
+            where state_hash = $1 and expires_at > now()
+            returning provider, code_verifier, nonce
+        "#,
+        state_hash
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// Returns the user the identity of the provider is linked to.
+#[tracing::instrument(skip(pool))]
+pub async fn get_identity_user(
+    pool: &PgPool,
+    provider: &str,
+    subject: &str,
+) -> Result<Option<Uuid>> {
+    sqlx::query_scalar!(
+        r#"select user_id from "user_identity" where provider = $1 and subject = $2"#,
+        provider,
+        subject
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// Links the identity of the provider to the user.
+#[tracing::instrument(skip(pool))]
+pub async fn link_identity(
+    pool: &PgPool,
+    provider: &str,
+    subject: &str,
+    user_id: Uuid,
+) -> Result<()> {
+    sqlx::query!(
+        r#"insert into "user_identity" (provider, subject, user_id) values ($1, $2, $3)"#,
+        provider,
+        subject,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use sqlx::PgPool;
+
+    use super::OidcLogin;
+    use crate::core::{self, user::User};
+
+    #[sqlx::test]
+    async fn consume_login(pool: PgPool) -> sqlx::Result<()> {
+        let login = OidcLogin {
+            provider: "school".to_owned(),
+            code_verifier: "verifier".to_owned(),
+            nonce: "nonce".to_owned(),
+        };
+        super::create_login(&pool, "state", &login, Utc::now() + Duration::minutes(5)).await?;
+        super::create_login(&pool, "expired", &login, Utc::now()).await?;
+
+        let consumed = super::consume_login(&pool, "state").await?.unwrap();
+        assert_eq!(consumed.code_verifier, "verifier");
+        assert!(super::consume_login(&pool, "state").await?.is_none());
+        assert!(super::consume_login(&pool, "expired").await?.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn link_identity(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        assert!(super::get_identity_user(&pool, "school", "subject")
+            .await?
+            .is_none());
+        super::link_identity(&pool, "school", "subject", user_id).await?;
+        assert_eq!(
+            super::get_identity_user(&pool, "school", "subject").await?,
+            Some(user_id)
+        );
+        assert!(super::get_identity_user(&pool, "other", "subject")
+            .await?
+            .is_none());
+        Ok(())
+    }
+}
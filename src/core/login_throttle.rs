@@ -0,0 +1,110 @@
+use chrono::{offset::Utc, DateTime, Duration};
+use sqlx::{PgPool, Result};
+
+/// Failures are forgotten once no new one happened for this many minutes.
+pub const FAILURE_WINDOW_MINUTES: i64 = 15;
+/// The first lockout in seconds. It doubles with every further failure.
+pub const BASE_LOCKOUT_SECONDS: i64 = 30;
+/// The longest a key can be locked in seconds.
+pub const MAX_LOCKOUT_SECONDS: i64 = 60 * 60;
+
+/// How long a key is locked after the given number of failures.
+pub fn lockout(failures: i32, free_attempts: i32) -> Option<Duration> {
+    let exceeded = failures.checked_sub(free_attempts).filter(|n| *n > 0)?;
+    let seconds = BASE_LOCKOUT_SECONDS
+        .saturating_mul(1 << (exceeded - 1).min(16))
+        .min(MAX_LOCKOUT_SECONDS);
+    Some(Duration::seconds(seconds))
+}
+
+// Returns until when the login is locked, if any of the keys is locked.
+#[tracing::instrument(skip(pool))]
+pub async fn locked_until(pool: &PgPool, keys: &[String]) -> Result<Option<DateTime<Utc>>> {
+    sqlx::query_scalar!(
+        r#"
+            select max(locked_until) from "login_failure"
+            where key = any($1) and locked_until > now()
+        "#,
+        keys
+    )
+    .fetch_one(pool)
+    .await
+}
+
+// Records a failed login for the key. Once there were more than `free_attempts`
+// failures within the window, the key is locked for an exponentially growing time.
+#[tracing::instrument(skip(pool))]
+pub async fn record_failure(pool: &PgPool, key: &str, free_attempts: i32) -> Result<()> {
+    let window_start = Utc::now() - Duration::minutes(FAILURE_WINDOW_MINUTES);
+    let failures = sqlx::query_scalar!(
+        r#"
+            insert into "login_failure" (key, failures) values ($1, 1)
+            on conflict (key) do update
+            set failures = case
+                    when "login_failure".last_failure_at < $2 then 1
+                    else "login_failure".failures + 1
+                end,
+                last_failure_at = now()
+            returning failures
+        "#,
+        key,
+        window_start
+    )
+    .fetch_one(pool)
+    .await?;
+
+    if let Some(lockout) = lockout(failures, free_attempts) {
+        sqlx::query!(
+            r#"update "login_failure" set locked_until = $1 where key = $2"#,
+            Utc::now() + lockout,
+            key
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+// Forgets the failures of the key, e.g. after a successful login.
+#[tracing::instrument(skip(pool))]
+pub async fn clear_failures(pool: &PgPool, key: &str) -> Result<()> {
+    sqlx::query!(r#"delete from "login_failure" where key = $1"#, key)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use sqlx::PgPool;
+
+    #[test]
+    fn lockout() {
+        assert_eq!(super::lockout(3, 5), None);
+        assert_eq!(super::lockout(5, 5), None);
+        assert_eq!(super::lockout(6, 5), Some(Duration::seconds(30)));
+        assert_eq!(super::lockout(8, 5), Some(Duration::seconds(120)));
+        assert_eq!(super::lockout(100, 5), Some(Duration::hours(1)));
+    }
+
+    #[sqlx::test]
+    async fn lock_after_failures(pool: PgPool) -> sqlx::Result<()> {
+        let keys = vec![
+            "account:a@example.com".to_owned(),
+            "ip:127.0.0.1".to_owned(),
+        ];
+        for _ in 0..2 {
+            super::record_failure(&pool, &keys[0], 2).await?;
+        }
+        assert!(super::locked_until(&pool, &keys).await?.is_none());
+
+        super::record_failure(&pool, &keys[0], 2).await?;
+        assert!(super::locked_until(&pool, &keys).await?.is_some());
+        assert!(super::locked_until(&pool, &keys[1..]).await?.is_none());
+
+        super::clear_failures(&pool, &keys[0]).await?;
+        assert!(super::locked_until(&pool, &keys).await?.is_none());
+        Ok(())
+    }
+}
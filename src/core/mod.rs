@@ -1,3 +1,12 @@
+pub mod api_key;
+pub mod audit;
 pub mod challenge;
+pub mod idempotency;
+pub mod identity;
+pub mod login_throttle;
 pub mod quiz;
+pub mod session;
+pub mod token;
+pub mod translation;
+pub mod two_factor;
 pub mod user;
@@ -1,24 +1,38 @@
 use futures::future;
-use sqlx::{PgPool, Result};
+use sqlx::{PgExecutor, PgPool, Result};
 use uuid::Uuid;
 
-use crate::entities::quiz::{DBQuiz, DBQuizQuestion};
+use crate::{
+    core::{
+        translation::{insert_translation, upsert_translation},
+        user::increase_score,
+    },
+    entities::quiz::{
+        DBQuiz, DBQuizAttempt, DBQuizQuestion, QuestionResult, QuizAnswer, QuizAttemptResult,
+        QuizTranslation,
+    },
+};
 
 #[tracing::instrument(skip(pool))]
-pub async fn get_quiz(pool: &PgPool, id: Uuid) -> Result<Option<DBQuiz>> {
+pub async fn get_quiz(pool: &PgPool, id: Uuid, languages: &[String]) -> Result<Option<DBQuiz>> {
     let Some(row) = sqlx::query!(
         r#"
             select
                 quiz.id id,
                 created_at,
                 created_by,
-                content title
+                title.content as "title!"
             from quiz quiz
-            inner join translation
-            on quiz.title = translation.id
+            inner join lateral (
+                select content from translation
+                where translation.id = quiz.title
+                order by array_position($2::text[], lower(language_code)) nulls last, language_code
+                limit 1
+            ) title on true
             where quiz.id = $1
         "#,
-        id
+        id,
+        languages
     )
     .fetch_optional(pool)
     .await? else  {
@@ -26,7 +40,7 @@ pub async fn get_quiz(pool: &PgPool, id: Uuid) -> Result<Option<DBQuiz>> {
         return Ok(None);
     };
 
-    let questions = get_questions_by_quiz_id(pool, row.id).await?;
+    let questions = get_questions_by_quiz_id(pool, row.id, languages).await?;
     Ok(Some(DBQuiz {
         id: row.id,
         title: row.title,
@@ -36,20 +50,29 @@ pub async fn get_quiz(pool: &PgPool, id: Uuid) -> Result<Option<DBQuiz>> {
     }))
 }
 
-pub async fn get_questions_by_quiz_id(pool: &PgPool, quiz_id: Uuid) -> Result<Vec<DBQuizQuestion>> {
+pub async fn get_questions_by_quiz_id(
+    pool: &PgPool,
+    quiz_id: Uuid,
+    languages: &[String],
+) -> Result<Vec<DBQuizQuestion>> {
     let row = sqlx::query!(
         r#"
             select 
                 question.id,
                 question.quiz_id,
-                content question,
+                translation.content as "question!",
                 data
             from question
-            inner join translation
-            on question.question = translation.id
+            inner join lateral (
+                select content from translation
+                where translation.id = question.question
+                order by array_position($2::text[], lower(language_code)) nulls last, language_code
+                limit 1
+            ) translation on true
             where question.quiz_id = $1
         "#,
-        quiz_id
+        quiz_id,
+        languages
     )
     .fetch_all(pool)
     .await?;
@@ -65,16 +88,14 @@ pub async fn get_questions_by_quiz_id(pool: &PgPool, quiz_id: Uuid) -> Result<Ve
 }
 
 #[tracing::instrument(skip(pool))]
-pub async fn insert_quiz(pool: &PgPool, quiz: &DBQuiz) -> Result<Uuid> {
+pub async fn insert_quiz(
+    pool: &PgPool,
+    quiz: &DBQuiz,
+    language_code: Option<String>,
+) -> Result<Uuid> {
     let conn = pool.begin().await?;
 
-    let title_id = sqlx::query_scalar!(
-        r#"insert into "translation" (language_code, content) values ($1, $2) returning id"#,
-        "en-GB",
-        quiz.title
-    )
-    .fetch_one(pool)
-    .await?;
+    let title_id = insert_translation(pool, &quiz.title, language_code.clone()).await?;
 
     let quiz_id = sqlx::query_scalar!(
         r#"insert into "quiz" (title, created_by) values ($1, $2) returning id"#,
@@ -89,7 +110,7 @@ pub async fn insert_quiz(pool: &PgPool, quiz: &DBQuiz) -> Result<Uuid> {
             quiz_id,
             ..question.clone()
         };
-        insert_question(pool, question)
+        insert_question(pool, question, language_code.clone())
     });
     future::join_all(fut_vec).await;
 
@@ -98,8 +119,12 @@ pub async fn insert_quiz(pool: &PgPool, quiz: &DBQuiz) -> Result<Uuid> {
 }
 
 #[tracing::instrument(skip(pool))]
-pub async fn insert_question(pool: &PgPool, question: DBQuizQuestion) -> Result<Uuid> {
-    let translation_id = insert_translation(pool, &question.question, None).await?;
+pub async fn insert_question(
+    pool: &PgPool,
+    question: DBQuizQuestion,
+    language_code: Option<String>,
+) -> Result<Uuid> {
+    let translation_id = insert_translation(pool, &question.question, language_code).await?;
     sqlx::query_scalar!(
         r#"insert into "question" (quiz_id, question, data) values ($1, $2, $3) returning id"#,
         question.quiz_id,
@@ -110,34 +135,188 @@ pub async fn insert_question(pool: &PgPool, question: DBQuizQuestion) -> Result<
     .await
 }
 
+// Adds or replaces the title and question texts of a quiz in the given language.
+// Questions that do not belong to the quiz are ignored.
+// Returns Ok(None) if the quiz does not exist
 #[tracing::instrument(skip(pool))]
-pub async fn insert_translation(
+pub async fn translate_quiz(
     pool: &PgPool,
-    content: &str,
-    language_code: Option<String>,
+    quiz_id: Uuid,
+    language_code: &str,
+    translation: &QuizTranslation,
+) -> Result<Option<()>> {
+    let Some(title_id) = sqlx::query_scalar!(r#"select title from "quiz" where id = $1"#, quiz_id)
+        .fetch_optional(pool)
+        .await?
+    else {
+        return Ok(None);
+    };
+    if let Some(title) = &translation.title {
+        upsert_translation(pool, title_id, language_code, title).await?;
+    }
+
+    let questions = sqlx::query!(
+        r#"select id, question from "question" where quiz_id = $1"#,
+        quiz_id
+    )
+    .fetch_all(pool)
+    .await?;
+    for question in &translation.questions {
+        if let Some(record) = questions
+            .iter()
+            .find(|record| record.id == question.question_id)
+        {
+            upsert_translation(pool, record.question, language_code, &question.question).await?;
+        }
+    }
+    Ok(Some(()))
+}
+
+/// Grades the given answers against the questions of the quiz.
+/// Unanswered questions are graded as incorrect, duplicate answers are ignored.
+pub fn grade_quiz(quiz: &DBQuiz, answers: &[QuizAnswer]) -> Vec<QuestionResult> {
+    quiz.questions
+        .iter()
+        .map(|question| QuestionResult {
+            question_id: question.id,
+            correct: answers
+                .iter()
+                .find(|answer| answer.question_id == question.id)
+                .is_some_and(|answer| question.data.is_correct(&answer.answer)),
+        })
+        .collect()
+}
+
+#[tracing::instrument(skip(executor))]
+pub async fn insert_attempt<'c>(
+    executor: impl PgExecutor<'c>,
+    attempt: &DBQuizAttempt,
 ) -> Result<Uuid> {
-    let language_code = language_code.unwrap_or_else(|| "en-GB".to_owned());
     sqlx::query_scalar!(
-        r#"insert into "translation" (language_code, content) values ($1, $2) returning id"#,
-        language_code,
-        content
+        r#"
+            insert into "quiz_attempt" (quiz_id, user_id, answers, correct, total, score_awarded)
+            values ($1, $2, $3, $4, $5, $6)
+            returning id
+        "#,
+        attempt.quiz_id,
+        attempt.user_id,
+        sqlx::types::Json(&attempt.answers) as _,
+        attempt.correct,
+        attempt.total,
+        attempt.score_awarded
     )
-    .fetch_one(pool)
+    .fetch_one(executor)
     .await
 }
 
+// Returns the highest number of correct answers the user achieved in a previous attempt
+#[tracing::instrument(skip(executor))]
+pub async fn get_best_attempt<'c>(
+    executor: impl PgExecutor<'c>,
+    quiz_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<i32>> {
+    sqlx::query_scalar!(
+        r#"select max(correct) from "quiz_attempt" where quiz_id = $1 and user_id = $2"#,
+        quiz_id,
+        user_id
+    )
+    .fetch_one(executor)
+    .await
+}
+
+// Grades and stores an attempt and credits the user.
+// Only improvements over the best previous attempt are credited, so repeating a quiz
+// does not increase the score indefinitely.
+// The user is locked while grading, so concurrent attempts can't be credited twice.
+// Returns Ok(None) if the quiz or the user does not exist
+#[tracing::instrument(skip(pool))]
+pub async fn submit_attempt(
+    pool: &PgPool,
+    quiz_id: Uuid,
+    user_id: Uuid,
+    answers: Vec<QuizAnswer>,
+) -> Result<Option<QuizAttemptResult>> {
+    let Some(quiz) = get_quiz(pool, quiz_id, &[]).await? else {
+        return Ok(None);
+    };
+    let results = grade_quiz(&quiz, &answers);
+    let correct = results.iter().filter(|result| result.correct).count() as i32;
+
+    let mut tx = pool.begin().await?;
+    let locked = sqlx::query_scalar!(r#"select id from "user" where id = $1 for update"#, user_id)
+        .fetch_optional(&mut tx)
+        .await?;
+    if locked.is_none() {
+        return Ok(None);
+    }
+    let best = get_best_attempt(&mut tx, quiz_id, user_id)
+        .await?
+        .unwrap_or(0);
+    let score_awarded = (correct - best).max(0);
+
+    let attempt = DBQuizAttempt {
+        quiz_id,
+        user_id,
+        answers,
+        correct,
+        total: results.len() as i32,
+        score_awarded,
+        ..DBQuizAttempt::default()
+    };
+    let id = insert_attempt(&mut tx, &attempt).await?;
+    if score_awarded > 0 {
+        increase_score(&mut tx, user_id, score_awarded).await?;
+    }
+    tx.commit().await?;
+
+    Ok(Some(QuizAttemptResult {
+        id,
+        quiz_id,
+        correct,
+        total: attempt.total,
+        score_awarded,
+        results,
+    }))
+}
+
 #[cfg(test)]
 mod tests {
     use sqlx::PgPool;
 
     use crate::{
-        core::{self, user::User},
-        entities::quiz::DBQuiz,
+        core::{self, translation::language_preferences, user::User},
+        entities::quiz::{
+            DBQuiz, DBQuizQuestion, QuestionAnswer, QuestionTranslation, QuestionType, QuizAnswer,
+            QuizTranslation, TrueOrFalseAnswer, TrueOrFalseQuestion,
+        },
     };
 
+    fn true_or_false_quiz(created_by: super::Uuid) -> DBQuiz {
+        DBQuiz {
+            created_by,
+            questions: vec![DBQuizQuestion {
+                id: super::Uuid::new_v4(),
+                quiz_id: super::Uuid::nil(),
+                question: "Is the earth round?".to_owned(),
+                data: sqlx::types::Json(QuestionType::TrueOrFalse(TrueOrFalseQuestion {
+                    correct_answer: true,
+                })),
+            }],
+            ..DBQuiz::default()
+        }
+    }
+
+    fn answer(question_id: super::Uuid, answer: bool) -> Vec<QuizAnswer> {
+        vec![QuizAnswer {
+            question_id,
+            answer: QuestionAnswer::TrueOrFalse(TrueOrFalseAnswer { answer }),
+        }]
+    }
+
     #[sqlx::test]
     async fn require_created_by(pool: PgPool) -> sqlx::Result<()> {
-        let res = super::insert_quiz(&pool, &DBQuiz::default()).await;
+        let res = super::insert_quiz(&pool, &DBQuiz::default(), None).await;
         assert!(res.is_err());
         Ok(())
     }
@@ -147,9 +326,11 @@ mod tests {
         let user_id = core::user::insert_user(&pool, &User::default())
             .await?
             .unwrap();
-        let mut quiz = DBQuiz::default();
-        quiz.created_by = user_id;
-        super::insert_quiz(&pool, &quiz).await?;
+        let quiz = DBQuiz {
+            created_by: user_id,
+            ..DBQuiz::default()
+        };
+        super::insert_quiz(&pool, &quiz, None).await?;
         Ok(())
     }
 
@@ -158,15 +339,121 @@ mod tests {
         let user_id = core::user::insert_user(&pool, &User::default())
             .await?
             .unwrap();
-        let mut quiz = DBQuiz::default();
-        quiz.created_by = user_id;
-        let quiz_id = super::insert_quiz(&pool, &quiz).await?;
+        let quiz = DBQuiz {
+            created_by: user_id,
+            ..DBQuiz::default()
+        };
+        let quiz_id = super::insert_quiz(&pool, &quiz, None).await?;
 
-        let db_quiz = super::get_quiz(&pool, quiz_id)
+        let db_quiz = super::get_quiz(&pool, quiz_id, &[])
             .await?
             .expect("Unable to retrive Quiz from DB");
         assert_eq!(db_quiz.title, quiz.title);
         assert_eq!(db_quiz.created_by, user_id);
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn submit_attempt(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        let quiz_id = super::insert_quiz(&pool, &true_or_false_quiz(user_id), None).await?;
+        let question_id = super::get_quiz(&pool, quiz_id, &[])
+            .await?
+            .unwrap()
+            .questions[0]
+            .id;
+
+        let wrong = super::submit_attempt(&pool, quiz_id, user_id, answer(question_id, false))
+            .await?
+            .unwrap();
+        assert_eq!(wrong.correct, 0);
+        assert_eq!(wrong.total, 1);
+        assert!(!wrong.results[0].correct);
+
+        let right = super::submit_attempt(&pool, quiz_id, user_id, answer(question_id, true))
+            .await?
+            .unwrap();
+        assert_eq!(right.correct, 1);
+        assert_eq!(right.score_awarded, 1);
+
+        let repeated = super::submit_attempt(&pool, quiz_id, user_id, answer(question_id, true))
+            .await?
+            .unwrap();
+        assert_eq!(repeated.score_awarded, 0);
+
+        let user = core::user::get_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.score, 1);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn concurrent_attempts(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        let quiz_id = super::insert_quiz(&pool, &true_or_false_quiz(user_id), None).await?;
+        let question_id = super::get_quiz(&pool, quiz_id, &[])
+            .await?
+            .unwrap()
+            .questions[0]
+            .id;
+
+        let (first, second) = futures::join!(
+            super::submit_attempt(&pool, quiz_id, user_id, answer(question_id, true)),
+            super::submit_attempt(&pool, quiz_id, user_id, answer(question_id, true)),
+        );
+        let awarded = first?.unwrap().score_awarded + second?.unwrap().score_awarded;
+        assert_eq!(awarded, 1);
+        let user = core::user::get_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.score, 1);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn submit_attempt_unknown_quiz(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        let res = super::submit_attempt(&pool, super::Uuid::new_v4(), user_id, vec![]).await?;
+        assert!(res.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn translate_quiz(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        let quiz = true_or_false_quiz(user_id);
+        let quiz_id = super::insert_quiz(&pool, &quiz, None).await?;
+        let question_id = super::get_quiz(&pool, quiz_id, &[])
+            .await?
+            .unwrap()
+            .questions[0]
+            .id;
+
+        let translation = QuizTranslation {
+            title: Some("Titel".to_owned()),
+            questions: vec![QuestionTranslation {
+                question_id,
+                question: "Ist die Erde rund?".to_owned(),
+            }],
+        };
+        super::translate_quiz(&pool, quiz_id, "de", &translation)
+            .await?
+            .unwrap();
+
+        let languages = language_preferences(Some("de-AT"), None);
+        let german = super::get_quiz(&pool, quiz_id, &languages).await?.unwrap();
+        assert_eq!(german.title, "Titel");
+        assert_eq!(german.questions[0].question, "Ist die Erde rund?");
+
+        let languages = language_preferences(Some("fr"), None);
+        let english = super::get_quiz(&pool, quiz_id, &languages).await?.unwrap();
+        assert_eq!(english.title, quiz.title);
+        assert_eq!(english.questions[0].question, "Is the earth round?");
+        Ok(())
+    }
 }
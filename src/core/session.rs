@@ -0,0 +1,287 @@
+use chrono::{offset::Utc, DateTime};
+use sqlx::{PgPool, Result};
+use uuid::Uuid;
+
+use crate::core::user::Role;
+
+#[derive(Debug, Clone)]
+pub struct AuthSession {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub enum RefreshOutcome {
+    /// The refresh token was exchanged, the session stays valid.
+    Rotated(AuthSession),
+    /// The refresh token was already used before. The session has been revoked,
+    /// since either the client or an attacker holds a stolen token.
+    Reused,
+    /// The refresh token is unknown, expired or belongs to a revoked session.
+    Invalid,
+}
+
+// Starts a new session for the user with the given (hashed) refresh token.
+#[tracing::instrument(skip(pool, refresh_token_hash))]
+pub async fn create_session(
+    pool: &PgPool,
+    user_id: Uuid,
+    refresh_token_hash: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<Uuid> {
+    let mut tx = pool.begin().await?;
+    let session_id = sqlx::query_scalar!(
+        r#"insert into "auth_session" (user_id, expires_at) values ($1, $2) returning id"#,
+        user_id,
+        expires_at
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"insert into "refresh_token" (hash, session_id) values ($1, $2)"#,
+        refresh_token_hash,
+        session_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(session_id)
+}
+
+// Exchanges a refresh token for a new one and extends the session.
+// Every refresh token can only be used once, reusing one revokes the whole session.
+#[tracing::instrument(skip(pool, refresh_token_hash, new_refresh_token_hash))]
+pub async fn rotate_refresh_token(
+    pool: &PgPool,
+    refresh_token_hash: &str,
+    new_refresh_token_hash: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<RefreshOutcome> {
+    let mut tx = pool.begin().await?;
+    let Some(token) = sqlx::query!(
+        r#"
+            select
+                refresh_token.used_at,
+                auth_session.id session_id,
+                auth_session.user_id,
+                auth_session.created_at,
+                auth_session.expires_at,
+                auth_session.revoked_at
+            from refresh_token
+            inner join auth_session
+            on refresh_token.session_id = auth_session.id
+            where refresh_token.hash = $1
+            for update
+        "#,
+        refresh_token_hash
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    else {
+        return Ok(RefreshOutcome::Invalid);
+    };
+
+    if token.revoked_at.is_some() || token.expires_at <= Utc::now() {
+        return Ok(RefreshOutcome::Invalid);
+    }
+
+    if token.used_at.is_some() {
+        sqlx::query!(
+            r#"update "auth_session" set revoked_at = now() where id = $1"#,
+            token.session_id
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        return Ok(RefreshOutcome::Reused);
+    }
+
+    sqlx::query!(
+        r#"update "refresh_token" set used_at = now() where hash = $1"#,
+        refresh_token_hash
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"insert into "refresh_token" (hash, session_id) values ($1, $2)"#,
+        new_refresh_token_hash,
+        token.session_id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"update "auth_session" set expires_at = $1 where id = $2"#,
+        expires_at,
+        token.session_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(RefreshOutcome::Rotated(AuthSession {
+        id: token.session_id,
+        user_id: token.user_id,
+        created_at: token.created_at,
+        expires_at,
+        revoked_at: None,
+    }))
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn is_session_active(pool: &PgPool, session_id: Uuid) -> Result<bool> {
+    Ok(sqlx::query_scalar!(
+        r#"
+            select id from "auth_session"
+            where id = $1 and revoked_at is null and expires_at > now()
+        "#,
+        session_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_some())
+}
+
+// Returns the user and their current role if the session is active.
+// Cookie sessions look the role up on every request instead of trusting a token.
+#[tracing::instrument(skip(pool))]
+pub async fn get_session_user(pool: &PgPool, session_id: Uuid) -> Result<Option<(Uuid, Role)>> {
+    let user = sqlx::query!(
+        r#"
+            select "user".id, "user".role as "role: Role" from "auth_session"
+            inner join "user" on "user".id = auth_session.user_id
+            where auth_session.id = $1 and auth_session.revoked_at is null
+                and auth_session.expires_at > now()
+        "#,
+        session_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(user.map(|user| (user.id, user.role)))
+}
+
+// Revokes a single session of the user.
+// Returns false if there is no such active session
+#[tracing::instrument(skip(pool))]
+pub async fn revoke_session(pool: &PgPool, user_id: Uuid, session_id: Uuid) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+            update "auth_session" set revoked_at = now()
+            where id = $1 and user_id = $2 and revoked_at is null
+        "#,
+        session_id,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// Revokes every session of the user and returns how many were active.
+#[tracing::instrument(skip(pool))]
+pub async fn revoke_user_sessions(pool: &PgPool, user_id: Uuid) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+            update "auth_session" set revoked_at = now()
+            where user_id = $1 and revoked_at is null
+        "#,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+// Revokes every session of the user except the given one, e.g. after a password change.
+#[tracing::instrument(skip(pool))]
+pub async fn revoke_other_sessions(pool: &PgPool, user_id: Uuid, keep: Uuid) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+            update "auth_session" set revoked_at = now()
+            where user_id = $1 and id <> $2 and revoked_at is null
+        "#,
+        user_id,
+        keep
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use sqlx::PgPool;
+
+    use super::RefreshOutcome;
+    use crate::core::{self, user::User};
+
+    #[sqlx::test]
+    async fn rotate_refresh_token(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        let expires_at = Utc::now() + Duration::days(1);
+        let session_id = super::create_session(&pool, user_id, "first", expires_at).await?;
+        assert!(super::is_session_active(&pool, session_id).await?);
+
+        let outcome = super::rotate_refresh_token(&pool, "first", "second", expires_at).await?;
+        assert!(matches!(outcome, RefreshOutcome::Rotated(session) if session.id == session_id));
+        let outcome = super::rotate_refresh_token(&pool, "unknown", "third", expires_at).await?;
+        assert!(matches!(outcome, RefreshOutcome::Invalid));
+        assert!(super::is_session_active(&pool, session_id).await?);
+
+        let outcome = super::rotate_refresh_token(&pool, "first", "third", expires_at).await?;
+        assert!(matches!(outcome, RefreshOutcome::Reused));
+        assert!(!super::is_session_active(&pool, session_id).await?);
+        let outcome = super::rotate_refresh_token(&pool, "second", "third", expires_at).await?;
+        assert!(matches!(outcome, RefreshOutcome::Invalid));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn expired_session(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        let expires_at = Utc::now() - Duration::seconds(1);
+        let session_id = super::create_session(&pool, user_id, "token", expires_at).await?;
+        assert!(!super::is_session_active(&pool, session_id).await?);
+
+        let outcome = super::rotate_refresh_token(&pool, "token", "new", Utc::now()).await?;
+        assert!(matches!(outcome, RefreshOutcome::Invalid));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn revoke_sessions(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        let expires_at = Utc::now() + Duration::days(1);
+        let first = super::create_session(&pool, user_id, "first", expires_at).await?;
+        let second = super::create_session(&pool, user_id, "second", expires_at).await?;
+
+        let (session_user, _) = super::get_session_user(&pool, first).await?.unwrap();
+        assert_eq!(session_user, user_id);
+        assert!(super::revoke_session(&pool, user_id, first).await?);
+        assert!(super::get_session_user(&pool, first).await?.is_none());
+        assert!(!super::revoke_session(&pool, user_id, first).await?);
+        assert!(!super::is_session_active(&pool, first).await?);
+        assert!(super::is_session_active(&pool, second).await?);
+
+        let third = super::create_session(&pool, user_id, "third", expires_at).await?;
+        assert_eq!(
+            super::revoke_other_sessions(&pool, user_id, third).await?,
+            1
+        );
+        assert!(!super::is_session_active(&pool, second).await?);
+        assert!(super::is_session_active(&pool, third).await?);
+
+        assert_eq!(super::revoke_user_sessions(&pool, user_id).await?, 1);
+        assert!(!super::is_session_active(&pool, third).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+use chrono::{offset::Utc, DateTime};
+use sqlx::{PgPool, Result, Type};
+use uuid::Uuid;
+
+#[derive(Type, Debug, Copy, Clone, Eq, PartialEq)]
+#[sqlx(type_name = "tokenpurpose", rename_all = "lowercase")]
+pub enum TokenPurpose {
+    PasswordReset,
+    EmailVerification,
+    LoginChallenge,
+    MagicLink,
+}
+
+// Stores the (hashed) token for the user.
+#[tracing::instrument(skip(pool, hash))]
+pub async fn create_token(
+    pool: &PgPool,
+    user_id: Uuid,
+    hash: &str,
+    purpose: TokenPurpose,
+    expires_at: DateTime<Utc>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+            insert into "user_token" (hash, user_id, purpose, expires_at)
+            values ($1, $2, $3, $4)
+        "#,
+        hash,
+        user_id,
+        purpose as _,
+        expires_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Marks the token as used and returns the user it was issued for.
+// Returns Ok(None) if the token is unknown, expired or has already been used
+#[tracing::instrument(skip(pool, hash))]
+pub async fn consume_token(
+    pool: &PgPool,
+    hash: &str,
+    purpose: TokenPurpose,
+) -> Result<Option<Uuid>> {
+    sqlx::query_scalar!(
+        r#"
+            update "user_token" set used_at = now()
+            where hash = $1 and purpose = $2 and used_at is null and expires_at > now()
+            returning user_id
+        "#,
+        hash,
+        purpose as _
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// Returns the user the token was issued for, without using it up.
+// Returns Ok(None) if the token is unknown, expired or has already been used
+#[tracing::instrument(skip(pool, hash))]
+pub async fn peek_token(pool: &PgPool, hash: &str, purpose: TokenPurpose) -> Result<Option<Uuid>> {
+    sqlx::query_scalar!(
+        r#"
+            select user_id from "user_token"
+            where hash = $1 and purpose = $2 and used_at is null and expires_at > now()
+        "#,
+        hash,
+        purpose as _
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// Remembers the guest to merge into the account once the login challenge is completed.
+#[tracing::instrument(skip(pool, hash))]
+pub async fn attach_guest(pool: &PgPool, hash: &str, guest_id: Uuid) -> Result<()> {
+    sqlx::query!(
+        r#"update "user_token" set guest_id = $2 where hash = $1"#,
+        hash,
+        guest_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Returns the guest attached to the token, if it is still a guest.
+#[tracing::instrument(skip(pool, hash))]
+pub async fn get_guest(pool: &PgPool, hash: &str) -> Result<Option<Uuid>> {
+    sqlx::query_scalar!(
+        r#"
+            select guest.id from "user_token"
+            join "user" guest on guest.id = "user_token".guest_id and guest.is_guest
+            where "user_token".hash = $1
+        "#,
+        hash
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// Invalidates all outstanding tokens of the user with the given purpose.
+#[tracing::instrument(skip(pool))]
+pub async fn revoke_tokens(pool: &PgPool, user_id: Uuid, purpose: TokenPurpose) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+            update "user_token" set used_at = now()
+            where user_id = $1 and purpose = $2 and used_at is null
+        "#,
+        user_id,
+        purpose as _
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use sqlx::PgPool;
+
+    use super::TokenPurpose;
+    use crate::core::{self, user::User};
+
+    #[sqlx::test]
+    async fn consume_token(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        let expires_at = Utc::now() + Duration::hours(1);
+        super::create_token(
+            &pool,
+            user_id,
+            "token",
+            TokenPurpose::PasswordReset,
+            expires_at,
+        )
+        .await?;
+
+        let consumed = super::consume_token(&pool, "token", TokenPurpose::PasswordReset).await?;
+        assert_eq!(consumed, Some(user_id));
+        let consumed = super::consume_token(&pool, "token", TokenPurpose::PasswordReset).await?;
+        assert!(consumed.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn expired_and_revoked_tokens(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        let expired = Utc::now() - Duration::seconds(1);
+        super::create_token(
+            &pool,
+            user_id,
+            "expired",
+            TokenPurpose::PasswordReset,
+            expired,
+        )
+        .await?;
+        let consumed = super::consume_token(&pool, "expired", TokenPurpose::PasswordReset).await?;
+        assert!(consumed.is_none());
+
+        let expires_at = Utc::now() + Duration::hours(1);
+        super::create_token(
+            &pool,
+            user_id,
+            "revoked",
+            TokenPurpose::PasswordReset,
+            expires_at,
+        )
+        .await?;
+        assert_eq!(
+            super::revoke_tokens(&pool, user_id, TokenPurpose::PasswordReset).await?,
+            2
+        );
+        let consumed = super::consume_token(&pool, "revoked", TokenPurpose::PasswordReset).await?;
+        assert!(consumed.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use sqlx::{PgPool, Result};
+use uuid::Uuid;
+
+/// The language every translation is expected to exist in.
+pub const DEFAULT_LANGUAGE: &str = "en-GB";
+
+/// Checks that the given string looks like a BCP 47 language tag, e.g. `de` or `de-AT`.
+pub fn is_valid_language_code(code: &str) -> bool {
+    let mut subtags = code.split('-');
+    let primary_ok = subtags.next().is_some_and(|primary| {
+        (2..=3).contains(&primary.len()) && primary.chars().all(|c| c.is_ascii_alphabetic())
+    });
+    primary_ok
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+/// Builds the ordered list of lowercase language codes to look translations up with.
+///
+/// An explicitly requested language takes precedence over the `Accept-Language` header.
+/// Every language is followed by its less specific variants and the list always ends
+/// with [`DEFAULT_LANGUAGE`], e.g. `de-AT` results in `de-at`, `de`, `en-gb`.
+pub fn language_preferences(lang_code: Option<&str>, accept_language: Option<&str>) -> Vec<String> {
+    let mut requested: Vec<(String, f32)> = accept_language
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (tag != "*" && is_valid_language_code(tag) && quality > 0.0)
+                .then(|| (tag.to_owned(), quality))
+        })
+        .collect();
+    requested.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    let mut preferences = Vec::new();
+    let tags = lang_code
+        .filter(|code| is_valid_language_code(code))
+        .into_iter()
+        .chain(requested.iter().map(|(tag, _)| tag.as_str()))
+        .chain(std::iter::once(DEFAULT_LANGUAGE));
+    for tag in tags {
+        let tag = tag.to_lowercase();
+        let subtags: Vec<&str> = tag.split('-').collect();
+        for len in (1..=subtags.len()).rev() {
+            let candidate = subtags[..len].join("-");
+            if !preferences.contains(&candidate) {
+                preferences.push(candidate);
+            }
+        }
+    }
+    preferences
+}
+
+// Creates a new translation key with content in the given language.
+// Falls back to DEFAULT_LANGUAGE if no language is given.
+#[tracing::instrument(skip(pool))]
+pub async fn insert_translation(
+    pool: &PgPool,
+    content: &str,
+    language_code: Option<String>,
+) -> Result<Uuid> {
+    let language_code = language_code.unwrap_or_else(|| DEFAULT_LANGUAGE.to_owned());
+    let id = sqlx::query_scalar!(r#"insert into "translation_key" default values returning id"#)
+        .fetch_one(pool)
+        .await?;
+    upsert_translation(pool, id, &language_code, content).await?;
+    Ok(id)
+}
+
+// Adds or replaces the content of an existing translation key in the given language.
+// Language codes are stored in lowercase, as they are looked up.
+#[tracing::instrument(skip(pool))]
+pub async fn upsert_translation(
+    pool: &PgPool,
+    id: Uuid,
+    language_code: &str,
+    content: &str,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+            insert into "translation" (id, language_code, content) values ($1, lower($2), $3)
+            on conflict (id, lower(language_code))
+            do update set content = EXCLUDED.content
+        "#,
+        id,
+        language_code,
+        content
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Returns the content in the best matching language of the given preferences.
+// If none of them match, any available language is returned.
+#[tracing::instrument(skip(pool))]
+pub async fn get_translation(
+    pool: &PgPool,
+    id: Uuid,
+    languages: &[String],
+) -> Result<Option<String>> {
+    sqlx::query_scalar!(
+        r#"
+            select content from "translation"
+            where id = $1
+            order by array_position($2::text[], lower(language_code)) nulls last, language_code
+            limit 1
+        "#,
+        id,
+        languages
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    #[test]
+    fn language_preferences() {
+        assert_eq!(super::language_preferences(None, None), vec!["en-gb", "en"]);
+        assert_eq!(
+            super::language_preferences(Some("de-AT"), None),
+            vec!["de-at", "de", "en-gb", "en"]
+        );
+        assert_eq!(
+            super::language_preferences(Some("fr"), Some("de-AT,de;q=0.9,en;q=0.8,*;q=0.5")),
+            vec!["fr", "de-at", "de", "en", "en-gb"]
+        );
+        assert_eq!(
+            super::language_preferences(Some("not a language"), Some("it;q=0.1, es")),
+            vec!["es", "it", "en-gb", "en"]
+        );
+    }
+
+    #[test]
+    fn is_valid_language_code() {
+        assert!(super::is_valid_language_code("en-GB"));
+        assert!(super::is_valid_language_code("de"));
+        assert!(super::is_valid_language_code("zh-Hant-TW"));
+        assert!(!super::is_valid_language_code(""));
+        assert!(!super::is_valid_language_code("e"));
+        assert!(!super::is_valid_language_code("de_AT"));
+        assert!(!super::is_valid_language_code("de-"));
+    }
+
+    #[sqlx::test]
+    async fn get_translation(pool: PgPool) -> sqlx::Result<()> {
+        let id = super::insert_translation(&pool, "Hello", None).await?;
+        super::upsert_translation(&pool, id, "de", "Hallo").await?;
+
+        let content =
+            super::get_translation(&pool, id, &super::language_preferences(Some("de-AT"), None))
+                .await?;
+        assert_eq!(content.as_deref(), Some("Hallo"));
+
+        let content =
+            super::get_translation(&pool, id, &super::language_preferences(Some("fr"), None))
+                .await?;
+        assert_eq!(content.as_deref(), Some("Hello"));
+
+        super::upsert_translation(&pool, id, "de", "Servus").await?;
+        let content = super::get_translation(&pool, id, &["de".to_owned()]).await?;
+        assert_eq!(content.as_deref(), Some("Servus"));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn case_insensitive_language_code(pool: PgPool) -> sqlx::Result<()> {
+        let id = super::insert_translation(&pool, "Hallo", Some("DE".to_owned())).await?;
+        super::upsert_translation(&pool, id, "de", "Servus").await?;
+
+        let codes = sqlx::query_scalar!(
+            r#"select language_code from "translation" where id = $1"#,
+            id
+        )
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(codes, ["de"]);
+        let content = super::get_translation(&pool, id, &["de".to_owned()]).await?;
+        assert_eq!(content.as_deref(), Some("Servus"));
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use chrono::{offset::Utc, DateTime};
+use sqlx::{PgPool, Result};
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct UserTotp {
+    pub user_id: Uuid,
+    /// Base32 encoded secret
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+    pub last_used_step: Option<i64>,
+}
+
+// Stores a new, unconfirmed secret for the user, replacing a previous unconfirmed one.
+// Returns Ok(None) if the user already has confirmed two-factor authentication
+#[tracing::instrument(skip(pool, secret))]
+pub async fn start_enrolment(pool: &PgPool, user_id: Uuid, secret: &str) -> Result<Option<()>> {
+    let result = sqlx::query!(
+        r#"
+            insert into "user_totp" (user_id, secret) values ($1, $2)
+            on conflict (user_id) do update
+            set secret = EXCLUDED.secret, created_at = now(), last_used_step = null
+            where "user_totp".confirmed_at is null
+        "#,
+        user_id,
+        secret
+    )
+    .execute(pool)
+    .await?;
+    Ok((result.rows_affected() > 0).then_some(()))
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn get_totp(pool: &PgPool, user_id: Uuid) -> Result<Option<UserTotp>> {
+    sqlx::query_as!(
+        UserTotp,
+        r#"select * from "user_totp" where user_id = $1"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// Whether the user has to provide a second factor to log in.
+#[tracing::instrument(skip(pool))]
+pub async fn is_enabled(pool: &PgPool, user_id: Uuid) -> Result<bool> {
+    Ok(get_totp(pool, user_id)
+        .await?
+        .is_some_and(|totp| totp.confirmed_at.is_some()))
+}
+
+// Remembers the time step of an accepted code.
+// Returns false if a code of this or a later step was used before, i.e. the code is replayed
+#[tracing::instrument(skip(pool))]
+pub async fn use_step(pool: &PgPool, user_id: Uuid, step: i64) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+            update "user_totp" set last_used_step = $1
+            where user_id = $2 and (last_used_step is null or last_used_step < $1)
+        "#,
+        step,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// Enables two-factor authentication if it is not yet and replaces the recovery codes of the user.
+#[tracing::instrument(skip(pool, recovery_code_hashes))]
+pub async fn confirm(pool: &PgPool, user_id: Uuid, recovery_code_hashes: &[String]) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        r#"update "user_totp" set confirmed_at = coalesce(confirmed_at, now()) where user_id = $1"#,
+        user_id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(r#"delete from "recovery_code" where user_id = $1"#, user_id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(
+        r#"
+            insert into "recovery_code" (hash, user_id)
+            select hash, $2 from unnest($1::text[]) as hash
+        "#,
+        recovery_code_hashes,
+        user_id
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// Marks the recovery code as used.
+// Returns false if the code does not belong to the user or has been used before
+#[tracing::instrument(skip(pool, hash))]
+pub async fn use_recovery_code(pool: &PgPool, user_id: Uuid, hash: &str) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+            update "recovery_code" set used_at = now()
+            where hash = $1 and user_id = $2 and used_at is null
+        "#,
+        hash,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// Disables two-factor authentication and removes all recovery codes.
+// Returns Ok(None) if it was not set up
+#[tracing::instrument(skip(pool))]
+pub async fn disable(pool: &PgPool, user_id: Uuid) -> Result<Option<()>> {
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query!(r#"delete from "user_totp" where user_id = $1"#, user_id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(r#"delete from "recovery_code" where user_id = $1"#, user_id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok((result.rows_affected() > 0).then_some(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::core::{self, user::User};
+
+    #[sqlx::test]
+    async fn enrolment(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        assert!(super::start_enrolment(&pool, user_id, "FIRST")
+            .await?
+            .is_some());
+        assert!(super::start_enrolment(&pool, user_id, "SECOND")
+            .await?
+            .is_some());
+        assert!(!super::is_enabled(&pool, user_id).await?);
+
+        let codes = vec!["a".to_owned(), "b".to_owned()];
+        super::confirm(&pool, user_id, &codes).await?;
+        assert!(super::is_enabled(&pool, user_id).await?);
+        assert!(super::start_enrolment(&pool, user_id, "THIRD")
+            .await?
+            .is_none());
+        let totp = super::get_totp(&pool, user_id).await?.unwrap();
+        assert_eq!(totp.secret, "SECOND");
+
+        assert!(super::use_recovery_code(&pool, user_id, "a").await?);
+        assert!(!super::use_recovery_code(&pool, user_id, "a").await?);
+        assert!(!super::use_recovery_code(&pool, user_id, "c").await?);
+
+        super::disable(&pool, user_id).await?.unwrap();
+        assert!(!super::is_enabled(&pool, user_id).await?);
+        assert!(!super::use_recovery_code(&pool, user_id, "b").await?);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn reject_replayed_step(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+        super::start_enrolment(&pool, user_id, "SECRET").await?;
+        assert!(super::use_step(&pool, user_id, 10).await?);
+        assert!(!super::use_step(&pool, user_id, 10).await?);
+        assert!(!super::use_step(&pool, user_id, 9).await?);
+        assert!(super::use_step(&pool, user_id, 11).await?);
+        Ok(())
+    }
+}
@@ -1,10 +1,21 @@
 use chrono::offset::Utc;
 use derivative::Derivative;
-use poem_openapi::Object;
+use poem_openapi::{Enum, Object};
 use serde::{Deserialize, Serialize};
-use sqlx::{PgPool, Result};
+use sqlx::{PgExecutor, PgPool, Result, Type};
 use uuid::Uuid;
 
+use crate::entities::user::{ExportedIdentity, ExportedQuizAttempt, ExportedSession, UserExport};
+
+#[derive(Type, Enum, Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[sqlx(type_name = "userrole", rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Teacher,
+    #[default]
+    Student,
+}
+
 #[derive(Object, Clone, Derivative, Serialize, Deserialize)]
 #[derivative(Debug, Default)]
 pub struct User {
@@ -23,6 +34,13 @@ pub struct User {
     pub created_at: chrono::DateTime<Utc>,
     pub updated_at: Option<chrono::DateTime<Utc>>,
     pub score: i32,
+    #[oai(read_only)]
+    pub email_verified_at: Option<chrono::DateTime<Utc>>,
+    #[oai(read_only)]
+    pub role: Role,
+    /// IANA name of the timezone daily challenges reset in, e.g. `Europe/Zurich`
+    #[derivative(Default(value = "\"UTC\".to_owned()"))]
+    pub timezone: String,
 }
 
 // Inserts a new user into the database.
@@ -52,16 +70,28 @@ pub async fn insert_user(pool: &PgPool, user: &User) -> Result<Option<Uuid>> {
 
 #[tracing::instrument(skip(pool))]
 pub async fn get_user(pool: &PgPool, id: Uuid) -> Result<Option<User>> {
-    sqlx::query_as!(User, r#"select * from "user" where "user".id = $1 "#, id)
-        .fetch_optional(pool)
-        .await
+    sqlx::query_as!(
+        User,
+        r#"
+            select id, name, email, avatar_seed, hash, is_guest, created_at, updated_at, score,
+                email_verified_at, role as "role: Role", timezone
+            from "user" where "user".id = $1
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await
 }
 
 #[tracing::instrument(skip(pool))]
 pub async fn get_user_by_email(pool: &PgPool, email: &str) -> Result<Option<User>> {
     sqlx::query_as!(
         User,
-        r#"select * from "user" where "user".email = $1"#,
+        r#"
+            select id, name, email, avatar_seed, hash, is_guest, created_at, updated_at, score,
+                email_verified_at, role as "role: Role", timezone
+            from "user" where "user".email = $1
+        "#,
         email
     )
     .fetch_optional(pool)
@@ -70,12 +100,13 @@ pub async fn get_user_by_email(pool: &PgPool, email: &str) -> Result<Option<User
 
 #[derive(Default, Debug, PartialEq, Eq)]
 pub struct UserPatch {
-    name: Option<String>,
-    email: Option<String>,
-    avatar_seed: Option<String>,
-    hash: Option<String>,
-    is_guest: Option<bool>,
-    score: Option<i32>,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub avatar_seed: Option<String>,
+    pub hash: Option<String>,
+    pub is_guest: Option<bool>,
+    pub score: Option<i32>,
+    pub timezone: Option<String>,
 }
 
 #[tracing::instrument(skip(pool))]
@@ -85,13 +116,18 @@ pub async fn update_user(pool: &PgPool, id: Uuid, patch: &UserPatch) -> Result<O
         r#"
             update "user"
             set email = coalesce($1, "user".email),
+                email_verified_at = case
+                    when $1 is null or $1 = "user".email then "user".email_verified_at
+                end,
                 name = coalesce($2, "user".name),
                 avatar_seed = coalesce($3, "user".avatar_seed),
                 hash = coalesce($4, "user".hash),
                 is_guest = coalesce($5, "user".is_guest),
-                score = coalesce($6, "user".score)
+                score = coalesce($6, "user".score),
+                timezone = coalesce($8, "user".timezone)
             where id = $7
-            returning *
+            returning id, name, email, avatar_seed, hash, is_guest, created_at, updated_at, score,
+                    email_verified_at, role as "role: Role", timezone
         "#,
         patch.email,
         patch.name,
@@ -99,6 +135,64 @@ pub async fn update_user(pool: &PgPool, id: Uuid, patch: &UserPatch) -> Result<O
         patch.hash,
         patch.is_guest,
         patch.score,
+        id,
+        patch.timezone
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// Adds to the score in a single statement, so that concurrent increases are not lost.
+// Returns Ok(None) if the user does not exist
+#[tracing::instrument(skip(executor))]
+pub async fn increase_score<'c>(
+    executor: impl PgExecutor<'c>,
+    id: Uuid,
+    score: i32,
+) -> Result<Option<()>> {
+    let updated = sqlx::query!(
+        r#"update "user" set score = score + $2 where id = $1"#,
+        id,
+        score
+    )
+    .execute(executor)
+    .await?;
+    Ok((updated.rows_affected() > 0).then_some(()))
+}
+
+// Turns a guest into a registered user, keeping its id and everything attached to it.
+// Returns Ok(None) if a user with the specified E-Mail adress already exists
+#[tracing::instrument(skip(pool, hash))]
+pub async fn upgrade_guest(
+    pool: &PgPool,
+    id: Uuid,
+    email: &str,
+    hash: &str,
+) -> Result<Option<User>> {
+    let patch = UserPatch {
+        email: Some(email.to_owned()),
+        hash: Some(hash.to_owned()),
+        is_guest: Some(false),
+        ..UserPatch::default()
+    };
+    match update_user(pool, id, &patch).await {
+        Ok(u) => Ok(u),
+        Err(e) if is_email_taken(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Marks the current E-Mail adress of the user as verified.
+#[tracing::instrument(skip(pool))]
+pub async fn verify_email(pool: &PgPool, id: Uuid) -> Result<Option<User>> {
+    sqlx::query_as!(
+        User,
+        r#"
+            update "user" set email_verified_at = now()
+            where id = $1 and email is not null
+            returning id, name, email, avatar_seed, hash, is_guest, created_at, updated_at, score,
+                    email_verified_at, role as "role: Role", timezone
+        "#,
         id
     )
     .fetch_optional(pool)
@@ -106,16 +200,466 @@ pub async fn update_user(pool: &PgPool, id: Uuid, patch: &UserPatch) -> Result<O
 }
 
 #[tracing::instrument(skip(pool))]
-pub async fn increase_score(pool: &PgPool, id: Uuid, score: i32) -> Result<Option<()>> {
-    let Some(user) = get_user(pool, id).await? else {
+pub async fn is_verified(pool: &PgPool, id: Uuid) -> Result<bool> {
+    Ok(get_user(pool, id)
+        .await?
+        .is_some_and(|user| user.email_verified_at.is_some()))
+}
+
+// Sets the role of the user, e.g. to promote a student to a teacher.
+#[tracing::instrument(skip(pool))]
+pub async fn set_role(pool: &PgPool, id: Uuid, role: Role) -> Result<Option<()>> {
+    let result = sqlx::query!(
+        r#"update "user" set role = $1 where id = $2"#,
+        role as Role,
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok((result.rows_affected() > 0).then_some(()))
+}
+
+// Whether Postgres knows the timezone, e.g. `Europe/Zurich`
+#[tracing::instrument(skip(pool))]
+pub async fn is_valid_timezone(pool: &PgPool, timezone: &str) -> Result<bool> {
+    sqlx::query_scalar!(
+        r#"select exists(select 1 from pg_timezone_names where name = $1) as "exists!""#,
+        timezone
+    )
+    .fetch_one(pool)
+    .await
+}
+
+// Whether it currently is the same date for the user in the given timezone,
+// daily challenges would otherwise be reset or skipped by the change.
+// `None` if the user doesn't exist
+#[tracing::instrument(skip(pool))]
+pub async fn keeps_local_date(pool: &PgPool, id: Uuid, timezone: &str) -> Result<Option<bool>> {
+    sqlx::query_scalar!(
+        r#"
+            select (now() at time zone timezone)::date = (now() at time zone $2)::date
+                as "same!"
+            from "user" where id = $1
+        "#,
+        id,
+        timezone
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// Whether the error was caused by setting an E-Mail adress that belongs to another user
+pub fn is_email_taken(error: &sqlx::Error) -> bool {
+    matches!(error, sqlx::Error::Database(e) if e.constraint() == Some("user_email_key"))
+}
+
+// Moves the score, challenge progress, quizzes and quiz attempts of a guest
+// into another account and deletes the guest afterwards.
+#[tracing::instrument(skip(pool))]
+pub async fn merge_users(pool: &PgPool, guest_id: Uuid, into_id: Uuid) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        r#"
+            insert into user_challenge (user_id, challenge_id, progress, completed_at)
+            select $2, challenge_id, progress, completed_at from user_challenge where user_id = $1
+            on conflict on constraint one_user_per_challenge
+            do update set progress = user_challenge.progress + EXCLUDED.progress,
+                completed_at = least(user_challenge.completed_at, EXCLUDED.completed_at)
+        "#,
+        guest_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(r#"delete from user_challenge where user_id = $1"#, guest_id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(
+        r#"
+            insert into user_challenge_day (user_id, challenge_id, day, progress, completed_at)
+            select $2, challenge_id, day, progress, completed_at
+            from user_challenge_day where user_id = $1
+            on conflict (user_id, challenge_id, day)
+            do update set progress = user_challenge_day.progress + EXCLUDED.progress,
+                completed_at = least(user_challenge_day.completed_at, EXCLUDED.completed_at)
+        "#,
+        guest_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"delete from user_challenge_day where user_id = $1"#,
+        guest_id
+    )
+    .execute(&mut tx)
+    .await?;
+    // Rewards both users got for the same challenge and day are only counted once
+    let duplicate_rewards = sqlx::query_scalar!(
+        r#"
+            select coalesce(sum(guest.reward), 0) as "sum!" from challenge_reward guest
+            join challenge_reward target on target.user_id = $2
+                and target.challenge_id = guest.challenge_id
+                and coalesce(target.day, '-infinity') = coalesce(guest.day, '-infinity')
+            where guest.user_id = $1
+        "#,
+        guest_id,
+        into_id
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+            insert into challenge_reward (user_id, challenge_id, day, reward, created_at)
+            select $2, challenge_id, day, reward, created_at from challenge_reward
+            where user_id = $1
+            on conflict do nothing
+        "#,
+        guest_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"delete from challenge_reward where user_id = $1"#,
+        guest_id
+    )
+    .execute(&mut tx)
+    .await?;
+    // The guest's own events are deleted together with the guest
+    sqlx::query!(
+        r#"
+            insert into challenge_progress_event
+                (user_id, challenge_id, amount, created_at, source, note, day)
+            select $2, challenge_id, amount, created_at, source, note, day
+            from challenge_progress_event where user_id = $1
+        "#,
+        guest_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"update quiz set created_by = $2 where created_by = $1"#,
+        guest_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"update quiz_attempt set user_id = $2 where user_id = $1"#,
+        guest_id,
+        into_id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(r#"delete from auth_session where user_id = $1"#, guest_id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(
+        r#"
+            update "user"
+            set score = "user".score + guest.score - $3::bigint
+            from "user" guest
+            where "user".id = $2 and guest.id = $1
+        "#,
+        guest_id,
+        into_id,
+        duplicate_rewards
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(r#"delete from "user" where id = $1"#, guest_id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await
+}
+
+// Deletes the user and everything stored about them: progress, quiz attempts, sessions,
+// tokens, second factors and linked identities. Their audit events are anonymized. Quizzes they created are deleted as well,
+// together with their questions, translations and the attempts of other users.
+// Returns Ok(None) if the user does not exist
+#[tracing::instrument(skip(pool))]
+pub async fn delete_user(pool: &PgPool, id: Uuid) -> Result<Option<()>> {
+    let mut tx = pool.begin().await?;
+    let Some(email) =
+        sqlx::query_scalar!(r#"select email from "user" where id = $1 for update"#, id)
+            .fetch_optional(&mut tx)
+            .await?
+    else {
         return Ok(None);
     };
-    let patch = UserPatch {
-        score: Some(user.score + score),
-        ..UserPatch::default()
+
+    let translation_keys = sqlx::query_scalar!(
+        r#"
+            select title as "id!" from "quiz" where created_by = $1
+            union all
+            select question.question from "question"
+            inner join "quiz" on quiz.id = question.quiz_id
+            where quiz.created_by = $1
+        "#,
+        id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+            delete from "quiz_attempt"
+            where user_id = $1 or quiz_id in (select id from "quiz" where created_by = $1)
+        "#,
+        id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"delete from "question" where quiz_id in (select id from "quiz" where created_by = $1)"#,
+        id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(r#"delete from "quiz" where created_by = $1"#, id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(
+        r#"delete from "translation" where id = any($1)"#,
+        &translation_keys
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"delete from "translation_key" where id = any($1)"#,
+        &translation_keys
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(r#"delete from "user_challenge" where user_id = $1"#, id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(r#"delete from "user_challenge_day" where user_id = $1"#, id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(r#"delete from "challenge_reward" where user_id = $1"#, id)
+        .execute(&mut tx)
+        .await?;
+    // Progress events can only be deleted together with the user, they cascade
+    sqlx::query!(r#"delete from "auth_session" where user_id = $1"#, id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(r#"delete from "user_token" where user_id = $1"#, id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(r#"delete from "recovery_code" where user_id = $1"#, id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(r#"delete from "user_totp" where user_id = $1"#, id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(r#"delete from "user_identity" where user_id = $1"#, id)
+        .execute(&mut tx)
+        .await?;
+    crate::core::audit::anonymize_users(&mut tx, &[id]).await?;
+    if let Some(email) = email {
+        sqlx::query!(
+            r#"delete from "login_failure" where key = 'account:' || lower($1)"#,
+            email
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    sqlx::query!(r#"delete from "user" where id = $1"#, id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(Some(()))
+}
+
+// Deletes guests without any activity for the given number of days, together with their
+// progress, quiz attempts, sessions and tokens. Activity is anything that touched the
+// account: a profile change, progress, a quiz attempt or a refreshed session.
+// Returns the number of deleted guests
+#[tracing::instrument(skip(pool))]
+pub async fn delete_inactive_guests(pool: &PgPool, retention_days: i32) -> Result<u64> {
+    let mut tx = pool.begin().await?;
+    let ids = sqlx::query_scalar!(
+        r#"
+            select u.id from "user" u
+            where u.is_guest
+                and not exists (select 1 from "quiz" where created_by = u.id)
+                and greatest(
+                    u.created_at,
+                    u.updated_at,
+                    (select max(updated_at) from "user_challenge" where user_id = u.id),
+                    (select max(created_at) from "quiz_attempt" where user_id = u.id),
+                    (
+                        select max(refresh_token.created_at) from "refresh_token"
+                        inner join "auth_session" on auth_session.id = refresh_token.session_id
+                        where auth_session.user_id = u.id
+                    )
+                ) < now() - make_interval(days => $1)
+            for update of u skip locked
+        "#,
+        retention_days
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    sqlx::query!(
+        r#"delete from "user_challenge" where user_id = any($1)"#,
+        &ids
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"delete from "user_challenge_day" where user_id = any($1)"#,
+        &ids
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"delete from "challenge_reward" where user_id = any($1)"#,
+        &ids
+    )
+    .execute(&mut tx)
+    .await?;
+    // Progress events can only be deleted together with the users, they cascade
+    sqlx::query!(
+        r#"delete from "quiz_attempt" where user_id = any($1)"#,
+        &ids
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"delete from "auth_session" where user_id = any($1)"#,
+        &ids
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(r#"delete from "user_token" where user_id = any($1)"#, &ids)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(
+        r#"delete from "recovery_code" where user_id = any($1)"#,
+        &ids
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(r#"delete from "user_totp" where user_id = any($1)"#, &ids)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query!(
+        r#"delete from "user_identity" where user_id = any($1)"#,
+        &ids
+    )
+    .execute(&mut tx)
+    .await?;
+    crate::core::audit::anonymize_users(&mut tx, &ids).await?;
+    let deleted = sqlx::query!(r#"delete from "user" where id = any($1)"#, &ids)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    tx.commit().await?;
+    Ok(deleted)
+}
+
+// Collects everything stored about the user, for data portability requests.
+// Texts of created quizzes are returned in the preferred of the given languages.
+// Returns Ok(None) if the user does not exist
+#[tracing::instrument(skip(pool))]
+pub async fn export_user(
+    pool: &PgPool,
+    id: Uuid,
+    languages: &[String],
+) -> Result<Option<UserExport>> {
+    let Some(user) = get_user(pool, id).await? else {
+        return Ok(None);
     };
+    let challenges = crate::core::challenge::get_user_challenges(pool, id, None).await?;
+    let challenge_days = crate::core::challenge::get_challenge_days(pool, id, None, None).await?;
+    let progress_events = crate::core::challenge::get_progress_events(
+        pool,
+        id,
+        &crate::core::challenge::ProgressEventFilter::default(),
+        None,
+    )
+    .await?;
 
-    Ok(update_user(pool, id, &patch).await?.map(|_| ()))
+    let quiz_ids = sqlx::query_scalar!(
+        r#"select id from "quiz" where created_by = $1 order by created_at"#,
+        id
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut quizzes = Vec::with_capacity(quiz_ids.len());
+    for quiz_id in quiz_ids {
+        if let Some(quiz) = crate::core::quiz::get_quiz(pool, quiz_id, languages).await? {
+            quizzes.push(quiz.into());
+        }
+    }
+
+    let quiz_attempts = sqlx::query!(
+        r#"
+            select id, quiz_id, answers, correct, total, score_awarded, created_at
+            from "quiz_attempt" where user_id = $1
+            order by created_at
+        "#,
+        id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| ExportedQuizAttempt {
+        id: row.id,
+        quiz_id: row.quiz_id,
+        answers: serde_json::from_value(row.answers).unwrap_or_default(),
+        correct: row.correct,
+        total: row.total,
+        score_awarded: row.score_awarded,
+        created_at: row.created_at,
+    })
+    .collect();
+
+    let sessions = sqlx::query_as!(
+        ExportedSession,
+        r#"
+            select id, created_at, expires_at, revoked_at from "auth_session"
+            where user_id = $1
+            order by created_at
+        "#,
+        id
+    )
+    .fetch_all(pool)
+    .await?;
+    let identities = sqlx::query_as!(
+        ExportedIdentity,
+        r#"
+            select provider, subject, created_at from "user_identity"
+            where user_id = $1
+            order by created_at
+        "#,
+        id
+    )
+    .fetch_all(pool)
+    .await?;
+    let api_keys = crate::core::api_key::get_api_keys(pool, id).await?;
+    let two_factor_enabled = crate::core::two_factor::is_enabled(pool, id).await?;
+
+    Ok(Some(UserExport {
+        exported_at: Utc::now(),
+        user,
+        challenges,
+        challenge_days,
+        progress_events,
+        quizzes,
+        quiz_attempts,
+        sessions,
+        identities,
+        api_keys,
+        two_factor_enabled,
+    }))
 }
 
 #[cfg(test)]
@@ -181,6 +725,7 @@ mod tests {
                 avatar_seed: Some("updated".to_owned()),
                 is_guest: Some(false),
                 score: Some(133),
+                timezone: Some("Europe/Zurich".to_owned()),
             },
         )
         .await?
@@ -191,6 +736,9 @@ mod tests {
         assert_eq!(updated.avatar_seed, "updated");
         assert!(!updated.is_guest);
         assert_eq!(updated.score, 133);
+        assert_eq!(updated.timezone, "Europe/Zurich");
+        assert!(super::is_valid_timezone(&pool, "Europe/Zurich").await?);
+        assert!(!super::is_valid_timezone(&pool, "Mars/Olympus_Mons").await?);
         Ok(())
     }
 
@@ -215,4 +763,187 @@ mod tests {
         assert_eq!(user.score, 0);
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn upgrade_guest(pool: PgPool) -> sqlx::Result<()> {
+        let guest_id = super::insert_user(&pool, &User::default()).await?.unwrap();
+        let user = super::upgrade_guest(&pool, guest_id, "guest@example.com", "hash")
+            .await?
+            .expect("unable to upgrade guest");
+        assert_eq!(user.id, guest_id);
+        assert!(!user.is_guest);
+        assert_eq!(user.email.as_deref(), Some("guest@example.com"));
+
+        let other_guest = super::insert_user(&pool, &User::default()).await?.unwrap();
+        let user = super::upgrade_guest(&pool, other_guest, "GUEST@example.com", "hash").await?;
+        assert!(user.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn merge_users(pool: PgPool) -> sqlx::Result<()> {
+        let guest_id = super::insert_user(&pool, &User::default()).await?.unwrap();
+        let user_id = super::insert_user(&pool, &verified_user()).await?.unwrap();
+        super::increase_score(&pool, guest_id, 5).await?;
+        super::increase_score(&pool, user_id, 3).await?;
+
+        let challenge = crate::entities::challenge::Challenge {
+            goal: 10,
+            ..Default::default()
+        };
+        let challenge_id =
+            crate::core::challenge::insert_challenge(&pool, &challenge, None).await?;
+        let source = crate::entities::challenge::ProgressSource::App;
+        crate::core::challenge::add_progress(&pool, guest_id, challenge_id, 2, source, None)
+            .await?;
+        crate::core::challenge::add_progress(&pool, user_id, challenge_id, 1, source, None).await?;
+
+        super::merge_users(&pool, guest_id, user_id).await?;
+
+        assert!(super::get_user(&pool, guest_id).await?.is_none());
+        let user = super::get_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.score, 8);
+        let progress =
+            crate::core::challenge::get_user_challenges(&pool, user_id, Some(challenge_id)).await?;
+        assert_eq!(progress[0].progress, 3);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn merge_users_with_same_reward(pool: PgPool) -> sqlx::Result<()> {
+        let guest_id = super::insert_user(&pool, &User::default()).await?.unwrap();
+        let user_id = super::insert_user(&pool, &verified_user()).await?.unwrap();
+        let challenge = crate::entities::challenge::Challenge {
+            goal: 1,
+            reward: 10,
+            ..Default::default()
+        };
+        let challenge_id =
+            crate::core::challenge::insert_challenge(&pool, &challenge, None).await?;
+        let source = crate::entities::challenge::ProgressSource::App;
+        for id in [guest_id, user_id] {
+            crate::core::challenge::add_progress(&pool, id, challenge_id, 1, source, None).await?;
+        }
+        super::increase_score(&pool, guest_id, 2).await?;
+
+        super::merge_users(&pool, guest_id, user_id).await?;
+
+        let user = super::get_user(&pool, user_id).await?.unwrap();
+        assert_eq!(user.score, 12);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn verify_email(pool: PgPool) -> sqlx::Result<()> {
+        let id = super::insert_user(&pool, &verified_user()).await?.unwrap();
+        assert!(!super::is_verified(&pool, id).await?);
+        super::verify_email(&pool, id).await?.unwrap();
+        assert!(super::is_verified(&pool, id).await?);
+
+        let same_email = super::UserPatch {
+            email: Some("TEST@example.com".to_owned()),
+            name: Some("renamed".to_owned()),
+            ..super::UserPatch::default()
+        };
+        super::update_user(&pool, id, &same_email).await?;
+        assert!(super::is_verified(&pool, id).await?);
+
+        let new_email = super::UserPatch {
+            email: Some("new@example.com".to_owned()),
+            ..super::UserPatch::default()
+        };
+        super::update_user(&pool, id, &new_email).await?;
+        assert!(!super::is_verified(&pool, id).await?);
+
+        let guest = super::insert_user(&pool, &User::default()).await?.unwrap();
+        assert!(super::verify_email(&pool, guest).await?.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn export_and_delete_user(pool: PgPool) -> sqlx::Result<()> {
+        let user_id = super::insert_user(&pool, &verified_user()).await?.unwrap();
+        let guest_id = super::insert_user(&pool, &User::default()).await?.unwrap();
+        let quiz = crate::entities::quiz::DBQuiz {
+            title: "Planets".to_owned(),
+            created_by: user_id,
+            ..Default::default()
+        };
+        let quiz_id = crate::core::quiz::insert_quiz(&pool, &quiz, None).await?;
+        crate::core::quiz::submit_attempt(&pool, quiz_id, user_id, vec![]).await?;
+        crate::core::quiz::submit_attempt(&pool, quiz_id, guest_id, vec![]).await?;
+
+        let export = super::export_user(&pool, user_id, &[]).await?.unwrap();
+        assert_eq!(export.user.id, user_id);
+        assert_eq!(export.quizzes.len(), 1);
+        assert_eq!(export.quizzes[0].title, "Planets");
+        assert_eq!(export.quiz_attempts.len(), 1);
+
+        assert!(super::delete_user(&pool, user_id).await?.is_some());
+        assert!(super::get_user(&pool, user_id).await?.is_none());
+        assert!(crate::core::quiz::get_quiz(&pool, quiz_id, &[])
+            .await?
+            .is_none());
+        assert!(super::get_user(&pool, guest_id).await?.is_some());
+        assert!(super::delete_user(&pool, user_id).await?.is_none());
+        assert!(super::export_user(&pool, user_id, &[]).await?.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn delete_inactive_guests(pool: PgPool) -> sqlx::Result<()> {
+        // Inserted directly, updates would set updated_at to now
+        let insert_guest = |days: i32| {
+            sqlx::query_scalar!(
+                r#"
+                    insert into "user" (id, name, avatar_seed, is_guest, created_at)
+                    values ($1, '', '', true, now() - make_interval(days => $2))
+                    returning id
+                "#,
+                super::Uuid::new_v4(),
+                days
+            )
+            .fetch_one(&pool)
+        };
+        let stale_id = insert_guest(40).await?;
+        let active_id = insert_guest(40).await?;
+        let new_id = insert_guest(1).await?;
+        let user_id = super::insert_user(&pool, &verified_user()).await?.unwrap();
+        sqlx::query!(
+            r#"update "user" set created_at = now() - interval '40 days' where id = $1"#,
+            user_id
+        )
+        .execute(&pool)
+        .await?;
+
+        let challenge = crate::entities::challenge::Challenge {
+            goal: 10,
+            ..Default::default()
+        };
+        let challenge_id =
+            crate::core::challenge::insert_challenge(&pool, &challenge, None).await?;
+        sqlx::query!(
+            r#"
+                insert into "user_challenge" (user_id, challenge_id, progress, updated_at)
+                values ($1, $3, 1, now() - interval '35 days'), ($2, $3, 1, now())
+            "#,
+            stale_id,
+            active_id,
+            challenge_id
+        )
+        .execute(&pool)
+        .await?;
+
+        assert_eq!(super::delete_inactive_guests(&pool, 30).await?, 1);
+        assert!(super::get_user(&pool, stale_id).await?.is_none());
+        assert!(super::get_user(&pool, active_id).await?.is_some());
+        assert!(super::get_user(&pool, new_id).await?.is_some());
+        assert!(super::get_user(&pool, user_id).await?.is_some());
+        let progress =
+            crate::core::challenge::get_user_challenges(&pool, stale_id, Some(challenge_id))
+                .await?;
+        assert!(progress.is_empty());
+        assert_eq!(super::delete_inactive_guests(&pool, 30).await?, 0);
+        Ok(())
+    }
 }
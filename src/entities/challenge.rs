@@ -1,22 +1,18 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
 use poem_openapi::{Enum, Object};
 use serde::{Deserialize, Serialize};
 use sqlx::Type;
 use uuid::Uuid;
 
-#[derive(Type, Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Type, Enum, Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 #[sqlx(type_name = "challengetype", rename_all = "lowercase")]
 pub enum ChallengeType {
+    #[default]
     Counter,
+    /// Progress only counts for the current day in the user's timezone
     DailyChallenge,
 }
 
-impl Default for ChallengeType {
-    fn default() -> Self {
-        Self::Counter
-    }
-}
-
 #[derive(Object, Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Challenge {
     #[oai(read_only)]
@@ -24,14 +20,119 @@ pub struct Challenge {
     pub title: String,
     pub category: String,
     pub r#type: ChallengeType,
+    #[oai(validator(minimum(value = "1")))]
     pub goal: i32,
     pub description: String,
+    /// Score for completing the challenge, every day for daily challenges
+    #[oai(default)]
+    #[serde(default)]
+    pub reward: i32,
+    /// Most progress a single submission may add, unlimited if not set
+    #[oai(validator(minimum(value = "1")))]
+    pub max_per_submission: Option<i32>,
+    /// Most progress a user may add per day in their timezone, unlimited if not set
+    #[oai(validator(minimum(value = "1")))]
+    pub max_per_day: Option<i32>,
+    /// Whether progress may be negative. Otherwise it only ever increases
+    #[oai(default)]
+    #[serde(default)]
+    pub allow_decrease: bool,
+}
+
+#[derive(Object, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChallengeTranslation {
+    pub description: String,
 }
 
 #[derive(Object, Debug, Clone, Default, Serialize, Deserialize)]
 pub struct UserChallenge {
     pub user_id: Uuid,
     pub challenge_id: Uuid,
+    /// The progress of today for daily challenges
     pub progress: i32,
     pub updated_at: Option<DateTime<Utc>>,
+    /// When the goal was reached, today for daily challenges
+    pub completed_at: Option<DateTime<Utc>>,
+    /// Daily challenges only: the current day in the user's timezone
+    pub day: Option<NaiveDate>,
+    /// Daily challenges only: days in a row the goal was reached, up to today or yesterday
+    pub current_streak: Option<i32>,
+    /// Daily challenges only: the most days in a row the goal was ever reached
+    pub longest_streak: Option<i32>,
+}
+
+/// Why progress was not accepted.
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ProgressRejectionReason {
+    /// Progress must not be zero
+    Zero,
+    /// The challenge only allows adding progress
+    Negative,
+    /// More than the challenge allows at once
+    SubmissionLimit,
+    /// More than the challenge allows per day
+    DailyLimit,
+    /// The progress would exceed the largest possible value
+    Overflow,
+    /// More would be removed than was added
+    BelowZero,
+}
+
+#[derive(Object, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProgressRejection {
+    pub reason: ProgressRejectionReason,
+    /// The exceeded limit, if any
+    pub limit: Option<i32>,
+    /// Progress that may still be added today for the daily limit,
+    /// or that may still be removed if the progress would go below zero
+    pub remaining: Option<i32>,
+}
+
+/// How progress was submitted.
+#[derive(Type, Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[sqlx(type_name = "progresssource", rename_all = "lowercase")]
+pub enum ProgressSource {
+    /// By the user in the app or browser
+    App,
+    /// With a personal API key, e.g. by a tracking device
+    ApiKey,
+    /// Progress from before events were recorded
+    Migration,
+    /// The user removed their progress, the amount undoes all previous events
+    Reset,
+}
+
+/// A single change of challenge progress.
+#[derive(Object, Debug, Clone, Serialize, Deserialize)]
+pub struct ProgressEvent {
+    pub id: Uuid,
+    pub challenge_id: Uuid,
+    pub amount: i32,
+    /// The progress in the challenge after this event
+    pub total: i64,
+    pub created_at: DateTime<Utc>,
+    pub source: ProgressSource,
+    pub note: Option<String>,
+}
+
+/// Progress of a daily challenge on a single day.
+#[derive(Object, Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeDay {
+    pub challenge_id: Uuid,
+    pub day: NaiveDate,
+    pub progress: i32,
+    /// Whether the goal was reached on this day
+    pub completed: bool,
+}
+
+/// The progress of a user after adding to it.
+#[derive(Object, Debug, Clone, Serialize, Deserialize)]
+pub struct ProgressResult {
+    #[oai(flatten)]
+    #[serde(flatten)]
+    pub challenge: UserChallenge,
+    /// Whether this progress reached the goal, today's goal for daily challenges
+    pub just_completed: bool,
+    /// Score awarded for reaching the goal, 0 unless it was just reached
+    pub reward: i32,
 }
@@ -17,6 +17,8 @@ pub struct MultipleChoiceQuestion {
     pub correct_answer: i32,
 }
 
+/// A question answered with a number. Any answer within
+/// `range_start..=range_end` is considered correct.
 #[derive(Object, Clone, Debug, Default, Serialize, Deserialize)]
 pub struct NumericQuestion {
     pub range_start: i32,
@@ -37,6 +39,73 @@ pub enum QuestionType {
     TrueOrFalse(TrueOrFalseQuestion),
 }
 
+impl QuestionType {
+    /// Grades the given answer. Answers of a different question type are never correct.
+    pub fn is_correct(&self, answer: &QuestionAnswer) -> bool {
+        match (self, answer) {
+            (Self::MultipleChoice(q), QuestionAnswer::MultipleChoice(a)) => {
+                q.correct_answer == a.answer
+            }
+            (Self::Numeric(q), QuestionAnswer::Numeric(a)) => {
+                (q.range_start..=q.range_end).contains(&a.answer)
+            }
+            (Self::TrueOrFalse(q), QuestionAnswer::TrueOrFalse(a)) => q.correct_answer == a.answer,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Object, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PlayerMultipleChoiceQuestion {
+    pub answers: Vec<String>,
+}
+
+#[derive(Object, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PlayerNumericQuestion {}
+
+#[derive(Object, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PlayerTrueOrFalseQuestion {}
+
+/// The player-facing view of a [`QuestionType`], without the correct answers.
+#[derive(Union, Clone, Debug, Serialize, Deserialize)]
+#[oai(discriminator_name = "type")]
+#[serde(tag = "type")]
+pub enum PlayerQuestionType {
+    MultipleChoice(PlayerMultipleChoiceQuestion),
+    Numeric(PlayerNumericQuestion),
+    TrueOrFalse(PlayerTrueOrFalseQuestion),
+}
+
+#[derive(Object, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MultipleChoiceAnswer {
+    pub answer: i32,
+}
+
+#[derive(Object, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NumericAnswer {
+    pub answer: i32,
+}
+
+#[derive(Object, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TrueOrFalseAnswer {
+    pub answer: bool,
+}
+
+#[derive(Union, Clone, Debug, Serialize, Deserialize)]
+#[oai(discriminator_name = "type")]
+#[serde(tag = "type")]
+pub enum QuestionAnswer {
+    MultipleChoice(MultipleChoiceAnswer),
+    Numeric(NumericAnswer),
+    TrueOrFalse(TrueOrFalseAnswer),
+}
+
+#[derive(Object, Clone, Debug, Serialize, Deserialize)]
+pub struct QuizAnswer {
+    pub question_id: Uuid,
+    pub answer: QuestionAnswer,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DBQuizQuestion {
     pub id: Uuid,
@@ -75,6 +144,64 @@ pub struct APIQuiz {
     pub questions: Vec<APIQuizQuestion>,
 }
 
+#[derive(Object, Clone, Debug, Serialize, Deserialize)]
+pub struct PlayerQuizQuestion {
+    pub id: Uuid,
+    pub quiz_id: Uuid,
+    pub question: String,
+    pub data: PlayerQuestionType,
+}
+
+#[derive(Object, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PlayerQuiz {
+    pub id: Uuid,
+    pub title: String,
+    pub created_at: DateTime<Utc>,
+    pub created_by: Uuid,
+    pub questions: Vec<PlayerQuizQuestion>,
+}
+
+#[derive(Object, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QuestionTranslation {
+    pub question_id: Uuid,
+    pub question: String,
+}
+
+#[derive(Object, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QuizTranslation {
+    pub title: Option<String>,
+    #[oai(default)]
+    pub questions: Vec<QuestionTranslation>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DBQuizAttempt {
+    pub id: Uuid,
+    pub quiz_id: Uuid,
+    pub user_id: Uuid,
+    pub answers: Vec<QuizAnswer>,
+    pub correct: i32,
+    pub total: i32,
+    pub score_awarded: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Object, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QuestionResult {
+    pub question_id: Uuid,
+    pub correct: bool,
+}
+
+#[derive(Object, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QuizAttemptResult {
+    pub id: Uuid,
+    pub quiz_id: Uuid,
+    pub correct: i32,
+    pub total: i32,
+    pub score_awarded: i32,
+    pub results: Vec<QuestionResult>,
+}
+
 impl From<APIQuizQuestion> for DBQuizQuestion {
     fn from(value: APIQuizQuestion) -> Self {
         Self {
@@ -120,3 +247,38 @@ impl From<DBQuiz> for APIQuiz {
         }
     }
 }
+
+impl From<QuestionType> for PlayerQuestionType {
+    fn from(value: QuestionType) -> Self {
+        match value {
+            QuestionType::MultipleChoice(q) => {
+                Self::MultipleChoice(PlayerMultipleChoiceQuestion { answers: q.answers })
+            }
+            QuestionType::Numeric(_) => Self::Numeric(PlayerNumericQuestion {}),
+            QuestionType::TrueOrFalse(_) => Self::TrueOrFalse(PlayerTrueOrFalseQuestion {}),
+        }
+    }
+}
+
+impl From<DBQuizQuestion> for PlayerQuizQuestion {
+    fn from(value: DBQuizQuestion) -> Self {
+        Self {
+            id: value.id,
+            quiz_id: value.quiz_id,
+            question: value.question,
+            data: value.data.0.into(),
+        }
+    }
+}
+
+impl From<DBQuiz> for PlayerQuiz {
+    fn from(value: DBQuiz) -> Self {
+        Self {
+            id: value.id,
+            title: value.title,
+            created_at: value.created_at,
+            created_by: value.created_by,
+            questions: value.questions.into_iter().map(|q| q.into()).collect(),
+        }
+    }
+}
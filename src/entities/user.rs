@@ -0,0 +1,57 @@
+use chrono::{DateTime, Utc};
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    core::{api_key::ApiKey, user::User},
+    entities::{
+        challenge::{ChallengeDay, ProgressEvent, UserChallenge},
+        quiz::{APIQuiz, QuizAnswer},
+    },
+};
+
+#[derive(Object, Clone, Debug, Serialize, Deserialize)]
+pub struct ExportedQuizAttempt {
+    pub id: Uuid,
+    pub quiz_id: Uuid,
+    pub answers: Vec<QuizAnswer>,
+    pub correct: i32,
+    pub total: i32,
+    pub score_awarded: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Object, Clone, Debug, Serialize, Deserialize)]
+pub struct ExportedSession {
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Object, Clone, Debug, Serialize, Deserialize)]
+pub struct ExportedIdentity {
+    pub provider: String,
+    /// The user's id at the provider
+    pub subject: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Everything stored about a user.
+#[derive(Object, Clone, Debug, Serialize, Deserialize)]
+pub struct UserExport {
+    pub exported_at: DateTime<Utc>,
+    pub user: User,
+    pub challenges: Vec<UserChallenge>,
+    /// Progress of daily challenges per day
+    pub challenge_days: Vec<ChallengeDay>,
+    pub progress_events: Vec<ProgressEvent>,
+    /// Quizzes created by the user
+    pub quizzes: Vec<APIQuiz>,
+    pub quiz_attempts: Vec<ExportedQuizAttempt>,
+    pub sessions: Vec<ExportedSession>,
+    pub identities: Vec<ExportedIdentity>,
+    pub api_keys: Vec<ApiKey>,
+    pub two_factor_enabled: bool,
+}
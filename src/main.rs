@@ -1,20 +1,33 @@
-use opentelemetry::global;
+use opentelemetry::{
+    global,
+    sdk::{
+        export::metrics::aggregation,
+        metrics::{controllers, processors, selectors},
+    },
+};
 use poem::{
     endpoint::PrometheusExporter,
     listener::TcpListener,
-    middleware::Cors,
-    session::{CookieConfig, CookieSession},
-    web::cookie::CookieKey,
+    middleware::Csrf,
+    session::{CookieConfig, ServerSession},
+    web::cookie::{CookieKey, SameSite},
     EndpointExt, Server,
 };
+use poem_dbsession::{sqlx::PgSessionStorage, DatabaseConfig};
+use sha2::{Digest, Sha256};
 use sqlx::{migrate::Migrator, PgPool};
 use tracing_subscriber::{layer::SubscriberExt, Registry};
 
+pub mod cleanup;
 pub mod core;
 pub mod entities;
+pub mod mail;
 pub mod middleware;
+pub mod oidc;
+pub mod password_policy;
 pub mod routes;
 pub mod security;
+pub mod totp;
 
 fn init_tracer() {
     if std::env::var_os("RUST_LOG").is_none() {
@@ -38,12 +51,56 @@ fn init_tracer() {
         .expect("Unable to set default global subscriber");
 }
 
+// Metrics recorded through the global meter are exported at /metrics
+fn init_metrics() -> PrometheusExporter {
+    let controller = controllers::basic(
+        processors::factory(
+            selectors::simple::histogram([0.1, 0.5, 1.0, 5.0, 10.0, 60.0]),
+            aggregation::cumulative_temporality_selector(),
+        )
+        .with_memory(true),
+    )
+    .build();
+    global::set_meter_provider(controller.clone());
+    PrometheusExporter::with_controller(controller)
+}
+
 static MIGRATOR: Migrator = sqlx::migrate!();
 
+// Builds the Bloom filter for BREACHED_PASSWORDS_FILE from a list of SHA-1 hashes,
+// e.g. the one of Have I Been Pwned. Arguments: <list> <filter> [false positive rate]
+fn build_breached_filter(args: &[String]) {
+    let [list, filter, rest @ ..] = args else {
+        panic!("Usage: build-breached-filter <list> <filter> [false positive rate]");
+    };
+    let false_positive_rate = match rest.first() {
+        Some(rate) => rate
+            .parse()
+            .expect("The false positive rate is not a number"),
+        None => 0.001,
+    };
+    let file = std::fs::File::create(filter).expect("Unable to create the filter");
+    let mut out = std::io::BufWriter::new(file);
+    password_policy::BreachedPasswords::build(
+        std::path::Path::new(list),
+        false_positive_rate,
+        &mut out,
+    )
+    .expect("Unable to build the filter");
+    std::io::Write::flush(&mut out).expect("Unable to write the filter");
+}
+
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("build-breached-filter") {
+        build_breached_filter(&args[2..]);
+        return;
+    }
+
     dotenvy::dotenv().ok();
     init_tracer();
+    let metrics = init_metrics();
 
     let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL is required");
 
@@ -64,23 +121,66 @@ async fn main() {
     //     return Err(anyhow!("secret was not found").into());
     // };
 
-    let cookie_config =
-        CookieConfig::signed(CookieKey::from(secret.as_bytes())).name("X-SESSION-TOKEN");
-    let session = CookieSession::new(cookie_config);
-
-    let cors = Cors::new()
-        .allow_origins_fn(|_| true)
-        .allow_methods(vec!["GET", "POST", "PUT", "DELETE", "PATCH"])
-        .allow_credentials(true);
+    let jwt_keys = security::JwtKeys::from_env(&secret).expect("Unable to load JWT keys");
+
+    let audit_key = security::AuditKey::new(&secret);
+    let password_hashing =
+        security::PasswordHashing::from_env().expect("Unable to configure password hashing");
+    let password_policy =
+        password_policy::PasswordPolicy::from_env().expect("Unable to load password policy");
+    let mailer = mail::Mailer::from_env().expect("Unable to set up mail transport");
+    let oidc_providers =
+        oidc::OidcProviders::from_env().expect("Unable to load identity providers");
+
+    let guest_cleanup =
+        cleanup::GuestCleanup::from_env().expect("Unable to configure guest cleanup");
+    guest_cleanup.clone().spawn(pool.clone());
+
+    // Browsers may log in with a session cookie instead of tokens. Set SESSION_COOKIE_SECURE=false
+    // to use it without HTTPS during development
+    let secure_cookies = std::env::var("SESSION_COOKIE_SECURE").map_or(true, |v| v != "false");
+    let cookie_config = CookieConfig::signed(CookieKey::from(secret.as_bytes()))
+        .name(security::SESSION_COOKIE_NAME)
+        .secure(secure_cookies)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(std::time::Duration::from_secs(
+            security::REFRESH_TOKEN_LIFETIME_DAYS as u64 * 24 * 3600,
+        ));
+    let session_storage = PgSessionStorage::try_new(DatabaseConfig::new(), pool.clone())
+        .await
+        .expect("Unable to set up session storage");
+    let session = ServerSession::new(cookie_config, session_storage);
+    let csrf = Csrf::new()
+        .key(Sha256::digest(secret.as_bytes()).into())
+        .secure(secure_cookies)
+        .same_site(SameSite::Strict);
+
+    let trusted_proxies =
+        routes::TrustedProxies::from_env().expect("Unable to read trusted proxies");
+
+    let cors = middleware::CorsConfig::from_env()
+        .expect("Unable to read allowed origins")
+        .cors();
 
     let app = routes::routes()
-        .at("/metrics", PrometheusExporter::new())
+        .at("/metrics", metrics)
         .data(pool)
+        .data(jwt_keys)
+        .data(password_hashing)
+        .data(audit_key)
+        .data(password_policy)
+        .data(mailer)
+        .data(oidc_providers)
+        .data(guest_cleanup)
+        .data(trusted_proxies)
         .with(session)
+        .with(csrf)
         .with(middleware::LogMiddleware)
         .with(cors);
 
     Server::new(TcpListener::bind(("0.0.0.0", port)))
         .run(app)
-        .await;
+        .await
+        .expect("Unable to run server");
 }
@@ -0,0 +1,94 @@
+use poem::{http::HeaderValue, middleware::Cors};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CorsConfigError {
+    #[error("{0} is not a valid origin")]
+    InvalidOrigin(String),
+}
+
+/// Origins of the web apps that may call the API from a browser.
+/// Only these get to send the session cookie, other sites could otherwise act for a logged in
+/// user and read the CSRF token.
+#[derive(Debug, Clone, Default)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<HeaderValue>,
+}
+
+impl CorsConfig {
+    /// Reads the comma separated `CORS_ALLOWED_ORIGINS`, e.g. `https://app.letsscience.ch`.
+    /// Without it any origin may call the API, but never with credentials.
+    pub fn from_env() -> Result<Self, CorsConfigError> {
+        let origins = std::env::var("CORS_ALLOWED_ORIGINS").unwrap_or_default();
+        let allowed_origins = origins
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .map_err(|_| CorsConfigError::InvalidOrigin(origin.to_owned()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { allowed_origins })
+    }
+
+    pub fn cors(&self) -> Cors {
+        Cors::new()
+            .allow_origins(self.allowed_origins.clone())
+            .allow_methods(vec!["GET", "POST", "PUT", "DELETE", "PATCH"])
+            .allow_credentials(!self.allowed_origins.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{
+        handler,
+        http::{header, HeaderValue},
+        Endpoint, EndpointExt, Request,
+    };
+
+    use super::CorsConfig;
+
+    #[handler]
+    fn index() -> &'static str {
+        "ok"
+    }
+
+    #[tokio::test]
+    async fn credentials_only_for_allowed_origins() {
+        let config = CorsConfig {
+            allowed_origins: vec![HeaderValue::from_static("https://app.letsscience.ch")],
+        };
+        let ep = index.with(config.cors());
+        let request = |origin: &'static str| {
+            Request::builder()
+                .header(header::ORIGIN, HeaderValue::from_static(origin))
+                .finish()
+        };
+
+        let resp = ep.get_response(request("https://app.letsscience.ch")).await;
+        assert_eq!(
+            resp.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            Some(&HeaderValue::from_static("true"))
+        );
+        let resp = ep.get_response(request("https://evil.example")).await;
+        assert!(resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS)
+            .is_none());
+        assert!(resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        // Without a list any origin may call the API, but without the cookie
+        let ep = index.with(CorsConfig::default().cors());
+        let resp = ep.get_response(request("https://evil.example")).await;
+        assert!(resp.status().is_success());
+        assert!(resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS)
+            .is_none());
+    }
+}
@@ -1,3 +1,5 @@
+mod cors;
 mod log;
 
+pub use cors::{CorsConfig, CorsConfigError};
 pub use log::LogMiddleware;
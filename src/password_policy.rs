@@ -0,0 +1,451 @@
+// Rules new passwords have to follow.
+
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use poem_openapi::{Enum, Object};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tracing::error;
+
+/// Bloom filter of breached passwords, bundled with the server.
+/// Built from the list of `data/breached_passwords.py`.
+const BUNDLED_BREACHED_PASSWORDS: &[u8] = include_bytes!("../data/breached_passwords.bloom");
+
+/// Starts every Bloom filter file, followed by the number of hashes (u32) and the number
+/// of bits (u64), both little endian, and the bits.
+const BLOOM_MAGIC: &[u8; 8] = b"LSBLOOM1";
+const BLOOM_HEADER_LEN: u64 = 8 + 4 + 8;
+
+#[derive(Error, Debug)]
+pub enum PasswordPolicyError {
+    #[error("{0} is not a number")]
+    NotANumber(&'static str),
+    #[error("unable to read breached passwords: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("the breached passwords are not a valid Bloom filter")]
+    InvalidFilter,
+    #[error("the false positive rate has to be between 0 and 1")]
+    InvalidFalsePositiveRate,
+}
+
+/// Where the bits of a Bloom filter are read from.
+enum Bits {
+    Memory(Cow<'static, [u8]>),
+    /// Large filters are read from their file, only the bits a lookup needs
+    File(Mutex<File>),
+}
+
+/// Passwords known from data breaches, stored as a Bloom filter of their SHA-1 hashes.
+/// It answers with the configured false positive rate, but without the memory a list of
+/// all hashes would take. Filters are built from lists in the format of Have I Been Pwned,
+/// see [`BreachedPasswords::build`].
+#[derive(Clone)]
+pub struct BreachedPasswords(Arc<BloomFilter>);
+
+struct BloomFilter {
+    hashes: u32,
+    bits: u64,
+    storage: Bits,
+}
+
+impl Default for BreachedPasswords {
+    fn default() -> Self {
+        Self(Arc::new(BloomFilter {
+            hashes: 0,
+            bits: 0,
+            storage: Bits::Memory(Cow::Borrowed(&[])),
+        }))
+    }
+}
+
+/// The positions of the SHA-1 hash in a filter with the given size, by double hashing.
+fn bit_positions(hash: &[u8; 20], hashes: u32, bits: u64) -> impl Iterator<Item = u64> {
+    let h1 = u64::from_le_bytes(hash[0..8].try_into().unwrap());
+    let h2 = u64::from_le_bytes(hash[8..16].try_into().unwrap()) | 1;
+    (0..u64::from(hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bits)
+}
+
+/// Parses a line of a hash list: an uppercase or lowercase hex SHA-1 hash, optionally
+/// followed by `:` and a count.
+fn parse_hash(line: &str) -> Option<[u8; 20]> {
+    let hex = line.split(':').next()?.trim();
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut hash = [0u8; 20];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+impl BreachedPasswords {
+    fn from_header(header: &[u8]) -> Result<(u32, u64), PasswordPolicyError> {
+        if header.len() < BLOOM_HEADER_LEN as usize || &header[0..8] != BLOOM_MAGIC {
+            return Err(PasswordPolicyError::InvalidFilter);
+        }
+        let hashes = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let bits = u64::from_le_bytes(header[12..20].try_into().unwrap());
+        Ok((hashes, bits))
+    }
+
+    /// Reads a filter held in memory, e.g. the bundled one.
+    pub fn from_bytes(filter: Cow<'static, [u8]>) -> Result<Self, PasswordPolicyError> {
+        let (hashes, bits) = Self::from_header(&filter)?;
+        if (filter.len() as u64 - BLOOM_HEADER_LEN) * 8 < bits {
+            return Err(PasswordPolicyError::InvalidFilter);
+        }
+        Ok(Self(Arc::new(BloomFilter {
+            hashes,
+            bits,
+            storage: Bits::Memory(filter),
+        })))
+    }
+
+    pub fn bundled() -> Self {
+        Self::from_bytes(Cow::Borrowed(BUNDLED_BREACHED_PASSWORDS))
+            .expect("The bundled breached passwords are a valid filter")
+    }
+
+    /// Opens a filter file built with [`BreachedPasswords::build`]. It is not loaded into
+    /// memory, lookups read the bits they need from the file.
+    pub fn from_file(path: &Path) -> Result<Self, PasswordPolicyError> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; BLOOM_HEADER_LEN as usize];
+        file.read_exact(&mut header)?;
+        let (hashes, bits) = Self::from_header(&header)?;
+        if (file.metadata()?.len() - BLOOM_HEADER_LEN) * 8 < bits {
+            return Err(PasswordPolicyError::InvalidFilter);
+        }
+        Ok(Self(Arc::new(BloomFilter {
+            hashes,
+            bits,
+            storage: Bits::File(Mutex::new(file)),
+        })))
+    }
+
+    /// Builds a filter from a list of SHA-1 hashes, e.g. the one of Have I Been Pwned.
+    /// The list is read twice, to count and to insert the hashes, so it never has to fit
+    /// into memory. Only the filter does, it takes about 1.8 bytes per hash at a false
+    /// positive rate of 0.1%.
+    pub fn build(
+        list: &Path,
+        false_positive_rate: f64,
+        out: &mut impl Write,
+    ) -> Result<(), PasswordPolicyError> {
+        if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+            return Err(PasswordPolicyError::InvalidFalsePositiveRate);
+        }
+        let hashes_in = |path: &Path| -> io::Result<_> {
+            Ok(BufReader::new(File::open(path)?)
+                .lines()
+                .map(|line| line.map(|line| parse_hash(&line))))
+        };
+        let mut count = 0u64;
+        for hash in hashes_in(list)? {
+            if hash?.is_some() {
+                count += 1;
+            }
+        }
+        let ln2 = std::f64::consts::LN_2;
+        let bits = ((-(count.max(1) as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil()
+            as u64)
+            .max(8);
+        let hashes = ((bits as f64 / count.max(1) as f64 * ln2).round() as u32).max(1);
+
+        let mut filter = vec![0u8; bits.div_ceil(8) as usize];
+        for hash in hashes_in(list)? {
+            let Some(hash) = hash? else {
+                continue;
+            };
+            for bit in bit_positions(&hash, hashes, bits) {
+                filter[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        out.write_all(BLOOM_MAGIC)?;
+        out.write_all(&hashes.to_le_bytes())?;
+        out.write_all(&bits.to_le_bytes())?;
+        out.write_all(&filter)?;
+        Ok(())
+    }
+
+    /// Whether the password is breached. Passwords that are not may be reported as
+    /// breached with the false positive rate of the filter.
+    pub fn contains(&self, password: &str) -> bool {
+        let filter = &self.0;
+        if filter.bits == 0 {
+            return false;
+        }
+        let hash = <[u8; 20]>::from(Sha1::digest(password.as_bytes()));
+        let mut positions = bit_positions(&hash, filter.hashes, filter.bits);
+        match &filter.storage {
+            Bits::Memory(bytes) => positions.all(|bit| {
+                let byte = bytes[(BLOOM_HEADER_LEN + bit / 8) as usize];
+                byte & (1 << (bit % 8)) != 0
+            }),
+            Bits::File(file) => {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                let result = positions.try_fold(true, |found, bit| {
+                    file.seek(SeekFrom::Start(BLOOM_HEADER_LEN + bit / 8))?;
+                    let mut byte = [0u8];
+                    file.read_exact(&mut byte)?;
+                    Ok::<_, io::Error>(found && byte[0] & (1 << (bit % 8)) != 0)
+                });
+                // A broken file must not keep users from setting passwords
+                result.unwrap_or_else(|e| {
+                    error!("error {:?} while reading breached passwords", e);
+                    false
+                })
+            }
+        }
+    }
+}
+
+/// The rule a rejected password broke.
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PasswordRule {
+    MinLength,
+    Strength,
+    Breached,
+}
+
+#[derive(Object, Debug, Clone, PartialEq, Eq)]
+pub struct PasswordViolation {
+    pub rule: PasswordRule,
+    /// Explanation that can be shown to the user
+    pub message: String,
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct PasswordRejection {
+    pub violations: Vec<PasswordViolation>,
+    /// Estimated strength from 0 (very weak) to 4 (very strong)
+    pub score: u8,
+}
+
+#[derive(Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// The minimum strength score from 0 to 4
+    pub min_score: u8,
+    pub breached: BreachedPasswords,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            min_score: 2,
+            breached: BreachedPasswords::bundled(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Reads `PASSWORD_MIN_LENGTH` and `PASSWORD_MIN_SCORE`. Breached passwords are read from
+    /// the Bloom filter at `BREACHED_PASSWORDS_FILE` if set, otherwise the bundled one is used.
+    pub fn from_env() -> Result<Self, PasswordPolicyError> {
+        let default = Self::default();
+        let min_length = match std::env::var("PASSWORD_MIN_LENGTH") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| PasswordPolicyError::NotANumber("PASSWORD_MIN_LENGTH"))?,
+            Err(_) => default.min_length,
+        };
+        let min_score = match std::env::var("PASSWORD_MIN_SCORE") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| PasswordPolicyError::NotANumber("PASSWORD_MIN_SCORE"))?,
+            Err(_) => default.min_score,
+        };
+        let breached = match std::env::var_os("BREACHED_PASSWORDS_FILE") {
+            Some(path) => BreachedPasswords::from_file(Path::new(&path))?,
+            None => default.breached,
+        };
+        Ok(Self {
+            min_length,
+            min_score,
+            breached,
+        })
+    }
+
+    /// Checks the password against every rule. `user_inputs` are values like the email
+    /// or name of the user, which make a password easier to guess.
+    pub fn check(&self, password: &str, user_inputs: &[&str]) -> Result<(), PasswordRejection> {
+        let mut violations = Vec::new();
+        if password.chars().count() < self.min_length {
+            violations.push(PasswordViolation {
+                rule: PasswordRule::MinLength,
+                message: format!(
+                    "The password has to be at least {} characters long.",
+                    self.min_length
+                ),
+            });
+        }
+        let breached = self.breached.contains(password);
+        if breached {
+            violations.push(PasswordViolation {
+                rule: PasswordRule::Breached,
+                message: "The password is known from data breaches.".to_owned(),
+            });
+        }
+        let score = if breached {
+            0
+        } else {
+            strength_score(password, user_inputs)
+        };
+        if score < self.min_score {
+            violations.push(PasswordViolation {
+                rule: PasswordRule::Strength,
+                message: "The password is too easy to guess. Try a longer password or a few unrelated words."
+                    .to_owned(),
+            });
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(PasswordRejection { violations, score })
+        }
+    }
+}
+
+/// Estimates the strength of a password from 0 to 4, in the spirit of zxcvbn:
+/// the number of guesses is estimated from the used characters, where repeated
+/// characters, sequences like `abc` or `123` and parts of the user's inputs hardly count.
+pub fn strength_score(password: &str, user_inputs: &[&str]) -> u8 {
+    let mut lowered = password.to_lowercase();
+    for input in user_inputs {
+        for part in input
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|part| part.chars().count() >= 3)
+        {
+            lowered = lowered.replace(part, "\u{0}");
+        }
+    }
+
+    let chars: Vec<char> = password.chars().collect();
+    let mut charset = 0u32;
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        charset += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_uppercase()) {
+        charset += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        charset += 10;
+    }
+    if chars.iter().any(|c| c.is_ascii_punctuation() || *c == ' ') {
+        charset += 33;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        charset += 100;
+    }
+
+    // Characters continuing a repetition or sequence only add a little
+    let lowered: Vec<char> = lowered.chars().collect();
+    let mut effective_length = 0.0;
+    for (i, c) in lowered.iter().enumerate() {
+        let predictable = i > 0 && {
+            let previous = lowered[i - 1] as i64;
+            let delta = *c as i64 - previous;
+            *c == '\u{0}' || (-1..=1).contains(&delta)
+        };
+        effective_length += if predictable { 0.1 } else { 1.0 };
+    }
+
+    let guesses_log10 = effective_length * f64::from(charset.max(1)).log10();
+    match guesses_log10 {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use sha1::{Digest, Sha1};
+
+    use super::{BreachedPasswords, PasswordPolicy, PasswordRule};
+
+    #[test]
+    fn breached_passwords() {
+        let breached = BreachedPasswords::bundled();
+        assert!(breached.contains("password"));
+        assert!(breached.contains("123456"));
+        assert!(breached.contains("Sommer2023"));
+        assert!(breached.contains("24121999"));
+        assert!(!breached.contains("correct horse battery staple"));
+        assert!(!BreachedPasswords::default().contains("password"));
+    }
+
+    #[test]
+    fn build_breached_filter() {
+        let dir = std::env::temp_dir().join(format!("breached-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let list_path = dir.join("list.txt");
+        let mut list = std::fs::File::create(&list_path).unwrap();
+        for i in 0..1000 {
+            let hash = Sha1::digest(format!("breached{}", i).as_bytes());
+            writeln!(list, "{:X}:{}", hash, i + 1).unwrap();
+        }
+        writeln!(list, "not a hash").unwrap();
+        let filter_path = dir.join("filter.bloom");
+        let mut filter = std::fs::File::create(&filter_path).unwrap();
+        BreachedPasswords::build(&list_path, 0.01, &mut filter).unwrap();
+        drop(filter);
+
+        let from_file = BreachedPasswords::from_file(&filter_path).unwrap();
+        let in_memory =
+            BreachedPasswords::from_bytes(std::fs::read(&filter_path).unwrap().into()).unwrap();
+        for breached in [&from_file, &in_memory] {
+            assert!((0..1000).all(|i| breached.contains(&format!("breached{}", i))));
+            let false_positives = (0..10000)
+                .filter(|i| breached.contains(&format!("safe{}", i)))
+                .count();
+            assert!(false_positives < 300, "{} false positives", false_positives);
+        }
+        assert!(BreachedPasswords::build(&list_path, 0.0, &mut Vec::new()).is_err());
+        assert!(BreachedPasswords::from_bytes(b"not a filter".as_slice().into()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn strength_score() {
+        assert_eq!(super::strength_score("", &[]), 0);
+        assert_eq!(super::strength_score("aaaaaaaaaaaa", &[]), 0);
+        assert!(super::strength_score("abcdefgh12345678", &[]) <= 1);
+        assert!(super::strength_score("Tr0ub4dor&3", &[]) >= 3);
+        assert_eq!(
+            super::strength_score("correct horse battery staple", &[]),
+            4
+        );
+        assert!(
+            super::strength_score("marie.curie1867", &["marie.curie@example.com"])
+                < super::strength_score("marie.curie1867", &[])
+        );
+    }
+
+    #[test]
+    fn check() {
+        let policy = PasswordPolicy::default();
+        assert!(policy.check("correct horse battery staple", &[]).is_ok());
+
+        let rejection = policy.check("password", &[]).unwrap_err();
+        let rules: Vec<PasswordRule> = rejection.violations.iter().map(|v| v.rule).collect();
+        assert_eq!(rules, vec![PasswordRule::Breached, PasswordRule::Strength]);
+
+        let rejection = policy.check("", &[]).unwrap_err();
+        assert_eq!(rejection.violations[0].rule, PasswordRule::MinLength);
+    }
+}
@@ -0,0 +1,200 @@
+use chrono::{DateTime, Utc};
+use poem::web::Data;
+use poem_openapi::{
+    param::{Path, Query},
+    payload::Json,
+    ApiResponse, Object, OpenApi,
+};
+use sqlx::PgPool;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    cleanup::{GuestCleanup, GuestCleanupReport},
+    core::{
+        self,
+        audit::{AuditAction, AuditEvent, AuditFilter},
+        user::Role,
+    },
+    security::JWTAuthorization,
+};
+
+use super::{audit, ApiTags, ClientInfo};
+
+/// Number of audit events returned if no limit is given.
+const DEFAULT_AUDIT_PAGE_SIZE: i64 = 50;
+/// Maximum number of audit events returned at once.
+const MAX_AUDIT_PAGE_SIZE: i64 = 200;
+
+pub struct AdminAPI;
+
+#[OpenApi(prefix_path = "/api/admin")]
+impl AdminAPI {
+    /// Deletes inactive guest accounts right away instead of waiting for the next scheduled run.
+    #[oai(path = "/cleanup/guests", method = "post", tag = "ApiTags::Admin")]
+    #[tracing::instrument(skip(self, pool, cleanup))]
+    async fn cleanup_guests(
+        &self,
+        pool: Data<&PgPool>,
+        cleanup: Data<&GuestCleanup>,
+        auth: JWTAuthorization,
+    ) -> CleanupGuestsResponse {
+        if !auth.has_role(&[Role::Admin]) {
+            return CleanupGuestsResponse::Forbidden;
+        }
+        match cleanup.run(&pool).await {
+            Ok(report) => CleanupGuestsResponse::Ok(Json(report)),
+            Err(e) => {
+                error!("error {:?} while deleting inactive guests", e);
+                CleanupGuestsResponse::Internal
+            }
+        }
+    }
+
+    /// Changes the role of a user. It takes effect once their access token is refreshed.
+    #[oai(path = "/users/:id/role", method = "put", tag = "ApiTags::Admin")]
+    #[tracing::instrument(skip(self, pool, id))]
+    async fn set_role(
+        &self,
+        pool: Data<&PgPool>,
+        id: Path<Uuid>,
+        client: ClientInfo,
+        auth: JWTAuthorization,
+        req: Json<SetRoleRequest>,
+    ) -> SetRoleResponse {
+        if !auth.has_role(&[Role::Admin]) {
+            return SetRoleResponse::Forbidden;
+        }
+        let previous = match core::user::get_user(&pool, id.0).await {
+            Ok(Some(u)) => u.role,
+            Ok(None) => return SetRoleResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while retrieving user {:?}", e, id.0);
+                return SetRoleResponse::Internal;
+            }
+        };
+        match core::user::set_role(&pool, id.0, req.role).await {
+            Ok(Some(())) => {
+                let details = serde_json::json!({ "from": previous, "to": req.role });
+                audit(
+                    &pool,
+                    &client,
+                    Some(auth.0.id),
+                    AuditAction::RoleChange,
+                    Some(id.0),
+                    Some(details),
+                )
+                .await;
+                SetRoleResponse::Ok
+            }
+            Ok(None) => SetRoleResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while changing role of {:?}", e, id.0);
+                SetRoleResponse::Internal
+            }
+        }
+    }
+
+    /// Lists audit events, newest first.
+    #[oai(path = "/audit-events", method = "get", tag = "ApiTags::Admin")]
+    #[tracing::instrument(skip(self, pool, actor_id, action, target_id, from, to, limit, offset))]
+    #[allow(clippy::too_many_arguments)]
+    async fn get_audit_events(
+        &self,
+        pool: Data<&PgPool>,
+        actor_id: Query<Option<Uuid>>,
+        action: Query<Option<AuditAction>>,
+        target_id: Query<Option<Uuid>>,
+        /// Only events at or after this time
+        from: Query<Option<DateTime<Utc>>>,
+        /// Only events before this time
+        to: Query<Option<DateTime<Utc>>>,
+        #[oai(validator(minimum(value = "1"), maximum(value = "200")))] limit: Query<Option<i64>>,
+        #[oai(validator(minimum(value = "0")))] offset: Query<Option<i64>>,
+        auth: JWTAuthorization,
+    ) -> GetAuditEventsResponse {
+        if !auth.has_role(&[Role::Admin]) {
+            return GetAuditEventsResponse::Forbidden;
+        }
+        let filter = AuditFilter {
+            actor_id: actor_id.0,
+            action: action.0,
+            target_id: target_id.0,
+            from: from.0,
+            to: to.0,
+        };
+        let limit = limit
+            .0
+            .unwrap_or(DEFAULT_AUDIT_PAGE_SIZE)
+            .min(MAX_AUDIT_PAGE_SIZE);
+        let offset = offset.0.unwrap_or(0);
+        match core::audit::get_events(&pool, &filter, limit, offset).await {
+            Ok((events, total)) => GetAuditEventsResponse::Ok(Json(AuditEventPage {
+                events,
+                total,
+                limit,
+                offset,
+            })),
+            Err(e) => {
+                error!("error {:?} while retrieving audit events", e);
+                GetAuditEventsResponse::Internal
+            }
+        }
+    }
+}
+
+#[derive(ApiResponse)]
+pub enum CleanupGuestsResponse {
+    #[oai(status = 200)]
+    Ok(Json<GuestCleanupReport>),
+
+    /// Only admins can run maintenance jobs
+    #[oai(status = 403)]
+    Forbidden,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(Object, Debug)]
+pub struct SetRoleRequest {
+    role: Role,
+}
+
+#[derive(ApiResponse)]
+pub enum SetRoleResponse {
+    #[oai(status = 200)]
+    Ok,
+
+    /// Only admins can change roles
+    #[oai(status = 403)]
+    Forbidden,
+
+    #[oai(status = 404)]
+    NotFound,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(Object, Debug)]
+pub struct AuditEventPage {
+    events: Vec<AuditEvent>,
+    /// Number of all events matching the filter
+    total: i64,
+    limit: i64,
+    offset: i64,
+}
+
+#[derive(ApiResponse)]
+pub enum GetAuditEventsResponse {
+    #[oai(status = 200)]
+    Ok(Json<AuditEventPage>),
+
+    /// Only admins can read the audit log
+    #[oai(status = 403)]
+    Forbidden,
+
+    #[oai(status = 500)]
+    Internal,
+}
@@ -0,0 +1,187 @@
+use crate::{
+    core::{
+        self,
+        api_key::{ApiKey, ApiScope},
+        audit::AuditAction,
+    },
+    security::{generate_token, hash_token, JWTAuthorization, API_KEY_PREFIX},
+};
+
+use super::{audit, ApiTags, ClientInfo};
+use chrono::{Duration, Utc};
+use poem::web::Data;
+use poem_openapi::{param::Path, payload::Json, ApiResponse, Object, OpenApi};
+use sqlx::PgPool;
+use tracing::error;
+use uuid::Uuid;
+
+/// Number of characters of a key kept to tell keys apart.
+const API_KEY_VISIBLE_PREFIX: usize = 8;
+
+pub struct ApiKeyAPI;
+
+#[OpenApi(prefix_path = "/api")]
+impl ApiKeyAPI {
+    /// Creates an API key for integrations. The key is only returned once.
+    #[oai(path = "/user/self/api-keys", method = "post", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, pool))]
+    async fn create_api_key(
+        &self,
+        pool: Data<&PgPool>,
+        client: ClientInfo,
+        auth: JWTAuthorization,
+        req: Json<CreateApiKeyRequest>,
+    ) -> CreateApiKeyResponse {
+        match core::user::get_user(&pool, auth.0.id).await {
+            Ok(Some(u)) if u.is_guest => return CreateApiKeyResponse::Forbidden,
+            Ok(Some(_)) => {}
+            Ok(None) => return CreateApiKeyResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while retrieving profile {:?}", e, auth.0);
+                return CreateApiKeyResponse::Internal;
+            }
+        }
+
+        let key = format!("{}{}", API_KEY_PREFIX, generate_token());
+        let prefix = &key[..API_KEY_PREFIX.len() + API_KEY_VISIBLE_PREFIX];
+        let expires_at = req
+            .expires_in_days
+            .map(|days| Utc::now() + Duration::days(days.into()));
+        match core::api_key::create_api_key(
+            &pool,
+            auth.0.id,
+            &req.name,
+            prefix,
+            &hash_token(&key),
+            &req.scopes,
+            expires_at,
+        )
+        .await
+        {
+            Ok(api_key) => {
+                let details = serde_json::json!({ "scopes": api_key.scopes });
+                audit(
+                    &pool,
+                    &client,
+                    Some(auth.0.id),
+                    AuditAction::ApiKeyCreate,
+                    Some(api_key.id),
+                    Some(details),
+                )
+                .await;
+                CreateApiKeyResponse::Ok(Json(CreatedApiKey { key, api_key }))
+            }
+            Err(e) => {
+                error!("error {:?} while creating API key for {:?}", e, auth.0.id);
+                CreateApiKeyResponse::Internal
+            }
+        }
+    }
+
+    /// Lists the API keys of the user, including revoked and expired ones.
+    #[oai(path = "/user/self/api-keys", method = "get", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, pool))]
+    async fn get_api_keys(
+        &self,
+        pool: Data<&PgPool>,
+        auth: JWTAuthorization,
+    ) -> GetApiKeysResponse {
+        match core::api_key::get_api_keys(&pool, auth.0.id).await {
+            Ok(keys) => GetApiKeysResponse::Ok(Json(keys)),
+            Err(e) => {
+                error!("error {:?} while retrieving API keys of {:?}", e, auth.0.id);
+                GetApiKeysResponse::Internal
+            }
+        }
+    }
+
+    #[oai(
+        path = "/user/self/api-keys/:id",
+        method = "delete",
+        tag = "ApiTags::User"
+    )]
+    #[tracing::instrument(skip(self, pool, id))]
+    async fn revoke_api_key(
+        &self,
+        pool: Data<&PgPool>,
+        id: Path<Uuid>,
+        client: ClientInfo,
+        auth: JWTAuthorization,
+    ) -> RevokeApiKeyResponse {
+        match core::api_key::revoke_api_key(&pool, auth.0.id, id.0).await {
+            Ok(true) => {
+                audit(
+                    &pool,
+                    &client,
+                    Some(auth.0.id),
+                    AuditAction::ApiKeyRevoke,
+                    Some(id.0),
+                    None,
+                )
+                .await;
+                RevokeApiKeyResponse::Ok
+            }
+            Ok(false) => RevokeApiKeyResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while revoking API key {:?}", e, id.0);
+                RevokeApiKeyResponse::Internal
+            }
+        }
+    }
+}
+
+#[derive(Object, Debug)]
+pub struct CreateApiKeyRequest {
+    /// Describes what the key is used for, e.g. `Smart meter dashboard`
+    #[oai(validator(max_length = 64))]
+    name: String,
+    #[oai(validator(min_items = 1))]
+    scopes: Vec<ApiScope>,
+    /// Keys without an expiry stay valid until they are revoked
+    #[oai(validator(minimum(value = "1")))]
+    expires_in_days: Option<u32>,
+}
+
+#[derive(Object, Debug)]
+pub struct CreatedApiKey {
+    /// Send it in the `X-API-KEY` header. It cannot be retrieved again
+    key: String,
+    api_key: ApiKey,
+}
+
+#[derive(ApiResponse)]
+pub enum CreateApiKeyResponse {
+    #[oai(status = 201)]
+    Ok(Json<CreatedApiKey>),
+
+    /// Guests cannot create API keys
+    #[oai(status = 403)]
+    Forbidden,
+
+    #[oai(status = 404)]
+    NotFound,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(ApiResponse)]
+pub enum GetApiKeysResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<ApiKey>>),
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(ApiResponse)]
+pub enum RevokeApiKeyResponse {
+    #[oai(status = 200)]
+    Ok,
+
+    #[oai(status = 404)]
+    NotFound,
+
+    #[oai(status = 500)]
+    Internal,
+}
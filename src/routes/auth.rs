@@ -1,17 +1,39 @@
 use crate::{
-    core::{self, user::User},
-    security::{create_jwt, JWTAuthorization},
+    core::{
+        self,
+        audit::AuditAction,
+        session::RefreshOutcome,
+        token::TokenPurpose,
+        translation::language_preferences,
+        user::{Role, User, UserPatch},
+    },
+    entities::user::UserExport,
+    mail::Mailer,
+    password_policy::{PasswordPolicy, PasswordRejection},
+    security::{
+        create_jwt, generate_token, hash_password, hash_token, verify_password, AuditKey,
+        JWTAuthorization, JwtKeys, PasswordHashing, ACCESS_TOKEN_LIFETIME,
+        EMAIL_VERIFICATION_TOKEN_LIFETIME_HOURS, LOGIN_ATTEMPTS_PER_ACCOUNT, LOGIN_ATTEMPTS_PER_IP,
+        LOGIN_CHALLENGE_LIFETIME_MINUTES, MAGIC_LINK_LIFETIME_MINUTES,
+        PASSWORD_RESET_TOKEN_LIFETIME_MINUTES, REFRESH_TOKEN_LIFETIME_DAYS, SESSION_AUTH_KEY,
+    },
 };
 
-use super::ApiTags;
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use super::{audit, ApiTags, ClientInfo};
+use chrono::{Duration, Utc};
 use derivative::Derivative;
-use password_hash::{rand_core::OsRng, SaltString};
-use poem::web::{cookie::CookieJar, Data};
-use poem_openapi::{payload::Json, ApiResponse, Object, OpenApi};
+use poem::{
+    session::Session,
+    web::{CsrfToken, Data},
+};
+use poem_openapi::{
+    param::{Header, Query},
+    payload::Json,
+    ApiResponse, Enum, Object, OpenApi,
+};
 use sqlx::PgPool;
-use tracing::error;
-use unicode_normalization::UnicodeNormalization;
+use std::sync::OnceLock;
+use tracing::{error, warn};
 use uuid::Uuid;
 
 pub struct AuthAPI;
@@ -19,11 +41,17 @@ pub struct AuthAPI;
 #[OpenApi(prefix_path = "/api")]
 impl AuthAPI {
     #[oai(path = "/register", method = "post", tag = "ApiTags::User")]
-    #[tracing::instrument(skip(self, pool, jar))]
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(skip(self, session, pool, hashing, policy, keys, mailer))]
     async fn register(
         &self,
-        jar: &CookieJar,
+        session: &Session,
         pool: Data<&PgPool>,
+        hashing: Data<&PasswordHashing>,
+        policy: Data<&PasswordPolicy>,
+        keys: Data<&JwtKeys>,
+        mailer: Data<&Mailer>,
+        client: ClientInfo,
         req: Json<RegisterRequest>,
     ) -> RegisterResponse {
         let mut user = User {
@@ -37,12 +65,15 @@ impl AuthAPI {
             let Some(password) = req.password.as_ref() else {
                 return RegisterResponse::BadRequest;
             };
-            let Ok(hash) = hash_password(password) else {
-                return RegisterResponse::Internal;
-            };
             let Some(email) = req.email.as_ref() else {
                 return RegisterResponse::BadRequest;
             };
+            if let Err(rejection) = policy.check(password, &[email, &req.name]) {
+                return RegisterResponse::WeakPassword(Json(rejection));
+            }
+            let Ok(hash) = hash_password(&hashing, password) else {
+                return RegisterResponse::Internal;
+            };
             user.hash = Some(hash);
             user.email = Some(email.clone());
         }
@@ -54,42 +85,210 @@ impl AuthAPI {
                 return RegisterResponse::Internal;
             }
         };
-        let Ok(jwt) = create_jwt(db_user) else {
+        if let Some(email) = &user.email {
+            send_verification_mail(&pool, &mailer, db_user, email).await;
+        }
+        let details = serde_json::json!({ "is_guest": user.is_guest });
+        audit(
+            &pool,
+            &client,
+            Some(db_user),
+            AuditAction::Register,
+            Some(db_user),
+            Some(details),
+        )
+        .await;
+        let Some(started) =
+            start_session_in_mode(&pool, &keys, session, req.mode, db_user, user.role).await
+        else {
             return RegisterResponse::Internal;
         };
-        RegisterResponse::Ok(Json(jwt))
+        match started {
+            StartedSession::Tokens(tokens) => RegisterResponse::Ok(Json(tokens)),
+            StartedSession::Cookie => RegisterResponse::CookieSession,
+        }
     }
 
     #[oai(path = "/login", method = "post", tag = "ApiTags::User")]
-    #[tracing::instrument(skip(self, jar, pool))]
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(skip(self, session, pool, hashing, audit_key, keys))]
     async fn login(
         &self,
-        jar: &CookieJar,
+        session: &Session,
         pool: Data<&PgPool>,
+        hashing: Data<&PasswordHashing>,
+        audit_key: Data<&AuditKey>,
+        keys: Data<&JwtKeys>,
+        client: ClientInfo,
         req: Json<LoginRequest>,
     ) -> LoginResponse {
-        let db_user = match core::user::get_user_by_email(&pool, &req.email).await {
-            Ok(Some(u)) => u,
-            Ok(None) => {
-                return LoginResponse::Unauthorized;
+        let db_user = match check_password(
+            &pool,
+            &hashing,
+            &audit_key,
+            &client,
+            &req.email,
+            &req.password,
+            "password",
+        )
+        .await
+        {
+            Some(PasswordCheck::Valid(user)) => user,
+            Some(PasswordCheck::Invalid) => return LoginResponse::Unauthorized,
+            Some(PasswordCheck::Locked(retry_after)) => {
+                return LoginResponse::TooManyRequests(retry_after)
+            }
+            None => return LoginResponse::Internal,
+        };
+        match core::two_factor::is_enabled(&pool, db_user.id).await {
+            Ok(true) => {
+                let Some(challenge) = start_login_challenge(&pool, db_user.id, None).await else {
+                    return LoginResponse::Internal;
+                };
+                return LoginResponse::SecondFactorRequired(Json(challenge));
             }
+            Ok(false) => {}
             Err(e) => {
-                error!("database get user error: {:?}", e);
+                error!("error {:?} while checking 2FA of {:?}", e, db_user.id);
                 return LoginResponse::Internal;
             }
+        }
+        let Some(started) =
+            start_session_in_mode(&pool, &keys, session, req.mode, db_user.id, db_user.role).await
+        else {
+            return LoginResponse::Internal;
         };
+        let details = serde_json::json!({ "method": "password" });
+        audit(
+            &pool,
+            &client,
+            Some(db_user.id),
+            AuditAction::Login,
+            None,
+            Some(details),
+        )
+        .await;
+        started.into()
+    }
 
-        let Ok(jwt) = create_jwt(db_user.id) else {
+    #[oai(path = "/token/refresh", method = "post", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, pool, keys))]
+    async fn refresh_token(
+        &self,
+        pool: Data<&PgPool>,
+        keys: Data<&JwtKeys>,
+        req: Json<RefreshTokenRequest>,
+    ) -> LoginResponse {
+        let refresh_token = generate_token();
+        let expires_at = Utc::now() + Duration::days(REFRESH_TOKEN_LIFETIME_DAYS);
+        let outcome = core::session::rotate_refresh_token(
+            &pool,
+            &hash_token(&req.refresh_token),
+            &hash_token(&refresh_token),
+            expires_at,
+        )
+        .await;
+        let session = match outcome {
+            Ok(RefreshOutcome::Rotated(session)) => session,
+            Ok(RefreshOutcome::Reused) => {
+                warn!("refresh token reused, revoked its session");
+                return LoginResponse::Unauthorized;
+            }
+            Ok(RefreshOutcome::Invalid) => return LoginResponse::Unauthorized,
+            Err(e) => {
+                error!("error {:?} while refreshing token", e);
+                return LoginResponse::Internal;
+            }
+        };
+        // The role is read again, so role changes apply with the next refresh
+        let role = match core::user::get_user(&pool, session.user_id).await {
+            Ok(Some(u)) => u.role,
+            Ok(None) => return LoginResponse::Unauthorized,
+            Err(e) => {
+                error!("error {:?} while retrieving user {:?}", e, session.user_id);
+                return LoginResponse::Internal;
+            }
+        };
+        let Ok(access_token) = create_jwt(&keys, session.user_id, session.id, role) else {
             return LoginResponse::Internal;
         };
+        LoginResponse::Ok(Json(AuthTokens {
+            access_token,
+            refresh_token,
+            expires_in: ACCESS_TOKEN_LIFETIME,
+        }))
+    }
+
+    /// Revokes the current session
+    #[oai(path = "/logout", method = "post", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, session, pool))]
+    async fn logout(
+        &self,
+        session: &Session,
+        pool: Data<&PgPool>,
+        client: ClientInfo,
+        auth: JWTAuthorization,
+    ) -> LogoutResponse {
+        match core::session::revoke_session(&pool, auth.0.id, auth.0.sid).await {
+            Ok(_) => {
+                session.purge();
+                audit(
+                    &pool,
+                    &client,
+                    Some(auth.0.id),
+                    AuditAction::Logout,
+                    None,
+                    None,
+                )
+                .await;
+                LogoutResponse::Ok
+            }
+            Err(e) => {
+                error!("error {:?} while revoking session {:?}", e, auth.0.sid);
+                LogoutResponse::Internal
+            }
+        }
+    }
 
-        if let Some(db_hash) = db_user.hash {
-            if matches!(verify_password(&req.password, &db_hash), Ok(true)) {
-                return LoginResponse::Ok(Json(jwt));
+    /// Revokes every session of the current user
+    #[oai(path = "/logout/all", method = "post", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, session, pool))]
+    async fn logout_all(
+        &self,
+        session: &Session,
+        pool: Data<&PgPool>,
+        client: ClientInfo,
+        auth: JWTAuthorization,
+    ) -> LogoutResponse {
+        match core::session::revoke_user_sessions(&pool, auth.0.id).await {
+            Ok(_) => {
+                session.purge();
+                let details = serde_json::json!({ "all_sessions": true });
+                audit(
+                    &pool,
+                    &client,
+                    Some(auth.0.id),
+                    AuditAction::Logout,
+                    None,
+                    Some(details),
+                )
+                .await;
+                LogoutResponse::Ok
+            }
+            Err(e) => {
+                error!("error {:?} while revoking sessions of {:?}", e, auth.0.id);
+                LogoutResponse::Internal
             }
         }
+    }
 
-        LoginResponse::Unauthorized
+    /// Returns a token to send in the `X-CSRF-TOKEN` header of requests with the session
+    /// cookie. It is checked against a cookie set by this response.
+    #[oai(path = "/csrf-token", method = "get", tag = "ApiTags::User")]
+    async fn csrf_token(&self, token: &CsrfToken) -> Json<CsrfTokenResponse> {
+        Json(CsrfTokenResponse {
+            token: token.0.clone(),
+        })
     }
 
     #[oai(path = "/user/self", method = "get", tag = "ApiTags::User")]
@@ -104,12 +303,832 @@ impl AuthAPI {
             }
         }
     }
+
+    #[oai(path = "/user/self", method = "patch", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, pool, mailer))]
+    async fn update_user(
+        &self,
+        pool: Data<&PgPool>,
+        mailer: Data<&Mailer>,
+        auth: JWTAuthorization,
+        req: Json<UpdateUserRequest>,
+    ) -> UpdateUserResponse {
+        if let Some(timezone) = &req.timezone {
+            match core::user::is_valid_timezone(&pool, timezone).await {
+                Ok(true) => {}
+                Ok(false) => return UpdateUserResponse::BadRequest,
+                Err(e) => {
+                    error!("error {:?} while checking timezone {:?}", e, timezone);
+                    return UpdateUserResponse::Internal;
+                }
+            }
+        }
+        if let Some(timezone) = &req.timezone {
+            match core::user::keeps_local_date(&pool, auth.0.id, timezone).await {
+                Ok(Some(true)) => {}
+                Ok(Some(false)) => return UpdateUserResponse::DateChange,
+                Ok(None) => return UpdateUserResponse::NotFound,
+                Err(e) => {
+                    error!("error {:?} while checking timezone {:?}", e, timezone);
+                    return UpdateUserResponse::Internal;
+                }
+            }
+        }
+        if req.email.is_some() {
+            match core::user::get_user(&pool, auth.0.id).await {
+                Ok(Some(u)) if u.is_guest => return UpdateUserResponse::BadRequest,
+                Ok(Some(_)) => {}
+                Ok(None) => return UpdateUserResponse::NotFound,
+                Err(e) => {
+                    error!("error {:?} while retrieving profile {:?}", e, auth.0);
+                    return UpdateUserResponse::Internal;
+                }
+            }
+        }
+        let patch = UserPatch {
+            name: req.name.clone(),
+            email: req.email.clone(),
+            avatar_seed: req.avatar_seed.clone(),
+            timezone: req.timezone.clone(),
+            ..UserPatch::default()
+        };
+        match core::user::update_user(&pool, auth.0.id, &patch).await {
+            Ok(Some(u)) => {
+                if let (Some(email), None) = (&req.email, u.email_verified_at) {
+                    send_verification_mail(&pool, &mailer, u.id, email).await;
+                }
+                UpdateUserResponse::Ok(Json(u))
+            }
+            Ok(None) => UpdateUserResponse::NotFound,
+            Err(e) if core::user::is_email_taken(&e) => UpdateUserResponse::UserAlreadyExists,
+            Err(e) => {
+                error!("error {:?} while updating profile {:?}", e, auth.0);
+                UpdateUserResponse::Internal
+            }
+        }
+    }
+
+    /// Deletes the account and everything stored about it, including the quizzes the user created.
+    #[oai(path = "/user/self", method = "delete", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, pool, audit_key))]
+    async fn delete_user(
+        &self,
+        pool: Data<&PgPool>,
+        audit_key: Data<&AuditKey>,
+        auth: JWTAuthorization,
+    ) -> DeleteUserResponse {
+        let email_hash = match core::user::get_user(&pool, auth.0.id).await {
+            Ok(Some(u)) => u.email.map(|email| audit_key.hash_email(&email)),
+            Ok(None) => return DeleteUserResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while retrieving profile {:?}", e, auth.0);
+                return DeleteUserResponse::Internal;
+            }
+        };
+        match core::user::delete_user(&pool, auth.0.id).await {
+            Ok(Some(())) => {
+                // Failed logins from before the account existed only know the email's hash
+                if let Some(email_hash) = email_hash {
+                    if let Err(e) = core::audit::anonymize_email(&pool, &email_hash).await {
+                        error!("error {:?} while anonymizing failed logins", e);
+                    }
+                }
+                // Recorded without the client, nothing about the user may stay behind
+                audit(
+                    &pool,
+                    &ClientInfo::default(),
+                    Some(auth.0.id),
+                    AuditAction::AccountDeletion,
+                    Some(auth.0.id),
+                    None,
+                )
+                .await;
+                DeleteUserResponse::Ok
+            }
+            Ok(None) => DeleteUserResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while deleting {:?}", e, auth.0.id);
+                DeleteUserResponse::Internal
+            }
+        }
+    }
+
+    /// Returns everything stored about the user as a JSON document.
+    #[oai(path = "/user/self/export", method = "get", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, pool, accept_language))]
+    async fn export_user(
+        &self,
+        pool: Data<&PgPool>,
+        #[oai(name = "Accept-Language")] accept_language: Header<Option<String>>,
+        auth: JWTAuthorization,
+    ) -> ExportUserResponse {
+        let languages = language_preferences(None, accept_language.as_deref());
+        match core::user::export_user(&pool, auth.0.id, &languages).await {
+            Ok(Some(export)) => ExportUserResponse::Ok(
+                Json(Box::new(export)),
+                format!(
+                    "attachment; filename=\"letsscience-export-{}.json\"",
+                    auth.0.id
+                ),
+            ),
+            Ok(None) => ExportUserResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while exporting {:?}", e, auth.0.id);
+                ExportUserResponse::Internal
+            }
+        }
+    }
+
+    /// Changes the password and logs out every other session.
+    #[oai(path = "/user/self/password", method = "post", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, pool, hashing, policy))]
+    async fn change_password(
+        &self,
+        pool: Data<&PgPool>,
+        hashing: Data<&PasswordHashing>,
+        policy: Data<&PasswordPolicy>,
+        client: ClientInfo,
+        auth: JWTAuthorization,
+        req: Json<ChangePasswordRequest>,
+    ) -> ChangePasswordResponse {
+        let user = match core::user::get_user(&pool, auth.0.id).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return ChangePasswordResponse::Unauthorized,
+            Err(e) => {
+                error!("error {:?} while retrieving profile {:?}", e, auth.0);
+                return ChangePasswordResponse::Internal;
+            }
+        };
+        let Some(db_hash) = user.hash.as_ref() else {
+            return ChangePasswordResponse::BadRequest;
+        };
+        if !matches!(
+            verify_password(&hashing, &req.current_password, db_hash),
+            Ok(true)
+        ) {
+            return ChangePasswordResponse::Unauthorized;
+        }
+        if let Err(rejection) = policy.check(&req.new_password, &user_inputs(&user)) {
+            return ChangePasswordResponse::WeakPassword(Json(rejection));
+        }
+
+        let Ok(hash) = hash_password(&hashing, &req.new_password) else {
+            return ChangePasswordResponse::Internal;
+        };
+        let patch = UserPatch {
+            hash: Some(hash),
+            ..UserPatch::default()
+        };
+        if let Err(e) = core::user::update_user(&pool, auth.0.id, &patch).await {
+            error!("error {:?} while changing password of {:?}", e, auth.0.id);
+            return ChangePasswordResponse::Internal;
+        }
+        if let Err(e) = core::session::revoke_other_sessions(&pool, auth.0.id, auth.0.sid).await {
+            error!("error {:?} while revoking sessions of {:?}", e, auth.0.id);
+            return ChangePasswordResponse::Internal;
+        }
+        audit(
+            &pool,
+            &client,
+            Some(auth.0.id),
+            AuditAction::PasswordChange,
+            Some(auth.0.id),
+            None,
+        )
+        .await;
+        ChangePasswordResponse::Ok
+    }
+
+    /// Sends a password reset link to the given email.
+    /// Always succeeds, so it cannot be used to find out which emails are registered.
+    #[oai(path = "/password/forgot", method = "post", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, pool, mailer))]
+    async fn forgot_password(
+        &self,
+        pool: Data<&PgPool>,
+        mailer: Data<&Mailer>,
+        req: Json<ForgotPasswordRequest>,
+    ) -> ForgotPasswordResponse {
+        let user = match core::user::get_user_by_email(&pool, &req.email).await {
+            Ok(Some(u)) if !u.is_guest => u,
+            Ok(_) => return ForgotPasswordResponse::Accepted,
+            Err(e) => {
+                error!("database get user error: {:?}", e);
+                return ForgotPasswordResponse::Internal;
+            }
+        };
+        let Some(email) = user.email else {
+            return ForgotPasswordResponse::Accepted;
+        };
+
+        let token = generate_token();
+        let expires_at = Utc::now() + Duration::minutes(PASSWORD_RESET_TOKEN_LIFETIME_MINUTES);
+        if let Err(e) = core::token::create_token(
+            &pool,
+            user.id,
+            &hash_token(&token),
+            TokenPurpose::PasswordReset,
+            expires_at,
+        )
+        .await
+        {
+            error!("error {:?} while creating reset token for {:?}", e, user.id);
+            return ForgotPasswordResponse::Internal;
+        }
+        // Sent in the background, waiting for it would tell registered emails apart
+        let mailer = mailer.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mailer.send_password_reset(&email, &token).await {
+                error!("error {:?} while sending reset mail to {:?}", e, user.id);
+            }
+        });
+        ForgotPasswordResponse::Accepted
+    }
+
+    /// Sends a link to log in without a password to the given email.
+    /// Always succeeds, so it cannot be used to find out which emails are registered.
+    #[oai(path = "/login/magic", method = "post", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, pool, mailer))]
+    async fn request_magic_link(
+        &self,
+        pool: Data<&PgPool>,
+        mailer: Data<&Mailer>,
+        req: Json<MagicLinkRequest>,
+    ) -> MagicLinkResponse {
+        let user = match core::user::get_user_by_email(&pool, &req.email).await {
+            Ok(Some(u)) if !u.is_guest => u,
+            Ok(_) => return MagicLinkResponse::Accepted,
+            Err(e) => {
+                error!("database get user error: {:?}", e);
+                return MagicLinkResponse::Internal;
+            }
+        };
+        let Some(email) = user.email else {
+            return MagicLinkResponse::Accepted;
+        };
+
+        // Only the latest link works
+        if let Err(e) = core::token::revoke_tokens(&pool, user.id, TokenPurpose::MagicLink).await {
+            error!("error {:?} while revoking login links of {:?}", e, user.id);
+            return MagicLinkResponse::Internal;
+        }
+        let token = generate_token();
+        let expires_at = Utc::now() + Duration::minutes(MAGIC_LINK_LIFETIME_MINUTES);
+        if let Err(e) = core::token::create_token(
+            &pool,
+            user.id,
+            &hash_token(&token),
+            TokenPurpose::MagicLink,
+            expires_at,
+        )
+        .await
+        {
+            error!("error {:?} while creating login link for {:?}", e, user.id);
+            return MagicLinkResponse::Internal;
+        }
+        // Sent in the background, waiting for it would tell registered emails apart
+        let mailer = mailer.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mailer.send_magic_link(&email, &token).await {
+                error!("error {:?} while sending login link to {:?}", e, user.id);
+            }
+        });
+        MagicLinkResponse::Accepted
+    }
+
+    /// Logs in with the token of a login link.
+    /// Since the link was delivered by mail, this also verifies the email.
+    #[oai(path = "/login/magic/consume", method = "post", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, session, pool, keys))]
+    async fn consume_magic_link(
+        &self,
+        session: &Session,
+        pool: Data<&PgPool>,
+        keys: Data<&JwtKeys>,
+        client: ClientInfo,
+        req: Json<ConsumeMagicLinkRequest>,
+    ) -> LoginResponse {
+        let user_id = match core::token::consume_token(
+            &pool,
+            &hash_token(&req.token),
+            TokenPurpose::MagicLink,
+        )
+        .await
+        {
+            Ok(Some(id)) => id,
+            Ok(None) => return LoginResponse::Unauthorized,
+            Err(e) => {
+                error!("error {:?} while consuming login link", e);
+                return LoginResponse::Internal;
+            }
+        };
+        let user = match core::user::get_user(&pool, user_id).await {
+            Ok(Some(u)) if u.email_verified_at.is_some() => Ok(Some(u)),
+            Ok(Some(_)) => core::user::verify_email(&pool, user_id).await,
+            result => result,
+        };
+        let user = match user {
+            Ok(Some(u)) => u,
+            Ok(None) => return LoginResponse::Unauthorized,
+            Err(e) => {
+                error!("error {:?} while retrieving user {:?}", e, user_id);
+                return LoginResponse::Internal;
+            }
+        };
+
+        match core::two_factor::is_enabled(&pool, user.id).await {
+            Ok(true) => {
+                let Some(challenge) = start_login_challenge(&pool, user.id, None).await else {
+                    return LoginResponse::Internal;
+                };
+                return LoginResponse::SecondFactorRequired(Json(challenge));
+            }
+            Ok(false) => {}
+            Err(e) => {
+                error!("error {:?} while checking 2FA of {:?}", e, user.id);
+                return LoginResponse::Internal;
+            }
+        }
+        let Some(started) =
+            start_session_in_mode(&pool, &keys, session, req.mode, user.id, user.role).await
+        else {
+            return LoginResponse::Internal;
+        };
+        let details = serde_json::json!({ "method": "magic_link" });
+        audit(
+            &pool,
+            &client,
+            Some(user.id),
+            AuditAction::Login,
+            None,
+            Some(details),
+        )
+        .await;
+        started.into()
+    }
+
+    /// Sets a new password with a token from a password reset mail and logs out every session.
+    #[oai(path = "/password/reset", method = "post", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, pool, hashing, policy))]
+    async fn reset_password(
+        &self,
+        pool: Data<&PgPool>,
+        hashing: Data<&PasswordHashing>,
+        policy: Data<&PasswordPolicy>,
+        client: ClientInfo,
+        req: Json<ResetPasswordRequest>,
+    ) -> ResetPasswordResponse {
+        // Checked before the token is used up, so the user can try another password
+        let user = match core::token::peek_token(
+            &pool,
+            &hash_token(&req.token),
+            TokenPurpose::PasswordReset,
+        )
+        .await
+        {
+            Ok(Some(id)) => core::user::get_user(&pool, id).await,
+            Ok(None) => return ResetPasswordResponse::InvalidToken,
+            Err(e) => Err(e),
+        };
+        match user {
+            Ok(Some(user)) => {
+                if let Err(rejection) = policy.check(&req.password, &user_inputs(&user)) {
+                    return ResetPasswordResponse::WeakPassword(Json(rejection));
+                }
+            }
+            Ok(None) => return ResetPasswordResponse::InvalidToken,
+            Err(e) => {
+                error!("error {:?} while checking reset token", e);
+                return ResetPasswordResponse::Internal;
+            }
+        }
+
+        let user_id = match core::token::consume_token(
+            &pool,
+            &hash_token(&req.token),
+            TokenPurpose::PasswordReset,
+        )
+        .await
+        {
+            Ok(Some(id)) => id,
+            Ok(None) => return ResetPasswordResponse::InvalidToken,
+            Err(e) => {
+                error!("error {:?} while consuming reset token", e);
+                return ResetPasswordResponse::Internal;
+            }
+        };
+
+        let Ok(hash) = hash_password(&hashing, &req.password) else {
+            return ResetPasswordResponse::Internal;
+        };
+        let patch = UserPatch {
+            hash: Some(hash),
+            ..UserPatch::default()
+        };
+        if let Err(e) = core::user::update_user(&pool, user_id, &patch).await {
+            error!("error {:?} while resetting password of {:?}", e, user_id);
+            return ResetPasswordResponse::Internal;
+        }
+        if let Err(e) =
+            core::token::revoke_tokens(&pool, user_id, TokenPurpose::PasswordReset).await
+        {
+            error!("error {:?} while revoking reset tokens of {:?}", e, user_id);
+        }
+        if let Err(e) = core::session::revoke_user_sessions(&pool, user_id).await {
+            error!("error {:?} while revoking sessions of {:?}", e, user_id);
+            return ResetPasswordResponse::Internal;
+        }
+        audit(
+            &pool,
+            &client,
+            Some(user_id),
+            AuditAction::PasswordReset,
+            Some(user_id),
+            None,
+        )
+        .await;
+        ResetPasswordResponse::Ok
+    }
+
+    /// Confirms the email of a user with a token from a verification mail.
+    #[oai(path = "/verify-email", method = "get", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, pool, token))]
+    async fn verify_email(
+        &self,
+        pool: Data<&PgPool>,
+        client: ClientInfo,
+        token: Query<String>,
+    ) -> VerifyEmailResponse {
+        let user_id = match core::token::consume_token(
+            &pool,
+            &hash_token(&token),
+            TokenPurpose::EmailVerification,
+        )
+        .await
+        {
+            Ok(Some(id)) => id,
+            Ok(None) => return VerifyEmailResponse::InvalidToken,
+            Err(e) => {
+                error!("error {:?} while consuming verification token", e);
+                return VerifyEmailResponse::Internal;
+            }
+        };
+        match core::user::verify_email(&pool, user_id).await {
+            Ok(Some(_)) => {
+                audit(
+                    &pool,
+                    &client,
+                    Some(user_id),
+                    AuditAction::EmailVerification,
+                    Some(user_id),
+                    None,
+                )
+                .await;
+                VerifyEmailResponse::Ok
+            }
+            Ok(None) => VerifyEmailResponse::InvalidToken,
+            Err(e) => {
+                error!("error {:?} while verifying email of {:?}", e, user_id);
+                VerifyEmailResponse::Internal
+            }
+        }
+    }
+
+    /// Sends a new verification mail to the current user.
+    #[oai(
+        path = "/user/self/verify-email",
+        method = "post",
+        tag = "ApiTags::User"
+    )]
+    #[tracing::instrument(skip(self, pool, mailer))]
+    async fn resend_verification(
+        &self,
+        pool: Data<&PgPool>,
+        mailer: Data<&Mailer>,
+        auth: JWTAuthorization,
+    ) -> ResendVerificationResponse {
+        let user = match core::user::get_user(&pool, auth.0.id).await {
+            Ok(Some(u)) => u,
+            Ok(None) => return ResendVerificationResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while retrieving profile {:?}", e, auth.0);
+                return ResendVerificationResponse::Internal;
+            }
+        };
+        let Some(email) = user.email else {
+            return ResendVerificationResponse::BadRequest;
+        };
+        if user.email_verified_at.is_some() {
+            return ResendVerificationResponse::AlreadyVerified;
+        }
+        if !send_verification_mail(&pool, &mailer, user.id, &email).await {
+            return ResendVerificationResponse::Internal;
+        }
+        ResendVerificationResponse::Accepted
+    }
+
+    /// Turns the current guest into a registered user.
+    /// If the email belongs to an existing account and the password matches,
+    /// the guest's progress is merged into that account instead.
+    #[oai(path = "/user/self/upgrade", method = "post", tag = "ApiTags::User")]
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(skip(self, pool, hashing, audit_key, policy, keys, mailer))]
+    async fn upgrade_guest(
+        &self,
+        pool: Data<&PgPool>,
+        hashing: Data<&PasswordHashing>,
+        audit_key: Data<&AuditKey>,
+        policy: Data<&PasswordPolicy>,
+        keys: Data<&JwtKeys>,
+        mailer: Data<&Mailer>,
+        client: ClientInfo,
+        auth: JWTAuthorization,
+        req: Json<UpgradeGuestRequest>,
+    ) -> UpgradeGuestResponse {
+        let guest = match core::user::get_user(&pool, auth.0.id).await {
+            Ok(Some(u)) if u.is_guest => u,
+            Ok(Some(_)) => return UpgradeGuestResponse::NotAGuest,
+            Ok(None) => return UpgradeGuestResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while retrieving profile {:?}", e, auth.0);
+                return UpgradeGuestResponse::Internal;
+            }
+        };
+
+        let (user_id, role) = match core::user::get_user_by_email(&pool, &req.email).await {
+            Ok(Some(_)) => {
+                let existing = match check_password(
+                    &pool,
+                    &hashing,
+                    &audit_key,
+                    &client,
+                    &req.email,
+                    &req.password,
+                    "guest_merge",
+                )
+                .await
+                {
+                    Some(PasswordCheck::Valid(user)) => user,
+                    Some(PasswordCheck::Invalid) => return UpgradeGuestResponse::UserAlreadyExists,
+                    Some(PasswordCheck::Locked(retry_after)) => {
+                        return UpgradeGuestResponse::TooManyRequests(retry_after)
+                    }
+                    None => return UpgradeGuestResponse::Internal,
+                };
+                match core::two_factor::is_enabled(&pool, existing.id).await {
+                    Ok(true) => {
+                        let Some(challenge) =
+                            start_login_challenge(&pool, existing.id, Some(auth.0.id)).await
+                        else {
+                            return UpgradeGuestResponse::Internal;
+                        };
+                        return UpgradeGuestResponse::SecondFactorRequired(Json(challenge));
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!("error {:?} while checking 2FA of {:?}", e, existing.id);
+                        return UpgradeGuestResponse::Internal;
+                    }
+                }
+                if let Err(e) = core::user::merge_users(&pool, auth.0.id, existing.id).await {
+                    error!(
+                        "error {:?} while merging guest {:?} into {:?}",
+                        e, auth.0.id, existing.id
+                    );
+                    return UpgradeGuestResponse::Internal;
+                }
+                let details = serde_json::json!({ "method": "guest_merge", "guest_id": auth.0.id });
+                audit(
+                    &pool,
+                    &client,
+                    Some(existing.id),
+                    AuditAction::Login,
+                    None,
+                    Some(details),
+                )
+                .await;
+                (existing.id, existing.role)
+            }
+            Ok(None) => {
+                if let Err(rejection) = policy.check(&req.password, &[&req.email, &guest.name]) {
+                    return UpgradeGuestResponse::WeakPassword(Json(rejection));
+                }
+                let Ok(hash) = hash_password(&hashing, &req.password) else {
+                    return UpgradeGuestResponse::Internal;
+                };
+                match core::user::upgrade_guest(&pool, auth.0.id, &req.email, &hash).await {
+                    Ok(Some(u)) => {
+                        send_verification_mail(&pool, &mailer, u.id, &req.email).await;
+                        (u.id, u.role)
+                    }
+                    Ok(None) => return UpgradeGuestResponse::UserAlreadyExists,
+                    Err(e) => {
+                        error!("error {:?} while upgrading guest {:?}", e, auth.0.id);
+                        return UpgradeGuestResponse::Internal;
+                    }
+                }
+            }
+            Err(e) => {
+                error!("database get user error: {:?}", e);
+                return UpgradeGuestResponse::Internal;
+            }
+        };
+
+        let Some(tokens) = start_session(&pool, &keys, user_id, role).await else {
+            return UpgradeGuestResponse::Internal;
+        };
+        UpgradeGuestResponse::Ok(Json(tokens))
+    }
+}
+
+/// Starts a new session for the user and issues its first tokens.
+pub(super) async fn start_session(
+    pool: &PgPool,
+    keys: &JwtKeys,
+    user_id: Uuid,
+    role: Role,
+) -> Option<AuthTokens> {
+    let refresh_token = generate_token();
+    let expires_at = Utc::now() + Duration::days(REFRESH_TOKEN_LIFETIME_DAYS);
+    let session_id =
+        match core::session::create_session(pool, user_id, &hash_token(&refresh_token), expires_at)
+            .await
+        {
+            Ok(id) => id,
+            Err(e) => {
+                error!("error {:?} while creating session for {:?}", e, user_id);
+                return None;
+            }
+        };
+    let access_token = create_jwt(keys, user_id, session_id, role).ok()?;
+    Some(AuthTokens {
+        access_token,
+        refresh_token,
+        expires_in: ACCESS_TOKEN_LIFETIME,
+    })
+}
+
+/// How a login was completed, see [`SessionMode`].
+pub(super) enum StartedSession {
+    Tokens(AuthTokens),
+    Cookie,
+}
+
+impl From<StartedSession> for LoginResponse {
+    fn from(started: StartedSession) -> Self {
+        match started {
+            StartedSession::Tokens(tokens) => LoginResponse::Ok(Json(tokens)),
+            StartedSession::Cookie => LoginResponse::CookieSession,
+        }
+    }
+}
+
+/// Starts a new session for the user, either issuing tokens or setting the session cookie.
+pub(super) async fn start_session_in_mode(
+    pool: &PgPool,
+    keys: &JwtKeys,
+    session: &Session,
+    mode: SessionMode,
+    user_id: Uuid,
+    role: Role,
+) -> Option<StartedSession> {
+    if mode == SessionMode::Token {
+        return start_session(pool, keys, user_id, role)
+            .await
+            .map(StartedSession::Tokens);
+    }
+    // The refresh token is never handed out, the session ends when the cookie expires
+    let expires_at = Utc::now() + Duration::days(REFRESH_TOKEN_LIFETIME_DAYS);
+    let session_id = match core::session::create_session(
+        pool,
+        user_id,
+        &hash_token(&generate_token()),
+        expires_at,
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(e) => {
+            error!("error {:?} while creating session for {:?}", e, user_id);
+            return None;
+        }
+    };
+    // A new session id prevents fixation with a cookie set before the login
+    session.renew();
+    session.set(SESSION_AUTH_KEY, session_id);
+    Some(StartedSession::Cookie)
+}
+
+/// Issues a token to complete a login with the second factor.
+/// The guest, if any, is merged into the account once the login is completed.
+pub(super) async fn start_login_challenge(
+    pool: &PgPool,
+    user_id: Uuid,
+    guest_id: Option<Uuid>,
+) -> Option<SecondFactorChallenge> {
+    let challenge_token = generate_token();
+    let expires_in = Duration::minutes(LOGIN_CHALLENGE_LIFETIME_MINUTES);
+    if let Err(e) = core::token::create_token(
+        pool,
+        user_id,
+        &hash_token(&challenge_token),
+        TokenPurpose::LoginChallenge,
+        Utc::now() + expires_in,
+    )
+    .await
+    {
+        error!(
+            "error {:?} while creating login challenge for {:?}",
+            e, user_id
+        );
+        return None;
+    }
+    if let Some(guest_id) = guest_id {
+        if let Err(e) =
+            core::token::attach_guest(pool, &hash_token(&challenge_token), guest_id).await
+        {
+            error!(
+                "error {:?} while attaching guest {:?} to login challenge",
+                e, guest_id
+            );
+            return None;
+        }
+    }
+    Some(SecondFactorChallenge {
+        challenge_token,
+        expires_in: expires_in.num_seconds() as u64,
+    })
+}
+
+/// Sends a new verification link to the user and invalidates previous ones.
+/// Returns false if that failed, errors are logged.
+async fn send_verification_mail(
+    pool: &PgPool,
+    mailer: &Mailer,
+    user_id: Uuid,
+    email: &str,
+) -> bool {
+    if let Err(e) = core::token::revoke_tokens(pool, user_id, TokenPurpose::EmailVerification).await
+    {
+        error!(
+            "error {:?} while revoking verification tokens of {:?}",
+            e, user_id
+        );
+        return false;
+    }
+    let token = generate_token();
+    let expires_at = Utc::now() + Duration::hours(EMAIL_VERIFICATION_TOKEN_LIFETIME_HOURS);
+    if let Err(e) = core::token::create_token(
+        pool,
+        user_id,
+        &hash_token(&token),
+        TokenPurpose::EmailVerification,
+        expires_at,
+    )
+    .await
+    {
+        error!(
+            "error {:?} while creating verification token for {:?}",
+            e, user_id
+        );
+        return false;
+    }
+    if let Err(e) = mailer.send_email_verification(email, &token).await {
+        error!(
+            "error {:?} while sending verification mail to {:?}",
+            e, user_id
+        );
+        return false;
+    }
+    true
+}
+
+#[derive(Object)]
+pub struct AuthTokens {
+    /// Short-lived JWT to authenticate requests with
+    access_token: String,
+    /// Single-use token to obtain new tokens from `/api/token/refresh`
+    refresh_token: String,
+    /// Seconds until the access token expires
+    expires_in: u64,
+}
+
+#[derive(Object)]
+pub struct SecondFactorChallenge {
+    /// Single-use token to complete the login at `/api/login/totp`
+    challenge_token: String,
+    /// Seconds until the challenge token expires
+    expires_in: u64,
 }
 
 #[derive(ApiResponse)]
 pub enum RegisterResponse {
     #[oai(status = 201)]
-    Ok(Json<String>),
+    Ok(Json<AuthTokens>),
+
+    /// Registered and logged in with the session cookie
+    #[oai(status = 204)]
+    CookieSession,
 
     #[oai(status = 409)]
     UserAlreadyExists,
@@ -117,18 +1136,53 @@ pub enum RegisterResponse {
     #[oai(status = 400)]
     BadRequest,
 
+    /// The password does not follow the password policy
+    #[oai(status = 400)]
+    WeakPassword(Json<PasswordRejection>),
+
     #[oai(status = 500)]
     Internal,
 }
 
+/// How a client keeps the user logged in.
+#[derive(Enum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[oai(rename_all = "lowercase")]
+pub enum SessionMode {
+    /// Access and refresh tokens are returned, e.g. for the app
+    #[default]
+    Token,
+    /// An HttpOnly session cookie is set, e.g. for the browser. Requests other than GET then
+    /// need a token from `/api/csrf-token` in the `X-CSRF-TOKEN` header.
+    Cookie,
+}
+
+#[derive(Object, Debug)]
+pub struct CsrfTokenResponse {
+    /// Send in the `X-CSRF-TOKEN` header
+    token: String,
+}
+
 #[derive(ApiResponse)]
 pub enum LoginResponse {
     #[oai(status = 200)]
-    Ok(Json<String>),
+    Ok(Json<AuthTokens>),
+
+    /// Logged in with the session cookie
+    #[oai(status = 204)]
+    CookieSession,
+
+    /// The password was correct, but the account requires a second factor
+    #[oai(status = 202)]
+    SecondFactorRequired(Json<SecondFactorChallenge>),
 
     #[oai(status = 401)]
     Unauthorized,
 
+    /// Too many failed logins for the account or from the client,
+    /// retry after the given number of seconds
+    #[oai(status = 429)]
+    TooManyRequests(#[oai(header = "Retry-After")] i64),
+
     #[oai(status = 500)]
     Internal,
 }
@@ -139,6 +1193,24 @@ pub struct LoginRequest {
     email: String,
     #[derivative(Debug = "ignore")]
     password: String,
+    #[oai(default)]
+    mode: SessionMode,
+}
+
+#[derive(Derivative, Object)]
+#[derivative(Debug)]
+pub struct RefreshTokenRequest {
+    #[derivative(Debug = "ignore")]
+    refresh_token: String,
+}
+
+#[derive(ApiResponse)]
+pub enum LogoutResponse {
+    #[oai(status = 200)]
+    Ok,
+
+    #[oai(status = 500)]
+    Internal,
 }
 
 #[derive(Derivative, Object)]
@@ -150,6 +1222,8 @@ pub struct RegisterRequest {
     password: Option<String>,
     avatar_seed: String,
     is_guest: bool,
+    #[oai(default)]
+    mode: SessionMode,
 }
 
 #[derive(ApiResponse)]
@@ -164,30 +1238,484 @@ pub enum GetUserResponse {
     Internal,
 }
 
-fn normalize(pass: &str) -> String {
-    pass.nfkc().collect::<String>()
+#[derive(ApiResponse)]
+pub enum DeleteUserResponse {
+    #[oai(status = 200)]
+    Ok,
+
+    #[oai(status = 404)]
+    NotFound,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(ApiResponse)]
+pub enum ExportUserResponse {
+    #[oai(status = 200)]
+    Ok(
+        Json<Box<UserExport>>,
+        #[oai(header = "Content-Disposition")] String,
+    ),
+
+    #[oai(status = 404)]
+    NotFound,
+
+    #[oai(status = 500)]
+    Internal,
 }
 
-/// Creates a hash with the given password.
-#[tracing::instrument(name = "Hash password", skip_all)]
-pub fn hash_password(password: &str) -> Result<String, password_hash::Error> {
-    let password = normalize(password);
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let hash = argon2.hash_password(password.as_bytes(), &salt)?;
-    let s = hash.serialize();
-    Ok(s.as_str().to_owned())
+#[derive(Object, Debug)]
+pub struct UpdateUserRequest {
+    #[oai(validator(max_length = 64))]
+    name: Option<String>,
+    email: Option<String>,
+    avatar_seed: Option<String>,
+    /// IANA name of the timezone daily challenges reset in, e.g. `Europe/Zurich`
+    timezone: Option<String>,
 }
 
-/// Verifies that the given password results in the given hash.
-#[tracing::instrument(name = "Validate password", skip_all)]
-pub fn verify_password(password: &str, hash: &str) -> Result<bool, password_hash::Error> {
-    let pass = normalize(password);
-    let argon2 = Argon2::default();
-    let result = argon2.verify_password(pass.as_bytes(), &PasswordHash::new(hash)?);
-    match result {
-        Ok(_) => Ok(true),
-        Err(password_hash::Error::Password) => Ok(false),
-        Err(e) => Err(e),
+#[derive(ApiResponse)]
+pub enum UpdateUserResponse {
+    #[oai(status = 200)]
+    Ok(Json<User>),
+
+    /// The timezone is unknown or a guest tried to set an email. Guests have to upgrade
+    /// their account instead
+    #[oai(status = 400)]
+    BadRequest,
+
+    #[oai(status = 404)]
+    NotFound,
+
+    #[oai(status = 409)]
+    UserAlreadyExists,
+
+    /// It currently is another date in the new timezone. The timezone can only be changed
+    /// while both are on the same date, daily challenges would otherwise be repeated
+    #[oai(status = 422)]
+    DateChange,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(Derivative, Object)]
+#[derivative(Debug)]
+pub struct ChangePasswordRequest {
+    #[derivative(Debug = "ignore")]
+    current_password: String,
+    #[derivative(Debug = "ignore")]
+    new_password: String,
+}
+
+#[derive(ApiResponse)]
+pub enum ChangePasswordResponse {
+    #[oai(status = 200)]
+    Ok,
+
+    /// Guests do not have a password
+    #[oai(status = 400)]
+    BadRequest,
+
+    /// The password does not follow the password policy
+    #[oai(status = 400)]
+    WeakPassword(Json<PasswordRejection>),
+
+    #[oai(status = 401)]
+    Unauthorized,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(Object, Debug)]
+pub struct ForgotPasswordRequest {
+    email: String,
+}
+
+#[derive(ApiResponse)]
+pub enum ForgotPasswordResponse {
+    #[oai(status = 202)]
+    Accepted,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(Object, Debug)]
+pub struct MagicLinkRequest {
+    email: String,
+}
+
+#[derive(ApiResponse)]
+pub enum MagicLinkResponse {
+    #[oai(status = 202)]
+    Accepted,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(Derivative, Object)]
+#[derivative(Debug)]
+pub struct ConsumeMagicLinkRequest {
+    #[derivative(Debug = "ignore")]
+    token: String,
+    #[oai(default)]
+    mode: SessionMode,
+}
+
+#[derive(Derivative, Object)]
+#[derivative(Debug)]
+pub struct ResetPasswordRequest {
+    #[derivative(Debug = "ignore")]
+    token: String,
+    #[derivative(Debug = "ignore")]
+    password: String,
+}
+
+#[derive(ApiResponse)]
+pub enum ResetPasswordResponse {
+    #[oai(status = 200)]
+    Ok,
+
+    #[oai(status = 400)]
+    InvalidToken,
+
+    /// The password does not follow the password policy
+    #[oai(status = 400)]
+    WeakPassword(Json<PasswordRejection>),
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(ApiResponse)]
+pub enum VerifyEmailResponse {
+    #[oai(status = 200)]
+    Ok,
+
+    #[oai(status = 400)]
+    InvalidToken,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(ApiResponse)]
+pub enum ResendVerificationResponse {
+    #[oai(status = 202)]
+    Accepted,
+
+    /// Guests do not have an email
+    #[oai(status = 400)]
+    BadRequest,
+
+    #[oai(status = 404)]
+    NotFound,
+
+    #[oai(status = 409)]
+    AlreadyVerified,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(Derivative, Object)]
+#[derivative(Debug)]
+pub struct UpgradeGuestRequest {
+    email: String,
+    #[derivative(Debug = "ignore")]
+    password: String,
+}
+
+#[derive(ApiResponse)]
+pub enum UpgradeGuestResponse {
+    #[oai(status = 200)]
+    Ok(Json<AuthTokens>),
+
+    /// The password was correct, but the account requires a second factor.
+    /// The guest is merged into the account once the login is completed
+    #[oai(status = 202)]
+    SecondFactorRequired(Json<SecondFactorChallenge>),
+
+    #[oai(status = 400)]
+    NotAGuest,
+
+    /// The password does not follow the password policy
+    #[oai(status = 400)]
+    WeakPassword(Json<PasswordRejection>),
+
+    #[oai(status = 404)]
+    NotFound,
+
+    /// The email belongs to an existing account and the password is wrong
+    #[oai(status = 409)]
+    UserAlreadyExists,
+
+    /// There were too many failed attempts for the account or from this address,
+    /// retry after the given number of seconds
+    #[oai(status = 429)]
+    TooManyRequests(#[oai(header = "Retry-After")] i64),
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+/// Stores a new hash of the password made with the current parameters.
+/// Failures are only logged, the old hash keeps working.
+async fn rehash_password(pool: &PgPool, hashing: &PasswordHashing, user_id: Uuid, password: &str) {
+    let hash = match hash_password(hashing, password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("error {:?} while rehashing password of {:?}", e, user_id);
+            return;
+        }
+    };
+    let patch = UserPatch {
+        hash: Some(hash),
+        ..UserPatch::default()
+    };
+    if let Err(e) = core::user::update_user(pool, user_id, &patch).await {
+        error!("error {:?} while storing new hash of {:?}", e, user_id);
+    }
+}
+
+/// The result of checking the password of an account, see [`check_password`].
+enum PasswordCheck {
+    /// The password matches the account
+    Valid(User),
+    /// The account does not exist or the password is wrong
+    Invalid,
+    /// There were too many failures, retry after the given number of seconds
+    Locked(i64),
+}
+
+/// Checks the password of the account with the email, throttled per account and per IP.
+/// Failures are recorded with an audit event of the given method.
+/// Returns None if that failed, errors are logged.
+async fn check_password(
+    pool: &PgPool,
+    hashing: &PasswordHashing,
+    audit_key: &AuditKey,
+    client: &ClientInfo,
+    email: &str,
+    password: &str,
+    method: &str,
+) -> Option<PasswordCheck> {
+    let account_key = format!("account:{}", email.to_lowercase());
+    let ip_key = client.ip.as_ref().map(|ip| format!("ip:{}", ip));
+    let throttle_keys: Vec<String> = std::iter::once(account_key.clone())
+        .chain(ip_key.clone())
+        .collect();
+    match core::login_throttle::locked_until(pool, &throttle_keys).await {
+        Ok(Some(until)) => {
+            let retry_after = (until - Utc::now()).num_seconds().max(1);
+            return Some(PasswordCheck::Locked(retry_after));
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("error {:?} while checking login throttle", e);
+            return None;
+        }
+    }
+
+    let db_user = match core::user::get_user_by_email(pool, email).await {
+        Ok(u) => u,
+        Err(e) => {
+            error!("database get user error: {:?}", e);
+            return None;
+        }
+    };
+
+    // Unknown accounts are checked against a dummy hash, so both cases take equally long
+    let hash = db_user
+        .as_ref()
+        .and_then(|u| u.hash.as_deref())
+        .unwrap_or_else(|| dummy_hash(hashing));
+    let password_matches = matches!(verify_password(hashing, password, hash), Ok(true));
+    let target_id = db_user.as_ref().map(|u| u.id);
+    if let (Some(db_user), true) = (db_user, password_matches) {
+        if let Err(e) = core::login_throttle::clear_failures(pool, &account_key).await {
+            error!("error {:?} while clearing login failures", e);
+        }
+        if db_user
+            .hash
+            .as_deref()
+            .is_some_and(|h| hashing.needs_rehash(h))
+        {
+            rehash_password(pool, hashing, db_user.id, password).await;
+        }
+        return Some(PasswordCheck::Valid(db_user));
+    }
+
+    let failures = [
+        Some((account_key, LOGIN_ATTEMPTS_PER_ACCOUNT)),
+        ip_key.map(|key| (key, LOGIN_ATTEMPTS_PER_IP)),
+    ];
+    for (key, free_attempts) in failures.into_iter().flatten() {
+        if let Err(e) = core::login_throttle::record_failure(pool, &key, free_attempts).await {
+            error!("error {:?} while recording login failure", e);
+        }
+    }
+    // The email itself is not stored, events would otherwise keep it after the account is deleted
+    let details = match target_id {
+        Some(_) => serde_json::json!({ "method": method }),
+        None => serde_json::json!({ "method": method, "email_hash": audit_key.hash_email(email) }),
+    };
+    audit(
+        pool,
+        client,
+        None,
+        AuditAction::LoginFailed,
+        target_id,
+        Some(details),
+    )
+    .await;
+    Some(PasswordCheck::Invalid)
+}
+
+/// A hash of a random password, used to verify logins of unknown accounts.
+fn dummy_hash(hashing: &PasswordHashing) -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| {
+        hash_password(hashing, &generate_token()).expect("Unable to hash dummy password")
+    })
+}
+
+/// Values of the user that should not make up the password.
+fn user_inputs(user: &User) -> Vec<&str> {
+    let mut inputs = vec![user.name.as_str()];
+    if let Some(email) = &user.email {
+        inputs.push(email);
+    }
+    inputs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use jsonwebtoken::Algorithm;
+    use poem::{
+        http::{HeaderMap, HeaderValue},
+        web::Data,
+    };
+    use poem_openapi::payload::Json;
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::{AuthAPI, PasswordCheck, UpgradeGuestRequest, UpgradeGuestResponse};
+    use crate::{
+        core::{self, user::User},
+        mail::{LogTransport, Mailer},
+        password_policy::PasswordPolicy,
+        routes::{ClientInfo, TrustedProxies},
+        security::{
+            hash_password, hash_token, AuditKey, AuthUser, JWTAuthorization, JwtKey, JwtKeys,
+            PasswordHashing, LOGIN_ATTEMPTS_PER_IP,
+        },
+    };
+
+    #[sqlx::test]
+    async fn merge_guest_requires_second_factor(pool: PgPool) -> sqlx::Result<()> {
+        let hashing = PasswordHashing::new(8, 1, 1, None).unwrap();
+        let owner = User {
+            email: Some("owner@example.com".to_owned()),
+            hash: Some(hash_password(&hashing, "correct horse").unwrap()),
+            is_guest: false,
+            ..User::default()
+        };
+        let owner_id = core::user::insert_user(&pool, &owner).await?.unwrap();
+        core::two_factor::start_enrolment(&pool, owner_id, "secret").await?;
+        core::two_factor::confirm(&pool, owner_id, &[]).await?;
+        let guest_id = core::user::insert_user(&pool, &User::default())
+            .await?
+            .unwrap();
+
+        let keys = JwtKeys::new(
+            vec![JwtKey::from_secret("test", Algorithm::HS256, b"secret")],
+            "test",
+        )
+        .unwrap();
+        let mailer = Mailer::new(
+            Arc::new(LogTransport::new(None)),
+            "noreply@example.com",
+            "http://localhost",
+        );
+        let client = ClientInfo {
+            ip: None,
+            user_agent: None,
+        };
+        let auth = JWTAuthorization(AuthUser::new(guest_id, Uuid::new_v4(), Default::default()));
+        let req = UpgradeGuestRequest {
+            email: "owner@example.com".to_owned(),
+            password: "correct horse".to_owned(),
+        };
+        let response = AuthAPI
+            .upgrade_guest(
+                Data(&pool),
+                Data(&hashing),
+                Data(&AuditKey::new("secret")),
+                Data(&PasswordPolicy::default()),
+                Data(&keys),
+                Data(&mailer),
+                client,
+                auth,
+                Json(req),
+            )
+            .await;
+
+        let UpgradeGuestResponse::SecondFactorRequired(Json(challenge)) = response else {
+            panic!("no second factor was required");
+        };
+        let sessions = sqlx::query_scalar!(
+            r#"select count(*) as "count!" from "auth_session" where user_id = $1"#,
+            owner_id
+        )
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(sessions, 0);
+        assert!(core::user::get_user(&pool, guest_id).await?.is_some());
+        let challenge_hash = hash_token(&challenge.challenge_token);
+        let guest = core::token::get_guest(&pool, &challenge_hash).await?;
+        assert_eq!(guest, Some(guest_id));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn forged_forwarded_header_is_throttled(pool: PgPool) -> sqlx::Result<()> {
+        let hashing = PasswordHashing::new(8, 1, 1, None).unwrap();
+        let key = AuditKey::new("secret");
+        let peer = "203.0.113.7".parse().ok();
+        // The free attempts and the one that causes the lockout fail normally
+        for attempt in 0..=LOGIN_ATTEMPTS_PER_IP + 1 {
+            // A new forged address and account every time, only the connection stays the same
+            let mut headers = HeaderMap::new();
+            let forged = format!("198.51.100.{}", attempt % 250);
+            headers.insert("x-forwarded-for", HeaderValue::from_str(&forged).unwrap());
+            let client = ClientInfo::new(&TrustedProxies::default(), peer, &headers);
+            let email = format!("user{}@example.com", attempt);
+            let check =
+                super::check_password(&pool, &hashing, &key, &client, &email, "wrong", "password")
+                    .await
+                    .unwrap();
+            if attempt <= LOGIN_ATTEMPTS_PER_IP {
+                assert!(matches!(check, PasswordCheck::Invalid));
+            } else {
+                assert!(matches!(check, PasswordCheck::Locked(_)));
+            }
+        }
+        let emails = sqlx::query_scalar!(
+            r#"
+                select count(*) as "count!" from "audit_event"
+                where details ->> 'email' is not null or details ->> 'email_hash' is null
+            "#
+        )
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(emails, 0);
+        Ok(())
     }
 }
@@ -1,7 +1,9 @@
-use poem::web::Data;
+use chrono::{DateTime, Utc};
+use poem::web::{self, Data};
 use poem_openapi::{
-    param::{Path, Query},
+    param::{Header, Path, Query},
     payload::Json,
+    types::ToJSON,
     ApiResponse, Object, OpenApi,
 };
 use sqlx::PgPool;
@@ -9,32 +11,86 @@ use tracing::error;
 use uuid::Uuid;
 
 use crate::{
-    core,
-    entities::challenge::{Challenge, UserChallenge},
-    security::JWTAuthorization,
+    core::{
+        self,
+        audit::AuditAction,
+        challenge::{DeleteChallengeOutcome, ProgressEventFilter, ProgressOutcome},
+        idempotency::StoredResponse,
+        translation::is_valid_language_code,
+    },
+    entities::challenge::{
+        Challenge, ChallengeDay, ChallengeTranslation, ProgressEvent, ProgressRejection,
+        ProgressResult, ProgressSource, UserChallenge,
+    },
+    security::{JWTAuthorization, STAFF_ROLES},
 };
 
-use super::ApiTags;
+use super::{
+    audit, idempotent, replay_json, store_json, ApiTags, ClientInfo, IdempotentResponse,
+    LocaleQuery,
+};
+
+/// Number of days returned by the history of a daily challenge if no number is given.
+const DEFAULT_HISTORY_DAYS: i64 = 30;
+/// Number of progress events returned by the timeline if no limit is given.
+const DEFAULT_TIMELINE_SIZE: i64 = 500;
 
 pub struct ChallengeAPI;
 
 #[OpenApi]
 impl ChallengeAPI {
     #[oai(path = "/api/challenge", method = "post", tag = "ApiTags::Challenge")]
-    #[tracing::instrument(skip(self, pool, auth))]
+    #[tracing::instrument(skip(self, pool, auth, idempotency_key))]
     async fn create_challenge(
         &self,
         pool: Data<&PgPool>,
         req: Json<Challenge>,
+        locale_query: web::Query<LocaleQuery>,
+        client: ClientInfo,
         auth: JWTAuthorization,
+        #[oai(name = "Idempotency-Key", validator(max_length = 255))] idempotency_key: Header<
+            Option<String>,
+        >,
     ) -> CreateChallengeResponse {
-        match core::challenge::insert_challenge(&pool, &req.0).await {
-            Ok(id) => CreateChallengeResponse::Ok(Json(id)),
-            Err(e) => {
-                error!("error while inserting challenge: {:?}", e);
-                CreateChallengeResponse::Internal
-            }
+        if !auth.has_role(STAFF_ROLES) {
+            return CreateChallengeResponse::Forbidden;
         }
+        let Ok(language_code) = locale_query.content_language() else {
+            return CreateChallengeResponse::BadRequest;
+        };
+        let request = format!(
+            "POST /api/challenge?lang_code={:?} {}",
+            language_code,
+            req.0.to_json_string()
+        );
+        idempotent(
+            &pool,
+            auth.0.id,
+            idempotency_key.as_deref(),
+            request,
+            async {
+                match core::challenge::insert_challenge(&pool, &req.0, language_code.clone()).await
+                {
+                    Ok(id) => {
+                        audit(
+                            &pool,
+                            &client,
+                            Some(auth.0.id),
+                            AuditAction::ChallengeCreate,
+                            Some(id),
+                            None,
+                        )
+                        .await;
+                        CreateChallengeResponse::Ok(Json(id))
+                    }
+                    Err(e) => {
+                        error!("error while inserting challenge: {:?}", e);
+                        CreateChallengeResponse::Internal
+                    }
+                }
+            },
+        )
+        .await
     }
 
     #[oai(
@@ -42,14 +98,17 @@ impl ChallengeAPI {
         method = "get",
         tag = "ApiTags::Challenge"
     )]
-    #[tracing::instrument(skip(self, pool, id, auth))]
+    #[tracing::instrument(skip(self, pool, id, accept_language, _auth))]
     async fn get_challenge(
         &self,
         pool: Data<&PgPool>,
         id: Path<Uuid>,
-        auth: JWTAuthorization,
+        locale_query: web::Query<LocaleQuery>,
+        #[oai(name = "Accept-Language")] accept_language: Header<Option<String>>,
+        _auth: JWTAuthorization,
     ) -> GetChallengeResponse {
-        match core::challenge::get_challenge(&pool, id.0).await {
+        let languages = locale_query.languages(accept_language.as_deref());
+        match core::challenge::get_challenge(&pool, id.0, &languages).await {
             Ok(Some(ch)) => GetChallengeResponse::Ok(Json(ch)),
             Ok(None) => GetChallengeResponse::NotFound,
             Err(e) => {
@@ -60,31 +119,136 @@ impl ChallengeAPI {
     }
 
     #[oai(
-        path = "/api/challenge/:id/progress",
-        method = "post",
+        path = "/api/challenge/:id",
+        method = "delete",
         tag = "ApiTags::Challenge"
     )]
     #[tracing::instrument(skip(self, pool, id, auth))]
-    async fn add_progress(
+    async fn delete_challenge(
         &self,
         pool: Data<&PgPool>,
         id: Path<Uuid>,
+        client: ClientInfo,
         auth: JWTAuthorization,
-        req: Json<AddProgressRequest>,
-    ) -> AddProgressResponse {
-        match core::challenge::add_progress(&pool, auth.0.id, id.0, req.progress).await {
-            Ok(Some(ch)) => AddProgressResponse::Ok(Json(ch)),
-            Ok(None) => AddProgressResponse::NotFound,
+    ) -> DeleteChallengeResponse {
+        if !auth.has_role(STAFF_ROLES) {
+            return DeleteChallengeResponse::Forbidden;
+        }
+        match core::challenge::delete_challenge(&pool, id.0).await {
+            Ok(DeleteChallengeOutcome::Deleted) => {
+                audit(
+                    &pool,
+                    &client,
+                    Some(auth.0.id),
+                    AuditAction::ChallengeDelete,
+                    Some(id.0),
+                    None,
+                )
+                .await;
+                DeleteChallengeResponse::Ok
+            }
+            Ok(DeleteChallengeOutcome::NotFound) => DeleteChallengeResponse::NotFound,
+            Ok(DeleteChallengeOutcome::HasProgress) => DeleteChallengeResponse::HasProgress,
+            Err(e) => {
+                error!("error {:?} while deleting challenge {:?}", e, id.0);
+                DeleteChallengeResponse::Internal
+            }
+        }
+    }
+
+    #[oai(
+        path = "/api/challenge/:id/translations/:lang_code",
+        method = "put",
+        tag = "ApiTags::Challenge"
+    )]
+    #[tracing::instrument(skip(self, pool, id, lang_code, auth))]
+    async fn translate_challenge(
+        &self,
+        pool: Data<&PgPool>,
+        id: Path<Uuid>,
+        lang_code: Path<String>,
+        req: Json<ChallengeTranslation>,
+        auth: JWTAuthorization,
+    ) -> TranslateChallengeResponse {
+        if !auth.has_role(STAFF_ROLES) {
+            return TranslateChallengeResponse::Forbidden;
+        }
+        if !is_valid_language_code(&lang_code) {
+            return TranslateChallengeResponse::BadRequest;
+        }
+        match core::challenge::translate_challenge(&pool, id.0, &lang_code, &req.0).await {
+            Ok(Some(())) => TranslateChallengeResponse::Ok,
+            Ok(None) => TranslateChallengeResponse::NotFound,
             Err(e) => {
                 error!(
-                    "error {:?} while adding progress {:?} to challenge {:?}",
-                    e, req.progress, id.0
+                    "error {:?} while translating challenge {:?} to {:?}",
+                    e, id.0, lang_code.0
                 );
-                AddProgressResponse::Internal
+                TranslateChallengeResponse::Internal
             }
         }
     }
 
+    #[oai(
+        path = "/api/challenge/:id/progress",
+        method = "post",
+        tag = "ApiTags::Challenge"
+    )]
+    #[tracing::instrument(skip(self, pool, id, auth, idempotency_key))]
+    async fn add_progress(
+        &self,
+        pool: Data<&PgPool>,
+        id: Path<Uuid>,
+        auth: JWTAuthorization,
+        #[oai(name = "Idempotency-Key", validator(max_length = 255))] idempotency_key: Header<
+            Option<String>,
+        >,
+        req: Json<AddProgressRequest>,
+    ) -> AddProgressResponse {
+        let source = if auth.0.scopes.is_some() {
+            ProgressSource::ApiKey
+        } else {
+            ProgressSource::App
+        };
+        let request = format!(
+            "POST /api/challenge/{}/progress {}",
+            id.0,
+            req.0.to_json_string()
+        );
+        idempotent(
+            &pool,
+            auth.0.id,
+            idempotency_key.as_deref(),
+            request,
+            async {
+                match core::challenge::add_progress(
+                    &pool,
+                    auth.0.id,
+                    id.0,
+                    req.progress,
+                    source,
+                    req.note.as_deref(),
+                )
+                .await
+                {
+                    Ok(ProgressOutcome::Added(ch)) => AddProgressResponse::Ok(Json(ch)),
+                    Ok(ProgressOutcome::NotFound) => AddProgressResponse::NotFound,
+                    Ok(ProgressOutcome::Rejected(rejection)) => {
+                        AddProgressResponse::InvalidProgress(Json(rejection))
+                    }
+                    Err(e) => {
+                        error!(
+                            "error {:?} while adding progress {:?} to challenge {:?}",
+                            e, req.progress, id.0
+                        );
+                        AddProgressResponse::Internal
+                    }
+                }
+            },
+        )
+        .await
+    }
+
     #[oai(
         path = "/api/challenges/self",
         method = "get",
@@ -108,9 +272,81 @@ impl ChallengeAPI {
         }
     }
 
+    /// Progress per day in a daily challenge, newest first
+    #[oai(
+        path = "/api/challenge/:id/history",
+        method = "get",
+        tag = "ApiTags::Challenge"
+    )]
+    #[tracing::instrument(skip(self, pool, id, days, auth))]
+    async fn get_challenge_history(
+        &self,
+        pool: Data<&PgPool>,
+        id: Path<Uuid>,
+        /// Number of days with progress to return
+        #[oai(validator(minimum(value = "1"), maximum(value = "366")))]
+        days: Query<Option<i64>>,
+        auth: JWTAuthorization,
+    ) -> GetChallengeHistoryResponse {
+        let days = days.0.unwrap_or(DEFAULT_HISTORY_DAYS);
+        match core::challenge::get_challenge_days(&pool, auth.0.id, Some(id.0), Some(days)).await {
+            Ok(days) => GetChallengeHistoryResponse::Ok(Json(days)),
+            Err(e) => {
+                error!(
+                    "error {:?} while retrieving history of challenge {:?}",
+                    e, id.0
+                );
+                GetChallengeHistoryResponse::Internal
+            }
+        }
+    }
+
+    /// Every change of the user's progress with the running total, oldest first, e.g. for charts.
+    /// Returns the newest events if there are more than `limit`.
+    #[oai(
+        path = "/api/challenges/self/timeline",
+        method = "get",
+        tag = "ApiTags::Challenge"
+    )]
+    #[tracing::instrument(skip(self, pool, challenge_id, from, to, limit, auth))]
+    async fn get_progress_timeline(
+        &self,
+        pool: Data<&PgPool>,
+        challenge_id: Query<Option<Uuid>>,
+        /// Only events at or after this time
+        from: Query<Option<DateTime<Utc>>>,
+        /// Only events before this time
+        to: Query<Option<DateTime<Utc>>>,
+        #[oai(validator(minimum(value = "1"), maximum(value = "5000")))] limit: Query<Option<i64>>,
+        auth: JWTAuthorization,
+    ) -> GetProgressTimelineResponse {
+        let filter = ProgressEventFilter {
+            challenge_id: challenge_id.0,
+            from: from.0,
+            to: to.0,
+        };
+        let limit = limit.0.unwrap_or(DEFAULT_TIMELINE_SIZE);
+        match core::challenge::get_progress_events(&pool, auth.0.id, &filter, Some(limit)).await {
+            Ok(events) => GetProgressTimelineResponse::Ok(Json(events)),
+            Err(e) => {
+                error!(
+                    "error {:?} while retrieving progress timeline of {:?}",
+                    e, auth.0.id
+                );
+                GetProgressTimelineResponse::Internal
+            }
+        }
+    }
+
     #[oai(path = "/api/challenges", method = "get", tag = "ApiTags::Challenge")]
-    async fn get_challenges(&self, pool: Data<&PgPool>) -> GetChallengesResponse {
-        match core::challenge::get_challenges(&pool).await {
+    async fn get_challenges(
+        &self,
+        pool: Data<&PgPool>,
+        locale_query: web::Query<LocaleQuery>,
+        #[oai(name = "Accept-Language")] accept_language: Header<Option<String>>,
+    ) -> GetChallengesResponse {
+        let languages = locale_query.languages(accept_language.as_deref());
+        match core::challenge::get_challenges(&pool, &languages).await {
             Ok(resp) => GetChallengesResponse::Ok(Json(resp)),
             Err(e) => {
                 error!("error {:?} while retrieving challenges", e);
@@ -150,6 +386,69 @@ pub enum CreateChallengeResponse {
     #[oai(status = 201)]
     Ok(Json<Uuid>),
 
+    #[oai(status = 400)]
+    BadRequest,
+
+    /// Only admins and teachers can create challenges
+    #[oai(status = 403)]
+    Forbidden,
+
+    /// A challenge with the same Idempotency-Key is still being created
+    #[oai(status = 409)]
+    IdempotencyKeyInUse,
+
+    /// The Idempotency-Key was already used for a different challenge
+    #[oai(status = 422)]
+    IdempotencyKeyReused,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+impl IdempotentResponse for CreateChallengeResponse {
+    fn store(&self) -> Option<StoredResponse> {
+        match self {
+            Self::Ok(id) => Some(store_json(201, &id.0)),
+            _ => None,
+        }
+    }
+
+    fn replay(stored: StoredResponse) -> Option<Self> {
+        match stored.status {
+            201 => replay_json(&stored).map(|id| Self::Ok(Json(id))),
+            _ => None,
+        }
+    }
+
+    fn key_reused() -> Self {
+        Self::IdempotencyKeyReused
+    }
+
+    fn key_in_use() -> Self {
+        Self::IdempotencyKeyInUse
+    }
+
+    fn internal() -> Self {
+        Self::Internal
+    }
+}
+
+#[derive(ApiResponse, Debug)]
+pub enum DeleteChallengeResponse {
+    #[oai(status = 200)]
+    Ok,
+
+    /// Only admins and teachers can delete challenges
+    #[oai(status = 403)]
+    Forbidden,
+
+    #[oai(status = 404)]
+    NotFound,
+
+    /// Progress was recorded for the challenge, it cannot be deleted anymore
+    #[oai(status = 409)]
+    HasProgress,
+
     #[oai(status = 500)]
     Internal,
 }
@@ -166,21 +465,91 @@ pub enum GetChallengeResponse {
     NotFound,
 }
 
+#[derive(ApiResponse, Debug)]
+pub enum TranslateChallengeResponse {
+    #[oai(status = 200)]
+    Ok,
+
+    #[oai(status = 400)]
+    BadRequest,
+
+    #[oai(status = 403)]
+    Forbidden,
+
+    #[oai(status = 404)]
+    NotFound,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
 #[derive(Object, Debug)]
 pub struct AddProgressRequest {
     progress: i32,
+    /// What the progress was made with, shown in the timeline
+    #[oai(validator(max_length = 280))]
+    note: Option<String>,
 }
 
 #[derive(ApiResponse, Debug)]
 pub enum AddProgressResponse {
+    /// The new progress, `just_completed` tells whether the goal was just reached
     #[oai(status = 200)]
-    Ok(Json<UserChallenge>),
+    Ok(Json<ProgressResult>),
+
+    /// The progress is zero, negative or exceeds a limit of the challenge
+    #[oai(status = 400)]
+    InvalidProgress(Json<ProgressRejection>),
 
     #[oai(status = 500)]
     Internal,
 
+    /// The challenge does not exist
     #[oai(status = 404)]
     NotFound,
+
+    /// A request with the same Idempotency-Key is still being processed
+    #[oai(status = 409)]
+    IdempotencyKeyInUse,
+
+    /// The Idempotency-Key was already used for a different request
+    #[oai(status = 422)]
+    IdempotencyKeyReused,
+}
+
+impl IdempotentResponse for AddProgressResponse {
+    fn store(&self) -> Option<StoredResponse> {
+        match self {
+            Self::Ok(result) => Some(store_json(200, &result.0)),
+            Self::InvalidProgress(rejection) => Some(store_json(400, &rejection.0)),
+            Self::NotFound => Some(StoredResponse {
+                status: 404,
+                body: None,
+            }),
+            _ => None,
+        }
+    }
+
+    fn replay(stored: StoredResponse) -> Option<Self> {
+        match stored.status {
+            200 => replay_json(&stored).map(|result| Self::Ok(Json(result))),
+            400 => replay_json(&stored).map(|rejection| Self::InvalidProgress(Json(rejection))),
+            404 => Some(Self::NotFound),
+            _ => None,
+        }
+    }
+
+    fn key_reused() -> Self {
+        Self::IdempotencyKeyReused
+    }
+
+    fn key_in_use() -> Self {
+        Self::IdempotencyKeyInUse
+    }
+
+    fn internal() -> Self {
+        Self::Internal
+    }
 }
 
 #[derive(ApiResponse)]
@@ -192,6 +561,24 @@ pub enum GetUserChallengesResponse {
     Internal,
 }
 
+#[derive(ApiResponse)]
+pub enum GetChallengeHistoryResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<ChallengeDay>>),
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(ApiResponse)]
+pub enum GetProgressTimelineResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<ProgressEvent>>),
+
+    #[oai(status = 500)]
+    Internal,
+}
+
 #[derive(ApiResponse)]
 pub enum GetChallengesResponse {
     #[oai(status = 200)]
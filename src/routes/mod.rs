@@ -1,20 +1,252 @@
-use poem::{endpoint::StaticFilesEndpoint, Route};
-use poem_openapi::{OpenApiService, Tags};
+use std::{
+    future::Future,
+    net::{AddrParseError, IpAddr},
+};
 
+use poem::{
+    endpoint::StaticFilesEndpoint,
+    http::{header, HeaderMap},
+    FromRequest, Request, RequestBody, Route,
+};
+use poem_openapi::{
+    types::{ParseFromJSON, ToJSON},
+    OpenApiService, Tags,
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::core::{
+    self,
+    audit::{AuditAction, NewAuditEvent},
+    idempotency::{ClaimOutcome, StoredResponse},
+    translation::{is_valid_language_code, language_preferences},
+};
+
+pub mod admin;
+pub mod api_key;
 pub mod auth;
 pub mod challenge;
+pub mod oidc;
 pub mod quiz;
+pub mod two_factor;
 
 #[derive(Tags)]
 enum ApiTags {
     User,
     Quiz,
     Challenge,
+    Admin,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LocaleQuery {
+    lang_code: Option<String>,
+}
+
+impl LocaleQuery {
+    /// The languages to look translations up with, best match first.
+    fn languages(&self, accept_language: Option<&str>) -> Vec<String> {
+        language_preferences(self.lang_code.as_deref(), accept_language)
+    }
+
+    /// The language new content is written in, in lowercase.
+    /// Returns Err(()) if an invalid language code was requested.
+    fn content_language(&self) -> Result<Option<String>, ()> {
+        match &self.lang_code {
+            Some(code) if !is_valid_language_code(code) => Err(()),
+            code => Ok(code.as_deref().map(str::to_lowercase)),
+        }
+    }
+}
+
+/// Addresses of the reverse proxies in front of the server.
+/// Only their `X-Forwarded-For` and `X-Real-IP` headers are trusted, anyone else could send
+/// a different address with every request.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+impl TrustedProxies {
+    /// Reads the comma separated `TRUSTED_PROXIES`, e.g. `10.0.0.2`.
+    /// Without it the address of the connection is used.
+    pub fn from_env() -> Result<Self, AddrParseError> {
+        let proxies = std::env::var("TRUSTED_PROXIES").unwrap_or_default();
+        let proxies = proxies
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Self(proxies))
+    }
+
+    /// The address of the client that connected through the peer.
+    /// Forwarded addresses are read from the right, the first one not added by a trusted
+    /// proxy is the client.
+    pub fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer = peer?;
+        if !self.0.contains(&peer) {
+            return Some(peer);
+        }
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        if let Some(forwarded) = header("x-forwarded-for") {
+            let mut client = peer;
+            for address in forwarded.rsplit(',') {
+                let Ok(address) = address.trim().parse() else {
+                    break;
+                };
+                client = address;
+                if !self.0.contains(&client) {
+                    break;
+                }
+            }
+            return Some(client);
+        }
+        let real_ip = header("x-real-ip").and_then(|value| value.trim().parse().ok());
+        Some(real_ip.unwrap_or(peer))
+    }
+}
+
+/// Where a request came from, recorded with audit events and used to throttle logins.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn new(proxies: &TrustedProxies, peer: Option<IpAddr>, headers: &HeaderMap) -> Self {
+        Self {
+            ip: proxies.client_ip(peer, headers).map(|ip| ip.to_string()),
+            user_agent: headers
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(ToOwned::to_owned),
+        }
+    }
+}
+
+#[poem::async_trait]
+impl<'a> FromRequest<'a> for ClientInfo {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> poem::Result<Self> {
+        let peer = req.remote_addr().as_socket_addr().map(|addr| addr.ip());
+        let proxies = req.data::<TrustedProxies>().cloned().unwrap_or_default();
+        Ok(Self::new(&proxies, peer, req.headers()))
+    }
+}
+
+/// Records an audit event. Failures are only logged, they never fail the request.
+async fn audit(
+    pool: &PgPool,
+    client: &ClientInfo,
+    actor_id: Option<Uuid>,
+    action: AuditAction,
+    target_id: Option<Uuid>,
+    details: Option<serde_json::Value>,
+) {
+    let event = NewAuditEvent {
+        actor_id,
+        action,
+        target_id,
+        ip: client.ip.clone(),
+        user_agent: client.user_agent.clone(),
+        details,
+    };
+    if let Err(e) = core::audit::record(pool, &event).await {
+        error!("error {:?} while recording audit event {:?}", e, event);
+    }
+}
+
+/// A response that is stored for an `Idempotency-Key` and replayed for retries.
+trait IdempotentResponse: Sized {
+    /// The response to store. `None` if the request may be retried, e.g. after internal errors.
+    fn store(&self) -> Option<StoredResponse>;
+    /// Restores a stored response. `None` if the stored response is invalid.
+    fn replay(stored: StoredResponse) -> Option<Self>;
+    /// The key was already used for a different request.
+    fn key_reused() -> Self;
+    /// A request with the same key is still being processed.
+    fn key_in_use() -> Self;
+    fn internal() -> Self;
+}
+
+/// Stores a response with a JSON body.
+fn store_json<T: ToJSON>(status: i16, body: &T) -> StoredResponse {
+    StoredResponse {
+        status,
+        body: body.to_json().map(|value| value.to_string()),
+    }
+}
+
+/// Parses the JSON body of a stored response.
+fn replay_json<T: ParseFromJSON>(stored: &StoredResponse) -> Option<T> {
+    let value = match &stored.body {
+        Some(body) => Some(serde_json::from_str(body).ok()?),
+        None => None,
+    };
+    T::parse_from_json(value).ok()
+}
+
+/// Handles a mutating request at most once per `Idempotency-Key` of the user.
+/// Retries of the same `request`, e.g. the endpoint and its JSON body, get the stored
+/// response within the window. Requests without a key are always handled.
+async fn idempotent<R, F>(
+    pool: &PgPool,
+    user_id: Uuid,
+    key: Option<&str>,
+    request: String,
+    handler: F,
+) -> R
+where
+    R: IdempotentResponse,
+    F: Future<Output = R>,
+{
+    let Some(key) = key else {
+        return handler.await;
+    };
+    let request_hash = core::idempotency::request_hash(&request);
+    match core::idempotency::claim(pool, user_id, key, &request_hash).await {
+        Ok(ClaimOutcome::Claimed) => {}
+        Ok(ClaimOutcome::Completed(stored)) => {
+            return R::replay(stored.clone()).unwrap_or_else(|| {
+                error!("invalid stored response {:?} for key {:?}", stored, key);
+                R::internal()
+            })
+        }
+        Ok(ClaimOutcome::InProgress) => return R::key_in_use(),
+        Ok(ClaimOutcome::Mismatch) => return R::key_reused(),
+        Err(e) => {
+            error!("error {:?} while claiming idempotency key {:?}", e, key);
+            return R::internal();
+        }
+    }
+
+    let response = handler.await;
+    let result = match response.store() {
+        Some(stored) => core::idempotency::complete(pool, user_id, key, &stored).await,
+        None => core::idempotency::release(pool, user_id, key).await,
+    };
+    if let Err(e) = result {
+        error!(
+            "error {:?} while storing the response for idempotency key {:?}",
+            e, key
+        );
+    }
+    response
 }
 
 pub fn routes() -> Route {
     let openapi_service = OpenApiService::new(
-        (auth::AuthAPI, quiz::QuizAPI, challenge::ChallengeAPI),
+        (
+            auth::AuthAPI,
+            two_factor::TwoFactorAPI,
+            api_key::ApiKeyAPI,
+            oidc::OidcAPI,
+            quiz::QuizAPI,
+            challenge::ChallengeAPI,
+            admin::AdminAPI,
+        ),
         "Let's Science API",
         "0.1",
     )
@@ -26,3 +258,39 @@ pub fn routes() -> Route {
         .nest("/docs", docs)
         .nest("/", files)
 }
+
+#[cfg(test)]
+mod tests {
+    use poem::http::{HeaderMap, HeaderValue};
+
+    use super::TrustedProxies;
+
+    #[test]
+    fn forwarded_client_ip() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("198.51.100.1, 203.0.113.9, 10.0.0.2"),
+        );
+        headers.insert("x-real-ip", HeaderValue::from_static("198.51.100.2"));
+        let client = "203.0.113.7".parse().unwrap();
+        let proxy = "10.0.0.1".parse().unwrap();
+
+        // Clients pick their own headers, only the connection counts for them
+        let proxies = TrustedProxies::default();
+        assert_eq!(proxies.client_ip(Some(client), &headers), Some(client));
+        assert_eq!(proxies.client_ip(None, &headers), None);
+
+        let proxies = TrustedProxies(vec![proxy, "10.0.0.2".parse().unwrap()]);
+        assert_eq!(proxies.client_ip(Some(client), &headers), Some(client));
+        assert_eq!(
+            proxies.client_ip(Some(proxy), &headers),
+            Some("203.0.113.9".parse().unwrap())
+        );
+        headers.remove("x-forwarded-for");
+        assert_eq!(
+            proxies.client_ip(Some(proxy), &headers),
+            Some("198.51.100.2".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use crate::{
+    core::{self, audit::AuditAction, identity::OidcLogin, user::User},
+    oidc::{Identity, OidcError, OidcProviders},
+    security::{generate_token, hash_token, JwtKeys, OIDC_LOGIN_LIFETIME_MINUTES},
+};
+
+use super::{
+    audit,
+    auth::{start_login_challenge, start_session, AuthTokens, SecondFactorChallenge},
+    ApiTags, ClientInfo,
+};
+use chrono::{Duration, Utc};
+use derivative::Derivative;
+use poem::web::Data;
+use poem_openapi::{param::Path, payload::Json, ApiResponse, Object, OpenApi};
+use sqlx::PgPool;
+use tracing::{error, warn};
+
+pub struct OidcAPI;
+
+#[OpenApi(prefix_path = "/api")]
+impl OidcAPI {
+    /// Starts a login at an external identity provider.
+    /// The user has to be sent to the returned URL, the provider sends them back
+    /// to the configured redirect URI with `code` and `state` parameters.
+    #[oai(
+        path = "/oidc/:provider/authorize",
+        method = "get",
+        tag = "ApiTags::User"
+    )]
+    #[tracing::instrument(skip(self, pool, providers, provider))]
+    async fn authorize(
+        &self,
+        pool: Data<&PgPool>,
+        providers: Data<&OidcProviders>,
+        provider: Path<String>,
+    ) -> AuthorizeResponse {
+        let Some(config) = providers.get(&provider) else {
+            return AuthorizeResponse::NotFound;
+        };
+        let state = generate_token();
+        let login = OidcLogin {
+            provider: config.name.clone(),
+            code_verifier: generate_token(),
+            nonce: generate_token(),
+        };
+        let authorization_url = match providers
+            .authorization_url(config, &state, &login.nonce, &login.code_verifier)
+            .await
+        {
+            Ok(url) => url,
+            Err(e) => {
+                error!("error {:?} while discovering provider {:?}", e, config.name);
+                return AuthorizeResponse::BadGateway;
+            }
+        };
+        let expires_at = Utc::now() + Duration::minutes(OIDC_LOGIN_LIFETIME_MINUTES);
+        if let Err(e) =
+            core::identity::create_login(&pool, &hash_token(&state), &login, expires_at).await
+        {
+            error!("error {:?} while storing login at {:?}", e, config.name);
+            return AuthorizeResponse::Internal;
+        }
+        AuthorizeResponse::Ok(Json(Authorization { authorization_url }))
+    }
+
+    /// Completes a login at an external identity provider.
+    /// Users are created on their first login, or linked to an existing account
+    /// if the provider verified that they own its email.
+    #[oai(
+        path = "/oidc/:provider/callback",
+        method = "post",
+        tag = "ApiTags::User"
+    )]
+    #[tracing::instrument(skip(self, pool, keys, providers, provider))]
+    async fn callback(
+        &self,
+        pool: Data<&PgPool>,
+        keys: Data<&JwtKeys>,
+        providers: Data<&OidcProviders>,
+        provider: Path<String>,
+        client: ClientInfo,
+        req: Json<OidcCallbackRequest>,
+    ) -> OidcCallbackResponse {
+        let Some(config) = providers.get(&provider) else {
+            return OidcCallbackResponse::NotFound;
+        };
+        let login = match core::identity::consume_login(&pool, &hash_token(&req.state)).await {
+            Ok(Some(login)) if login.provider == config.name => login,
+            Ok(_) => return OidcCallbackResponse::Unauthorized,
+            Err(e) => {
+                error!("error {:?} while retrieving login at {:?}", e, config.name);
+                return OidcCallbackResponse::Internal;
+            }
+        };
+        let identity = match providers
+            .exchange_code(config, &req.code, &login.code_verifier, &login.nonce)
+            .await
+        {
+            Ok(identity) => identity,
+            Err(OidcError::Http(e)) => {
+                warn!("error {:?} while exchanging code at {:?}", e, config.name);
+                return OidcCallbackResponse::BadGateway;
+            }
+            Err(e) => {
+                warn!("rejected login at {:?}: {:?}", config.name, e);
+                return OidcCallbackResponse::Unauthorized;
+            }
+        };
+
+        let user = match find_or_create_user(&pool, &config.name, &identity).await {
+            Ok(Ok(user)) => user,
+            Ok(Err(response)) => return response,
+            Err(e) => {
+                error!(
+                    "error {:?} while resolving identity at {:?}",
+                    e, config.name
+                );
+                return OidcCallbackResponse::Internal;
+            }
+        };
+
+        match core::two_factor::is_enabled(&pool, user.id).await {
+            Ok(true) => {
+                let Some(challenge) = start_login_challenge(&pool, user.id, None).await else {
+                    return OidcCallbackResponse::Internal;
+                };
+                return OidcCallbackResponse::SecondFactorRequired(Json(challenge));
+            }
+            Ok(false) => {}
+            Err(e) => {
+                error!("error {:?} while checking 2FA of {:?}", e, user.id);
+                return OidcCallbackResponse::Internal;
+            }
+        }
+        let Some(tokens) = start_session(&pool, &keys, user.id, user.role).await else {
+            return OidcCallbackResponse::Internal;
+        };
+        let details = serde_json::json!({ "method": "oidc", "provider": config.name });
+        audit(
+            &pool,
+            &client,
+            Some(user.id),
+            AuditAction::Login,
+            None,
+            Some(details),
+        )
+        .await;
+        OidcCallbackResponse::Ok(Json(tokens))
+    }
+}
+
+/// Returns the user linked to the identity, links it to the account with the same
+/// verified email or creates a new user.
+async fn find_or_create_user(
+    pool: &PgPool,
+    provider: &str,
+    identity: &Identity,
+) -> sqlx::Result<Result<User, OidcCallbackResponse>> {
+    if let Some(user_id) =
+        core::identity::get_identity_user(pool, provider, &identity.subject).await?
+    {
+        return Ok(core::user::get_user(pool, user_id)
+            .await?
+            .ok_or(OidcCallbackResponse::Unauthorized));
+    }
+
+    let Some(email) = &identity.email else {
+        return Ok(Err(OidcCallbackResponse::MissingEmail));
+    };
+    let user = match core::user::get_user_by_email(pool, email).await? {
+        // Only link if the provider vouches for the email, otherwise anyone could
+        // take over an account by registering its email at the provider
+        Some(existing) if identity.email_verified => existing,
+        Some(_) => return Ok(Err(OidcCallbackResponse::UserAlreadyExists)),
+        None => {
+            let name = identity
+                .name
+                .as_deref()
+                .unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+            let user = User {
+                name: name.chars().take(64).collect(),
+                email: Some(email.clone()),
+                is_guest: false,
+                avatar_seed: generate_token(),
+                ..User::default()
+            };
+            let Some(user_id) = core::user::insert_user(pool, &user).await? else {
+                return Ok(Err(OidcCallbackResponse::UserAlreadyExists));
+            };
+            let user = if identity.email_verified {
+                core::user::verify_email(pool, user_id).await?
+            } else {
+                core::user::get_user(pool, user_id).await?
+            };
+            let Some(user) = user else {
+                return Ok(Err(OidcCallbackResponse::Internal));
+            };
+            user
+        }
+    };
+    core::identity::link_identity(pool, provider, &identity.subject, user.id).await?;
+    Ok(Ok(user))
+}
+
+#[derive(Object)]
+pub struct Authorization {
+    /// The provider's login page
+    authorization_url: String,
+}
+
+#[derive(ApiResponse)]
+pub enum AuthorizeResponse {
+    #[oai(status = 200)]
+    Ok(Json<Authorization>),
+
+    /// The provider is not configured
+    #[oai(status = 404)]
+    NotFound,
+
+    #[oai(status = 500)]
+    Internal,
+
+    /// The provider could not be reached
+    #[oai(status = 502)]
+    BadGateway,
+}
+
+#[derive(Derivative, Object)]
+#[derivative(Debug)]
+pub struct OidcCallbackRequest {
+    #[derivative(Debug = "ignore")]
+    code: String,
+    #[derivative(Debug = "ignore")]
+    state: String,
+}
+
+#[derive(ApiResponse)]
+pub enum OidcCallbackResponse {
+    #[oai(status = 200)]
+    Ok(Json<AuthTokens>),
+
+    /// The account requires a second factor, see `/api/login/totp`
+    #[oai(status = 202)]
+    SecondFactorRequired(Json<SecondFactorChallenge>),
+
+    /// The provider did not share the user's email
+    #[oai(status = 400)]
+    MissingEmail,
+
+    /// The login is unknown, expired or was rejected by the provider
+    #[oai(status = 401)]
+    Unauthorized,
+
+    /// The provider is not configured
+    #[oai(status = 404)]
+    NotFound,
+
+    /// An account with the email exists, but the provider did not verify the email
+    #[oai(status = 409)]
+    UserAlreadyExists,
+
+    #[oai(status = 500)]
+    Internal,
+
+    /// The provider could not be reached
+    #[oai(status = 502)]
+    BadGateway,
+}
@@ -1,13 +1,22 @@
 use crate::{
-    core,
-    entities::quiz::{APIQuiz, DBQuiz},
+    core::{
+        self, audit::AuditAction, idempotency::StoredResponse, translation::is_valid_language_code,
+    },
+    entities::quiz::{APIQuiz, DBQuiz, PlayerQuiz, QuizAnswer, QuizAttemptResult, QuizTranslation},
     security::JWTAuthorization,
 };
 
-use super::ApiTags;
+use super::{
+    audit, idempotent, replay_json, store_json, ApiTags, ClientInfo, IdempotentResponse,
+    LocaleQuery,
+};
 use poem::web::{Data, Query};
-use poem_openapi::{param::Path, payload::Json, ApiResponse, OpenApi};
-use serde::Deserialize;
+use poem_openapi::{
+    param::{Header, Path},
+    payload::Json,
+    types::ToJSON,
+    ApiResponse, Object, OpenApi,
+};
 use sqlx::PgPool;
 use tracing::error;
 use uuid::Uuid;
@@ -17,35 +26,79 @@ pub struct QuizAPI;
 #[OpenApi]
 impl QuizAPI {
     #[oai(path = "/api/quiz", method = "post", tag = "ApiTags::Quiz")]
-    #[tracing::instrument(skip(self, pool, auth))]
+    #[tracing::instrument(skip(self, pool, auth, idempotency_key))]
     async fn create_quiz(
         &self,
         pool: Data<&PgPool>,
         req: Json<APIQuiz>,
+        locale_query: Query<LocaleQuery>,
+        client: ClientInfo,
         auth: JWTAuthorization,
+        #[oai(name = "Idempotency-Key", validator(max_length = 255))] idempotency_key: Header<
+            Option<String>,
+        >,
     ) -> CreateQuizResponse {
-        let mut db_quiz: DBQuiz = req.0.into();
-        db_quiz.created_by = auth.0.id;
-        let id = match core::quiz::insert_quiz(&pool, &db_quiz).await {
-            Ok(id) => id,
+        let Ok(language_code) = locale_query.content_language() else {
+            return CreateQuizResponse::BadRequest;
+        };
+        match core::user::is_verified(&pool, auth.0.id).await {
+            Ok(true) => {}
+            Ok(false) => return CreateQuizResponse::Forbidden,
             Err(e) => {
-                error!("{:?}", e);
+                error!(
+                    "error {:?} while checking verification of {:?}",
+                    e, auth.0.id
+                );
                 return CreateQuizResponse::Internal;
             }
-        };
-        CreateQuizResponse::Ok(Json(id))
+        }
+        let request = format!(
+            "POST /api/quiz?lang_code={:?} {}",
+            language_code,
+            req.0.to_json_string()
+        );
+        idempotent(
+            &pool,
+            auth.0.id,
+            idempotency_key.as_deref(),
+            request,
+            async {
+                let mut db_quiz: DBQuiz = req.0.into();
+                db_quiz.created_by = auth.0.id;
+                let id = match core::quiz::insert_quiz(&pool, &db_quiz, language_code).await {
+                    Ok(id) => id,
+                    Err(e) => {
+                        error!("{:?}", e);
+                        return CreateQuizResponse::Internal;
+                    }
+                };
+                audit(
+                    &pool,
+                    &client,
+                    Some(auth.0.id),
+                    AuditAction::QuizCreate,
+                    Some(id),
+                    None,
+                )
+                .await;
+                CreateQuizResponse::Ok(Json(id))
+            },
+        )
+        .await
     }
 
     #[oai(path = "/api/quiz/:id", method = "get", tag = "ApiTags::Quiz")]
-    #[tracing::instrument(skip(self, pool, id, auth))]
+    #[tracing::instrument(skip(self, pool, id, accept_language, _auth))]
     async fn get_quiz(
         &self,
         pool: Data<&PgPool>,
         id: Path<Uuid>,
         locale_query: Query<LocaleQuery>,
-        auth: JWTAuthorization,
+        #[oai(name = "Accept-Language")] accept_language: Header<Option<String>>,
+        _auth: JWTAuthorization,
     ) -> GetQuizResponse {
-        let quiz = match core::quiz::get_quiz(&pool, id.0).await {
+        let languages = locale_query.languages(accept_language.as_deref());
+        let quiz = match core::quiz::get_quiz(&pool, id.0, &languages).await {
             Ok(Some(q)) => q,
             Ok(None) => return GetQuizResponse::NotFound,
             Err(e) => {
@@ -55,17 +108,75 @@ impl QuizAPI {
         };
         GetQuizResponse::Ok(Json(quiz.into()))
     }
-}
 
-#[derive(Debug, Deserialize)]
-pub struct LocaleQuery {
-    lang_code: Option<String>,
+    #[oai(
+        path = "/api/quiz/:id/translations/:lang_code",
+        method = "put",
+        tag = "ApiTags::Quiz"
+    )]
+    #[tracing::instrument(skip(self, pool, id, lang_code, auth))]
+    async fn translate_quiz(
+        &self,
+        pool: Data<&PgPool>,
+        id: Path<Uuid>,
+        lang_code: Path<String>,
+        req: Json<QuizTranslation>,
+        auth: JWTAuthorization,
+    ) -> TranslateQuizResponse {
+        if !is_valid_language_code(&lang_code) {
+            return TranslateQuizResponse::BadRequest;
+        }
+        match core::quiz::get_quiz(&pool, id.0, &[]).await {
+            Ok(Some(quiz)) if quiz.created_by != auth.0.id => {
+                return TranslateQuizResponse::Forbidden
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => return TranslateQuizResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while retrieving quiz {:?}", e, id.0);
+                return TranslateQuizResponse::Internal;
+            }
+        }
+        match core::quiz::translate_quiz(&pool, id.0, &lang_code, &req.0).await {
+            Ok(Some(())) => TranslateQuizResponse::Ok,
+            Ok(None) => TranslateQuizResponse::NotFound,
+            Err(e) => {
+                error!(
+                    "error {:?} while translating quiz {:?} to {:?}",
+                    e, id.0, lang_code.0
+                );
+                TranslateQuizResponse::Internal
+            }
+        }
+    }
+
+    #[oai(path = "/api/quiz/:id/attempt", method = "post", tag = "ApiTags::Quiz")]
+    #[tracing::instrument(skip(self, pool, id, auth))]
+    async fn submit_attempt(
+        &self,
+        pool: Data<&PgPool>,
+        id: Path<Uuid>,
+        req: Json<SubmitAttemptRequest>,
+        auth: JWTAuthorization,
+    ) -> SubmitAttemptResponse {
+        match core::quiz::submit_attempt(&pool, id.0, auth.0.id, req.0.answers).await {
+            Ok(Some(result)) => SubmitAttemptResponse::Ok(Json(result)),
+            Ok(None) => SubmitAttemptResponse::NotFound,
+            Err(e) => {
+                error!(
+                    "error {:?} while submitting attempt for quiz {:?} by user {:?}",
+                    e, id.0, auth.0.id
+                );
+                SubmitAttemptResponse::Internal
+            }
+        }
+    }
 }
 
 #[derive(ApiResponse)]
 pub enum GetQuizResponse {
     #[oai(status = 200)]
-    Ok(Json<APIQuiz>),
+    Ok(Json<PlayerQuiz>),
 
     #[oai(status = 404)]
     NotFound,
@@ -79,6 +190,84 @@ pub enum CreateQuizResponse {
     #[oai(status = 201)]
     Ok(Json<Uuid>),
 
+    #[oai(status = 400)]
+    BadRequest,
+
+    /// Only users with a verified email can publish quizzes
+    #[oai(status = 403)]
+    Forbidden,
+
+    /// A quiz with the same Idempotency-Key is still being created
+    #[oai(status = 409)]
+    IdempotencyKeyInUse,
+
+    /// The Idempotency-Key was already used for a different quiz
+    #[oai(status = 422)]
+    IdempotencyKeyReused,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+impl IdempotentResponse for CreateQuizResponse {
+    fn store(&self) -> Option<StoredResponse> {
+        match self {
+            Self::Ok(id) => Some(store_json(201, &id.0)),
+            _ => None,
+        }
+    }
+
+    fn replay(stored: StoredResponse) -> Option<Self> {
+        match stored.status {
+            201 => replay_json(&stored).map(|id| Self::Ok(Json(id))),
+            _ => None,
+        }
+    }
+
+    fn key_reused() -> Self {
+        Self::IdempotencyKeyReused
+    }
+
+    fn key_in_use() -> Self {
+        Self::IdempotencyKeyInUse
+    }
+
+    fn internal() -> Self {
+        Self::Internal
+    }
+}
+
+#[derive(Object, Debug)]
+pub struct SubmitAttemptRequest {
+    answers: Vec<QuizAnswer>,
+}
+
+#[derive(ApiResponse)]
+pub enum SubmitAttemptResponse {
+    #[oai(status = 201)]
+    Ok(Json<QuizAttemptResult>),
+
+    #[oai(status = 404)]
+    NotFound,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(ApiResponse)]
+pub enum TranslateQuizResponse {
+    #[oai(status = 200)]
+    Ok,
+
+    #[oai(status = 400)]
+    BadRequest,
+
+    #[oai(status = 403)]
+    Forbidden,
+
+    #[oai(status = 404)]
+    NotFound,
+
     #[oai(status = 500)]
     Internal,
 }
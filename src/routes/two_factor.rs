@@ -0,0 +1,613 @@
+use crate::{
+    core::{self, audit::AuditAction, token::TokenPurpose, two_factor::UserTotp},
+    security::{
+        get_current_timestamp, hash_token, verify_password, JWTAuthorization, JwtKeys,
+        PasswordHashing, LOGIN_ATTEMPTS_PER_ACCOUNT,
+    },
+    totp,
+};
+
+use super::{
+    audit,
+    auth::{start_session_in_mode, LoginResponse, SessionMode},
+    ApiTags, ClientInfo,
+};
+use chrono::Utc;
+use derivative::Derivative;
+use password_hash::rand_core::{OsRng, RngCore};
+use poem::{session::Session, web::Data};
+use poem_openapi::{payload::Json, ApiResponse, Object, OpenApi};
+use sqlx::PgPool;
+use tracing::error;
+use uuid::Uuid;
+
+/// Number of recovery codes handed out at once.
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Shown as the account's provider in authenticator apps.
+const TOTP_ISSUER: &str = "Let's Science";
+
+pub struct TwoFactorAPI;
+
+#[OpenApi(prefix_path = "/api")]
+impl TwoFactorAPI {
+    /// Starts setting up two-factor authentication.
+    /// It is enabled once a code is confirmed at `/api/user/self/totp/confirm`.
+    #[oai(path = "/user/self/totp", method = "post", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, pool))]
+    async fn enrol_totp(&self, pool: Data<&PgPool>, auth: JWTAuthorization) -> EnrolTotpResponse {
+        let user = match core::user::get_user(&pool, auth.0.id).await {
+            Ok(Some(u)) => u,
+            Ok(None) => return EnrolTotpResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while retrieving profile {:?}", e, auth.0);
+                return EnrolTotpResponse::Internal;
+            }
+        };
+        let Some(email) = user.email else {
+            return EnrolTotpResponse::BadRequest;
+        };
+
+        let secret = totp::generate_secret();
+        let encoded = totp::base32_encode(&secret);
+        match core::two_factor::start_enrolment(&pool, user.id, &encoded).await {
+            Ok(Some(())) => EnrolTotpResponse::Ok(Json(TotpEnrolment {
+                provisioning_uri: totp::provisioning_uri(&secret, TOTP_ISSUER, &email),
+                secret: encoded,
+            })),
+            Ok(None) => EnrolTotpResponse::AlreadyEnabled,
+            Err(e) => {
+                error!(
+                    "error {:?} while starting 2FA enrolment of {:?}",
+                    e, user.id
+                );
+                EnrolTotpResponse::Internal
+            }
+        }
+    }
+
+    /// Enables two-factor authentication with a code from the authenticator app.
+    #[oai(
+        path = "/user/self/totp/confirm",
+        method = "post",
+        tag = "ApiTags::User"
+    )]
+    #[tracing::instrument(skip(self, pool))]
+    async fn confirm_totp(
+        &self,
+        pool: Data<&PgPool>,
+        client: ClientInfo,
+        auth: JWTAuthorization,
+        req: Json<TotpCodeRequest>,
+    ) -> RecoveryCodesResponse {
+        let totp = match core::two_factor::get_totp(&pool, auth.0.id).await {
+            Ok(Some(totp)) if totp.confirmed_at.is_none() => totp,
+            Ok(Some(_)) => return RecoveryCodesResponse::AlreadyEnabled,
+            Ok(None) => return RecoveryCodesResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while retrieving 2FA of {:?}", e, auth.0.id);
+                return RecoveryCodesResponse::Internal;
+            }
+        };
+        let response = replace_recovery_codes(&pool, &totp, &req.code).await;
+        if let RecoveryCodesResponse::Ok(_) = response {
+            audit(
+                &pool,
+                &client,
+                Some(auth.0.id),
+                AuditAction::TwoFactorEnable,
+                Some(auth.0.id),
+                None,
+            )
+            .await;
+        }
+        response
+    }
+
+    /// Replaces all recovery codes with new ones.
+    #[oai(
+        path = "/user/self/totp/recovery-codes",
+        method = "post",
+        tag = "ApiTags::User"
+    )]
+    #[tracing::instrument(skip(self, pool))]
+    async fn regenerate_recovery_codes(
+        &self,
+        pool: Data<&PgPool>,
+        auth: JWTAuthorization,
+        req: Json<TotpCodeRequest>,
+    ) -> RecoveryCodesResponse {
+        let totp = match core::two_factor::get_totp(&pool, auth.0.id).await {
+            Ok(Some(totp)) if totp.confirmed_at.is_some() => totp,
+            Ok(_) => return RecoveryCodesResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while retrieving 2FA of {:?}", e, auth.0.id);
+                return RecoveryCodesResponse::Internal;
+            }
+        };
+        replace_recovery_codes(&pool, &totp, &req.code).await
+    }
+
+    /// Turns two-factor authentication off again. Confirmed with the password, or with a code
+    /// or recovery code if the account has no password.
+    #[oai(
+        path = "/user/self/totp/disable",
+        method = "post",
+        tag = "ApiTags::User"
+    )]
+    #[tracing::instrument(skip(self, pool, hashing))]
+    async fn disable_totp(
+        &self,
+        pool: Data<&PgPool>,
+        hashing: Data<&PasswordHashing>,
+        client: ClientInfo,
+        auth: JWTAuthorization,
+        req: Json<DisableTotpRequest>,
+    ) -> DisableTotpResponse {
+        let hash = match core::user::get_user(&pool, auth.0.id).await {
+            Ok(Some(u)) => u.hash,
+            Ok(None) => return DisableTotpResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while retrieving profile {:?}", e, auth.0);
+                return DisableTotpResponse::Internal;
+            }
+        };
+        let verified = match hash {
+            Some(hash) => {
+                let password = req.password.as_deref().unwrap_or_default();
+                matches!(verify_password(&hashing, password, &hash), Ok(true))
+            }
+            // Accounts of identity providers have no password, they confirm with a second factor
+            None => {
+                let throttle_key = format!("totp:{}", auth.0.id);
+                match core::login_throttle::locked_until(&pool, std::slice::from_ref(&throttle_key))
+                    .await
+                {
+                    Ok(Some(until)) => {
+                        let retry_after = (until - Utc::now()).num_seconds().max(1);
+                        return DisableTotpResponse::TooManyRequests(retry_after);
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("error {:?} while checking login throttle", e);
+                        return DisableTotpResponse::Internal;
+                    }
+                }
+                let verified = verify_second_factor(
+                    &pool,
+                    auth.0.id,
+                    req.code.as_deref(),
+                    req.recovery_code.as_deref(),
+                )
+                .await;
+                match verified {
+                    Ok(true) => true,
+                    Ok(false) => {
+                        if let Err(e) = core::login_throttle::record_failure(
+                            &pool,
+                            &throttle_key,
+                            LOGIN_ATTEMPTS_PER_ACCOUNT,
+                        )
+                        .await
+                        {
+                            error!("error {:?} while recording login failure", e);
+                        }
+                        false
+                    }
+                    Err(e) => {
+                        error!(
+                            "error {:?} while verifying second factor of {:?}",
+                            e, auth.0.id
+                        );
+                        return DisableTotpResponse::Internal;
+                    }
+                }
+            }
+        };
+        if !verified {
+            return DisableTotpResponse::Unauthorized;
+        }
+        match core::two_factor::disable(&pool, auth.0.id).await {
+            Ok(Some(())) => {
+                audit(
+                    &pool,
+                    &client,
+                    Some(auth.0.id),
+                    AuditAction::TwoFactorDisable,
+                    Some(auth.0.id),
+                    None,
+                )
+                .await;
+                DisableTotpResponse::Ok
+            }
+            Ok(None) => DisableTotpResponse::NotFound,
+            Err(e) => {
+                error!("error {:?} while disabling 2FA of {:?}", e, auth.0.id);
+                DisableTotpResponse::Internal
+            }
+        }
+    }
+
+    /// Completes a login of an account with two-factor authentication,
+    /// using either a code from the authenticator app or a recovery code.
+    #[oai(path = "/login/totp", method = "post", tag = "ApiTags::User")]
+    #[tracing::instrument(skip(self, session, pool, keys))]
+    async fn login_totp(
+        &self,
+        session: &Session,
+        pool: Data<&PgPool>,
+        keys: Data<&JwtKeys>,
+        client: ClientInfo,
+        req: Json<SecondFactorRequest>,
+    ) -> LoginResponse {
+        let challenge_hash = hash_token(&req.challenge_token);
+        let user_id =
+            match core::token::peek_token(&pool, &challenge_hash, TokenPurpose::LoginChallenge)
+                .await
+            {
+                Ok(Some(id)) => id,
+                Ok(None) => return LoginResponse::Unauthorized,
+                Err(e) => {
+                    error!("error {:?} while checking login challenge", e);
+                    return LoginResponse::Internal;
+                }
+            };
+
+        let throttle_key = format!("totp:{}", user_id);
+        match core::login_throttle::locked_until(&pool, std::slice::from_ref(&throttle_key)).await {
+            Ok(Some(until)) => {
+                let retry_after = (until - Utc::now()).num_seconds().max(1);
+                return LoginResponse::TooManyRequests(retry_after);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("error {:?} while checking login throttle", e);
+                return LoginResponse::Internal;
+            }
+        }
+
+        let verified = verify_second_factor(
+            &pool,
+            user_id,
+            req.code.as_deref(),
+            req.recovery_code.as_deref(),
+        )
+        .await;
+        match verified {
+            Ok(true) => {}
+            Ok(false) => {
+                if let Err(e) = core::login_throttle::record_failure(
+                    &pool,
+                    &throttle_key,
+                    LOGIN_ATTEMPTS_PER_ACCOUNT,
+                )
+                .await
+                {
+                    error!("error {:?} while recording login failure", e);
+                }
+                let details = serde_json::json!({ "method": "totp" });
+                audit(
+                    &pool,
+                    &client,
+                    Some(user_id),
+                    AuditAction::LoginFailed,
+                    None,
+                    Some(details),
+                )
+                .await;
+                return LoginResponse::Unauthorized;
+            }
+            Err(e) => {
+                error!(
+                    "error {:?} while verifying second factor of {:?}",
+                    e, user_id
+                );
+                return LoginResponse::Internal;
+            }
+        }
+
+        match core::token::consume_token(&pool, &challenge_hash, TokenPurpose::LoginChallenge).await
+        {
+            Ok(Some(_)) => {}
+            Ok(None) => return LoginResponse::Unauthorized,
+            Err(e) => {
+                error!("error {:?} while consuming login challenge", e);
+                return LoginResponse::Internal;
+            }
+        }
+        if let Err(e) = core::login_throttle::clear_failures(&pool, &throttle_key).await {
+            error!("error {:?} while clearing login failures", e);
+        }
+        let role = match core::user::get_user(&pool, user_id).await {
+            Ok(Some(u)) => u.role,
+            Ok(None) => return LoginResponse::Unauthorized,
+            Err(e) => {
+                error!("error {:?} while retrieving user {:?}", e, user_id);
+                return LoginResponse::Internal;
+            }
+        };
+        // Completes merging the guest the login was started for, see `upgrade_guest`
+        match core::token::get_guest(&pool, &challenge_hash).await {
+            Ok(Some(guest_id)) => {
+                if let Err(e) = core::user::merge_users(&pool, guest_id, user_id).await {
+                    error!(
+                        "error {:?} while merging guest {:?} into {:?}",
+                        e, guest_id, user_id
+                    );
+                    return LoginResponse::Internal;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("error {:?} while retrieving guest of login challenge", e);
+                return LoginResponse::Internal;
+            }
+        }
+        let Some(started) =
+            start_session_in_mode(&pool, &keys, session, req.mode, user_id, role).await
+        else {
+            return LoginResponse::Internal;
+        };
+        let method = if req.code.is_some() {
+            "totp"
+        } else {
+            "recovery_code"
+        };
+        let details = serde_json::json!({ "method": method });
+        audit(
+            &pool,
+            &client,
+            Some(user_id),
+            AuditAction::Login,
+            None,
+            Some(details),
+        )
+        .await;
+        started.into()
+    }
+}
+
+/// Checks a code from the authenticator app, or else a recovery code, which is used up then.
+async fn verify_second_factor(
+    pool: &PgPool,
+    user_id: Uuid,
+    code: Option<&str>,
+    recovery_code: Option<&str>,
+) -> sqlx::Result<bool> {
+    match (code, recovery_code) {
+        (Some(code), _) => match core::two_factor::get_totp(pool, user_id).await? {
+            Some(totp) if totp.confirmed_at.is_some() => verify_code(pool, &totp, code).await,
+            _ => Ok(false),
+        },
+        (None, Some(recovery_code)) => {
+            let hash = hash_token(&normalize_recovery_code(recovery_code));
+            core::two_factor::use_recovery_code(pool, user_id, &hash).await
+        }
+        (None, None) => Ok(false),
+    }
+}
+
+/// Checks a code from the authenticator app. Every code is accepted only once.
+async fn verify_code(pool: &PgPool, totp: &UserTotp, code: &str) -> sqlx::Result<bool> {
+    let Some(secret) = totp::base32_decode(&totp.secret) else {
+        error!("invalid 2FA secret stored for {:?}", totp.user_id);
+        return Ok(false);
+    };
+    let Some(step) = totp::verify(&secret, code, get_current_timestamp()) else {
+        return Ok(false);
+    };
+    core::two_factor::use_step(pool, totp.user_id, step as i64).await
+}
+
+/// Verifies the code and hands out a new set of recovery codes, enabling 2FA if necessary.
+async fn replace_recovery_codes(
+    pool: &PgPool,
+    totp: &UserTotp,
+    code: &str,
+) -> RecoveryCodesResponse {
+    match verify_code(pool, totp, code).await {
+        Ok(true) => {}
+        Ok(false) => return RecoveryCodesResponse::InvalidCode,
+        Err(e) => {
+            error!("error {:?} while verifying code of {:?}", e, totp.user_id);
+            return RecoveryCodesResponse::Internal;
+        }
+    }
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+    let hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| hash_token(&normalize_recovery_code(code)))
+        .collect();
+    match core::two_factor::confirm(pool, totp.user_id, &hashes).await {
+        Ok(()) => RecoveryCodesResponse::Ok(Json(RecoveryCodes { recovery_codes })),
+        Err(e) => {
+            error!(
+                "error {:?} while storing recovery codes of {:?}",
+                e, totp.user_id
+            );
+            RecoveryCodesResponse::Internal
+        }
+    }
+}
+
+/// Generates a recovery code like `ABCD-EFGH`.
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    OsRng.fill_bytes(&mut bytes);
+    let code = totp::base32_encode(&bytes);
+    format!("{}-{}", &code[..4], &code[4..])
+}
+
+/// Recovery codes are accepted regardless of case and separators.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+#[derive(Object)]
+pub struct TotpEnrolment {
+    /// Base32 encoded secret, for manual entry in an authenticator app
+    secret: String,
+    /// `otpauth://` URI to show as QR code
+    provisioning_uri: String,
+}
+
+#[derive(ApiResponse)]
+pub enum EnrolTotpResponse {
+    #[oai(status = 200)]
+    Ok(Json<TotpEnrolment>),
+
+    /// Guests cannot use two-factor authentication
+    #[oai(status = 400)]
+    BadRequest,
+
+    #[oai(status = 404)]
+    NotFound,
+
+    #[oai(status = 409)]
+    AlreadyEnabled,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(Derivative, Object)]
+#[derivative(Debug)]
+pub struct TotpCodeRequest {
+    #[derivative(Debug = "ignore")]
+    code: String,
+}
+
+#[derive(Object)]
+pub struct RecoveryCodes {
+    /// Single-use codes to log in without the authenticator app.
+    /// They are only shown once.
+    recovery_codes: Vec<String>,
+}
+
+#[derive(ApiResponse)]
+pub enum RecoveryCodesResponse {
+    #[oai(status = 200)]
+    Ok(Json<RecoveryCodes>),
+
+    #[oai(status = 400)]
+    InvalidCode,
+
+    /// Two-factor authentication was not set up
+    #[oai(status = 404)]
+    NotFound,
+
+    #[oai(status = 409)]
+    AlreadyEnabled,
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(Derivative, Object)]
+#[derivative(Debug)]
+pub struct DisableTotpRequest {
+    /// The password of the account
+    #[derivative(Debug = "ignore")]
+    password: Option<String>,
+    /// Code from the authenticator app, for accounts without a password
+    #[derivative(Debug = "ignore")]
+    code: Option<String>,
+    /// One of the recovery codes, for accounts without a password
+    #[derivative(Debug = "ignore")]
+    recovery_code: Option<String>,
+}
+
+#[derive(ApiResponse)]
+pub enum DisableTotpResponse {
+    #[oai(status = 200)]
+    Ok,
+
+    /// The password is wrong, or for accounts without one the code
+    #[oai(status = 401)]
+    Unauthorized,
+
+    #[oai(status = 404)]
+    NotFound,
+
+    /// There were too many wrong codes, retry after the given number of seconds
+    #[oai(status = 429)]
+    TooManyRequests(#[oai(header = "Retry-After")] i64),
+
+    #[oai(status = 500)]
+    Internal,
+}
+
+#[derive(Derivative, Object)]
+#[derivative(Debug)]
+pub struct SecondFactorRequest {
+    #[derivative(Debug = "ignore")]
+    challenge_token: String,
+    /// Code from the authenticator app
+    #[derivative(Debug = "ignore")]
+    code: Option<String>,
+    /// One of the recovery codes, if the authenticator app is not available
+    #[derivative(Debug = "ignore")]
+    recovery_code: Option<String>,
+    #[oai(default)]
+    mode: SessionMode,
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::web::Data;
+    use poem_openapi::payload::Json;
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::{DisableTotpRequest, DisableTotpResponse, TwoFactorAPI};
+    use crate::{
+        core::{self, user::User},
+        routes::ClientInfo,
+        security::{hash_token, AuthUser, JWTAuthorization, PasswordHashing},
+    };
+
+    #[sqlx::test]
+    async fn disable_without_password(pool: PgPool) -> sqlx::Result<()> {
+        // Accounts of identity providers have no password
+        let user = User {
+            email: Some("student@school.example".to_owned()),
+            is_guest: false,
+            ..User::default()
+        };
+        let user_id = core::user::insert_user(&pool, &user).await?.unwrap();
+        core::two_factor::start_enrolment(&pool, user_id, "secret").await?;
+        let recovery_code = hash_token(&super::normalize_recovery_code("abcd-efgh"));
+        core::two_factor::confirm(&pool, user_id, &[recovery_code]).await?;
+
+        let hashing = PasswordHashing::new(8, 1, 1, None).unwrap();
+        let disable = |password: Option<&str>, recovery_code: Option<&str>| {
+            let req = DisableTotpRequest {
+                password: password.map(ToOwned::to_owned),
+                code: None,
+                recovery_code: recovery_code.map(ToOwned::to_owned),
+            };
+            let auth = JWTAuthorization(AuthUser::new(user_id, Uuid::new_v4(), Default::default()));
+            TwoFactorAPI.disable_totp(
+                Data(&pool),
+                Data(&hashing),
+                ClientInfo::default(),
+                auth,
+                Json(req),
+            )
+        };
+        let response = disable(Some(""), None).await;
+        assert!(matches!(response, DisableTotpResponse::Unauthorized));
+        let response = disable(None, Some("WXYZ-WXYZ")).await;
+        assert!(matches!(response, DisableTotpResponse::Unauthorized));
+        assert!(core::two_factor::is_enabled(&pool, user_id).await?);
+
+        let response = disable(None, Some("ABCD-EFGH")).await;
+        assert!(matches!(response, DisableTotpResponse::Ok));
+        assert!(!core::two_factor::is_enabled(&pool, user_id).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+// Time-based one-time passwords as described in RFC 6238,
+// compatible with common authenticator apps.
+
+use hmac::{Hmac, Mac};
+use password_hash::rand_core::{OsRng, RngCore};
+use sha1::Sha1;
+
+/// Seconds a code is valid for.
+pub const STEP: u64 = 30;
+/// Number of digits of a code.
+pub const DIGITS: u32 = 6;
+/// Codes of this many steps before and after the current one are accepted as well,
+/// to allow for clock drift.
+pub const SKEW: u64 = 1;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Generates a new random secret of 160 bits.
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; 20];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// Encodes bytes as base32 without padding, the format authenticator apps expect.
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | u16::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[usize::from((buffer >> bits) & 0x1f)] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[usize::from((buffer << (5 - bits)) & 0x1f)] as char);
+    }
+    encoded
+}
+
+/// Decodes unpadded base32, ignoring case. Returns None for invalid characters.
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// The URI to put into a QR code, so authenticator apps can import the secret.
+pub fn provisioning_uri(secret: &[u8], issuer: &str, account: &str) -> String {
+    let issuer = encode_uri_component(issuer);
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer,
+        encode_uri_component(account),
+        base32_encode(secret),
+        issuer,
+        DIGITS,
+        STEP
+    )
+}
+
+fn encode_uri_component(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The time step a unix timestamp falls into.
+pub fn step_at(timestamp: u64) -> u64 {
+    timestamp / STEP
+}
+
+/// Computes the code for the given time step.
+pub fn code_at(secret: &[u8], step: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        truncated % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// Checks the code against the steps around the given timestamp.
+/// Returns the matching step, so callers can reject codes that were used before.
+pub fn verify(secret: &[u8], code: &str, timestamp: u64) -> Option<u64> {
+    let code = code.trim();
+    let current = step_at(timestamp);
+    (current.saturating_sub(SKEW)..=current + SKEW).find(|step| code_at(secret, *step) == code)
+}
+
+#[cfg(test)]
+mod tests {
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc_6238_vectors() {
+        assert_eq!(super::code_at(RFC_SECRET, super::step_at(59)), "287082");
+        assert_eq!(
+            super::code_at(RFC_SECRET, super::step_at(1111111109)),
+            "081804"
+        );
+        assert_eq!(
+            super::code_at(RFC_SECRET, super::step_at(20000000000)),
+            "353130"
+        );
+    }
+
+    #[test]
+    fn verify_with_skew() {
+        let now = 1111111109;
+        let code = super::code_at(RFC_SECRET, super::step_at(now));
+        assert_eq!(
+            super::verify(RFC_SECRET, &code, now),
+            Some(super::step_at(now))
+        );
+        assert!(super::verify(RFC_SECRET, &code, now + super::STEP).is_some());
+        assert!(super::verify(RFC_SECRET, &code, now + 3 * super::STEP).is_none());
+        assert!(super::verify(RFC_SECRET, "000000", now).is_none());
+    }
+
+    #[test]
+    fn base32() {
+        assert_eq!(super::base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(super::base32_decode("mzxw6ytboi").unwrap(), b"foobar");
+        assert_eq!(super::base32_decode("MZXW6==").unwrap(), b"foo");
+        assert!(super::base32_decode("1").is_none());
+        let secret = super::generate_secret();
+        assert_eq!(
+            super::base32_decode(&super::base32_encode(&secret)).unwrap(),
+            secret
+        );
+    }
+
+    #[test]
+    fn provisioning_uri() {
+        let uri = super::provisioning_uri(b"foobar", "Let's Science", "a@example.com");
+        assert_eq!(
+            uri,
+            "otpauth://totp/Let%27s%20Science:a%40example.com?secret=MZXW6YTBOI&issuer=Let%27s%20Science&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}